@@ -51,13 +51,41 @@ function stopNats (done) {
   natsProc.kill();
 }
 
+// start mayastor connected to the nats server
+function startMayastor (extraArgs) {
+  common.startMayastor(null, [
+    '-r', common.SOCK,
+    '-g', common.grpcEndpoint,
+    '-n', NATS_ENDPOINT,
+    '-N', NODE_NAME
+  ].concat(extraArgs || []), {
+    MAYASTOR_HB_INTERVAL: HB_INTERVAL
+  });
+}
+
 function assertRegisterMessage (msg) {
   const args = JSON.parse(msg);
-  assert.hasAllKeys(args, ['id', 'grpcEndpoint']);
+  assert.containsAllKeys(args, ['id', 'grpcEndpoint']);
   assert.strictEqual(args.id, NODE_NAME);
   assert.strictEqual(args.grpcEndpoint, common.grpcEndpoint);
 }
 
+// Check the shape of the fields describing the state of the node, the values
+// depend on what the other tests have created.
+function assertNodeState (msg) {
+  const args = JSON.parse(msg);
+  assert.isNumber(args.schemaVersion);
+  assert.isAtLeast(args.schemaVersion, 101);
+  assert.isAbove(args.startEpoch, 0);
+  assert.isAtLeast(args.seq, 1);
+  assert.oneOf(args.status, ['Starting', 'Ready', 'Degraded']);
+  assert.isObject(args.health);
+  assert.isArray(args.health.nexus);
+  args.health.nexus.forEach((nexus) => {
+    assert.hasAllKeys(nexus, ['name', 'status', 'degradedChildren']);
+  });
+}
+
 // The tests must be run in sequence. We start/stop mayastor and NATS as part
 // of the tests and setting the right environment for each test would be
 // tedious.
@@ -81,13 +109,7 @@ describe('nats', function () {
     client = nats.connect(`nats://${NATS_ENDPOINT}`);
     client.on('connect', () => {
       // start mayastor
-      common.startMayastor(null, [
-        '-g', common.grpcEndpoint,
-        '-n', NATS_ENDPOINT,
-        '-N', NODE_NAME
-      ], {
-        MAYASTOR_HB_INTERVAL: HB_INTERVAL
-      });
+      startMayastor();
       // wait for the register message
       const sid = client.subscribe('register', (msg) => {
         client.unsubscribe(sid);
@@ -97,10 +119,23 @@ describe('nats', function () {
     });
   });
 
+  it('should report the state of the node in registration messages', (done) => {
+    const sid = client.subscribe('register', (msg) => {
+      client.unsubscribe(sid);
+      assertRegisterMessage(msg);
+      assertNodeState(msg);
+      done();
+    });
+  });
+
   it('should keep sending registration messages', (done) => {
     const sid = client.subscribe('register', (msg) => {
       client.unsubscribe(sid);
       assertRegisterMessage(msg);
+      // mayastor has been up for a while, so it must be ready
+      assert.strictEqual(JSON.parse(msg).status, 'Ready');
+      // not the first message
+      assert.isAbove(JSON.parse(msg).seq, 1);
       done();
     });
   });
@@ -112,6 +147,8 @@ describe('nats', function () {
         const sid = client.subscribe('register', (msg) => {
           client.unsubscribe(sid);
           assertRegisterMessage(msg);
+          // the sequence goes on after the reconnect
+          assert.isAbove(JSON.parse(msg).seq, 1);
           done();
         });
         startNats((err) => {
@@ -121,6 +158,45 @@ describe('nats', function () {
     });
   });
 
+  it('should send a deregistration message when asked to deregister', (done) => {
+    const sid = client.subscribe('deregister', (msg) => {
+      client.unsubscribe(sid);
+      const args = JSON.parse(msg);
+      assert.strictEqual(args.id, NODE_NAME);
+      done();
+    });
+    common.jsonrpcCommand(null, 'mayastor_deregister', (err) => {
+      if (err) done(err);
+    });
+  });
+
+  it('should not send registration messages when deregistered', function (done) {
+    // long enough for two heartbeats to be missed
+    const wait = 2500 * HB_INTERVAL;
+    // leave the test a margin of five more heartbeats on slow CI machines
+    this.timeout(wait + 5000 * HB_INTERVAL);
+    var received = false;
+    const sid = client.subscribe('register', () => {
+      received = true;
+    });
+    setTimeout(() => {
+      client.unsubscribe(sid);
+      assert.isFalse(received);
+      done();
+    }, wait);
+  });
+
+  it('should resume registration when asked to register', (done) => {
+    const sid = client.subscribe('register', (msg) => {
+      client.unsubscribe(sid);
+      assertRegisterMessage(msg);
+      done();
+    });
+    common.jsonrpcCommand(null, 'mayastor_register', (err) => {
+      if (err) done(err);
+    });
+  });
+
   it('should send a deregistration message when mayastor is shut down', (done) => {
     const sid = client.subscribe('deregister', (msg) => {
       client.unsubscribe(sid);
@@ -134,3 +210,79 @@ describe('nats', function () {
     });
   });
 });
+
+describe('nats with registration delay', function () {
+  const REGISTER_DELAY = 3;
+  var client;
+
+  this.timeout(10000);
+
+  before(startNats);
+
+  after((done) => {
+    if (client != null) {
+      client.close();
+      client = null;
+    }
+    stopNats(() => common.stopAll(done));
+  });
+
+  it('should delay the first registration message', (done) => {
+    client = nats.connect(`nats://${NATS_ENDPOINT}`);
+    client.on('connect', () => {
+      const start = Date.now();
+      const sid = client.subscribe('register', (msg) => {
+        client.unsubscribe(sid);
+        assertRegisterMessage(msg);
+        assert.isAtLeast(Date.now() - start, REGISTER_DELAY * 1000);
+        done();
+      });
+      startMayastor(['--mbus-register-delay', REGISTER_DELAY.toString()]);
+    });
+  });
+});
+
+describe('nats with custom subjects', function () {
+  const REGISTER_SUBJECT = 'custom.register';
+  const DEREGISTER_SUBJECT = 'custom.deregister';
+  var client;
+
+  this.timeout(5000);
+
+  before(startNats);
+
+  after((done) => {
+    if (client != null) {
+      client.close();
+      client = null;
+    }
+    stopNats(() => common.stopAll(done));
+  });
+
+  it('should send registration messages to the custom subject', (done) => {
+    client = nats.connect(`nats://${NATS_ENDPOINT}`);
+    client.on('connect', () => {
+      const sid = client.subscribe(REGISTER_SUBJECT, (msg) => {
+        client.unsubscribe(sid);
+        assertRegisterMessage(msg);
+        done();
+      });
+      startMayastor([
+        '--mbus-register-subject', REGISTER_SUBJECT,
+        '--mbus-deregister-subject', DEREGISTER_SUBJECT
+      ]);
+    });
+  });
+
+  it('should send deregistration message to the custom subject', (done) => {
+    const sid = client.subscribe(DEREGISTER_SUBJECT, (msg) => {
+      client.unsubscribe(sid);
+      const args = JSON.parse(msg);
+      assert.strictEqual(args.id, NODE_NAME);
+      done();
+    });
+    common.stopAll((err) => {
+      if (err) done(err);
+    });
+  });
+});
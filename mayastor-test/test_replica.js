@@ -9,6 +9,7 @@
 const assert = require('chai').assert;
 const async = require('async');
 const fs = require('fs');
+const net = require('net');
 const path = require('path');
 const { exec } = require('child_process');
 const grpc = require('grpc');
@@ -620,6 +621,30 @@ describe('replica', function () {
       );
     });
 
+    it('should show io stats of nvmf replica', (done) => {
+      common.execAsRoot(
+        common.getCmdPath('initiator'),
+        ['--offset=4096', uri, 'io-stats', '--blocks=2'],
+        (err, stdout) => {
+          if (err) return done(err);
+          const stats = {};
+          stdout
+            .split('\n')
+            .filter((line) => line.indexOf(': ') > 0)
+            .forEach((line) => {
+              const [key, val] = line.split(': ');
+              stats[key] = parseInt(val);
+            });
+          assert.isAtLeast(stats.num_read_ops, 2);
+          assert.isAtLeast(stats.num_write_ops, 2);
+          assert.isAtLeast(stats.bytes_read, 1024);
+          assert.isAtLeast(stats.bytes_written, 1024);
+          assert.equal(stats.errors, 0);
+          done();
+        }
+      );
+    });
+
     it('should destroy nvmf replica', (done) => {
       client.destroyReplica({ uuid: UUID }, (err, res) => {
         if (err) return done(err);
@@ -701,6 +726,40 @@ describe('replica', function () {
       });
     });
 
+    it('should take named snapshot on nvmf replica', (done) => {
+      common.execAsRoot(
+        common.getCmdPath('initiator'),
+        [uri, 'create-snapshot', '--name', 'before-upgrade'],
+        done
+      );
+    });
+
+    it('should list the snapshot by its name', (done) => {
+      client.listReplicas({}, (err, res) => {
+        if (err) return done(err);
+        const snap = res.replicas.find(
+          (ent) => ent.uuid === UUID + '-snap-before-upgrade'
+        );
+        assert(snap, 'named snapshot not found');
+        assert.equal(snap.pool, POOL);
+        assert.isTrue(
+          snap.uri.startsWith('bdev:///' + UUID + '-snap-before-upgrade')
+        );
+        done();
+      });
+    });
+
+    it('should fail to take snapshot with invalid name', (done) => {
+      common.execAsRoot(
+        common.getCmdPath('initiator'),
+        [uri, 'create-snapshot', '--name', 'not a valid label'],
+        (err) => {
+          assert.instanceOf(err, Error);
+          done();
+        }
+      );
+    });
+
     it('should destroy nvmf replica', (done) => {
       client.destroyReplica({ uuid: UUID }, (err, res) => {
         if (err) return done(err);
@@ -710,3 +769,573 @@ describe('replica', function () {
     });
   });
 });
+
+describe('initiator', function () {
+  const childFiles = ['/tmp/initiator_child0.img', '/tmp/initiator_child1.img'];
+  const blockFile = '/tmp/initiator_block';
+  const uris = childFiles.map((file) => `aio://${file}?blk_size=512`);
+  const diffFiles = ['/tmp/initiator_diff0.img', '/tmp/initiator_diff1.img'];
+  const configFile = '/tmp/initiator_config.yaml';
+
+  this.timeout(10000);
+
+  before((done) => {
+    // each child has different content, so that we can tell them apart
+    async.eachOf(
+      childFiles,
+      (file, i, next) => {
+        fs.writeFile(file, Buffer.alloc(1024 * 1024, 'ab'[i]), next);
+      },
+      done
+    );
+  });
+
+  after((done) => {
+    // the block files are created by initiator running as root
+    common.execAsRoot(
+      'rm',
+      ['-f'].concat(childFiles, diffFiles, [
+        configFile,
+        blockFile,
+        `${blockFile}.0`,
+        `${blockFile}.1`,
+        `${blockFile}.split.0000`,
+        `${blockFile}.split.0001`
+      ]),
+      (err) => {
+        if (err) console.log('Remove files failed', err);
+        done();
+      }
+    );
+  });
+
+  it('should read from each of the children', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      [uris.join(','), 'read', blockFile],
+      (err, stdout) => {
+        if (err) return done(err);
+        assert.match(stdout, /child 0 aio:\/\/\S+: ok/);
+        assert.match(stdout, /child 1 aio:\/\/\S+: ok/);
+        ['a', 'b'].forEach((ch, i) => {
+          const data = fs.readFileSync(`${blockFile}.${i}`).toString();
+          assert.lengthOf(data, 512);
+          assert.equal(data, ch.repeat(512));
+        });
+        done();
+      }
+    );
+  });
+
+  it('should reject a list of children for zero', (done) => {
+    const child = common.runAsRoot(common.getCmdPath('initiator'), [
+      uris.join(','),
+      'zero',
+      '--length=512'
+    ]);
+    let output = '';
+    child.stderr.on('data', (data) => {
+      output += data;
+    });
+    child.on('close', (code) => {
+      assert.notEqual(code, 0);
+      assert.match(output, /Only read and write accept a list of replicas/);
+      done();
+    });
+  });
+
+  it('should log read in a span with uri, offset and length', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      ['--log-json', '--offset=1024', uris[0], 'read', blockFile],
+      (err, stdout) => {
+        if (err) return done(err);
+        const spans = stdout
+          .split('\n')
+          .map((line) => {
+            try {
+              return JSON.parse(line).span;
+            } catch (err) {
+              return undefined;
+            }
+          })
+          .filter((span) => span && span.name === 'read');
+        assert.isNotEmpty(spans);
+        const span = spans.find((span) => span.length !== undefined);
+        assert(span, 'span with length not found');
+        assert.include(String(span.uri), uris[0]);
+        assert.equal(Number(span.offset), 1024);
+        assert.equal(Number(span.length), 512);
+        done();
+      }
+    );
+  });
+
+  it('should select the same random offsets for the same seed', (done) => {
+    const bench = (seed, cb) => {
+      common.execAsRoot(
+        common.getCmdPath('initiator'),
+        [
+          uris[0],
+          'bench',
+          '--pattern=rand',
+          '--count=16',
+          `--seed=${seed}`,
+          '--show-offsets'
+        ],
+        (err, stdout) => {
+          if (err) return cb(err);
+          const line = stdout.split('\n').find((l) => l.startsWith('offsets: '));
+          assert(line, 'offsets not printed');
+          cb(null, line.slice('offsets: '.length).split(',').map(Number));
+        }
+      );
+    };
+
+    async.series(
+      [(next) => bench(42, next), (next) => bench(42, next), (next) => bench(7, next)],
+      (err, res) => {
+        if (err) return done(err);
+        assert.lengthOf(res[0], 16);
+        res[0].forEach((off) => {
+          assert.equal(off % 512, 0);
+          assert.isBelow(off, 1024 * 1024);
+        });
+        assert.deepEqual(res[0], res[1]);
+        assert.notDeepEqual(res[0], res[2]);
+        done();
+      }
+    );
+  });
+
+  it('should probe a healthy replica', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      [uris[0], 'probe', '--timeout=5'],
+      (err, stdout) => {
+        if (err) return done(err);
+        assert.match(stdout, /^OK aio:\/\/\S+ latency_us=\d+$/m);
+        done();
+      }
+    );
+  });
+
+  it('should fail to probe a nonexistent replica', (done) => {
+    // the verdict is printed to stdout, so we cannot use execAsRoot
+    const child = common.runAsRoot(common.getCmdPath('initiator'), [
+      'aio:///tmp/initiator_nonexistent.img?blk_size=512',
+      'probe'
+    ]);
+    let stdout = '';
+    child.stdout.on('data', (data) => {
+      stdout += data;
+    });
+    child.on('close', (code) => {
+      assert.notEqual(code, 0);
+      assert.match(
+        stdout,
+        /^FAIL aio:\/\/\/tmp\/initiator_nonexistent\.img\S* latency_us=\d+: .+$/m
+      );
+      done();
+    });
+  });
+
+  it('should time out IO to a stalled device', (done) => {
+    const dmName = 'initiator_stall';
+    let loopDev;
+
+    // IO to a suspended device mapper device blocks until it is resumed
+    const resume = (cb) => {
+      common.execAsRoot('dmsetup', ['resume', dmName], cb);
+    };
+
+    async.series(
+      [
+        (next) => {
+          common.execAsRoot(
+            'losetup',
+            ['-f', '--show', childFiles[0]],
+            (err, stdout) => {
+              if (err) return next(err);
+              loopDev = stdout.trim();
+              next();
+            }
+          );
+        },
+        (next) => {
+          common.execAsRoot(
+            'dmsetup',
+            ['create', dmName, '--table', `0 2048 linear ${loopDev} 0`],
+            next
+          );
+        },
+        (next) => common.execAsRoot('dmsetup', ['suspend', dmName], next),
+        (next) => {
+          // the initiator exits on timeout, the stuck IO is left to the device
+          setTimeout(() => resume(() => {}), 5000);
+          // the error is logged to stdout, so we cannot use execAsRoot
+          const child = common.runAsRoot(common.getCmdPath('initiator'), [
+            '--io-timeout=1',
+            '--offset=4096',
+            `aio:///dev/mapper/${dmName}?blk_size=512`,
+            'read',
+            blockFile
+          ]);
+          let output = '';
+          child.stdout.on('data', (data) => {
+            output += data;
+          });
+          child.stderr.on('data', (data) => {
+            output += data;
+          });
+          child.on('close', (code) => {
+            assert.notEqual(code, 0);
+            assert.match(output, /IO timed out at offset 4096/);
+            next();
+          });
+        }
+      ],
+      (err) => {
+        resume(() => {
+          common.execAsRoot('dmsetup', ['remove', dmName], () => {
+            if (!loopDev) return done(err);
+            common.execAsRoot('losetup', ['-d', loopDev], () => done(err));
+          });
+        });
+      }
+    );
+  });
+
+  it('should measure connect and first IO latency', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      [uris[0], 'connect-latency'],
+      (err, stdout) => {
+        if (err) return done(err);
+        const connect = stdout.match(/^connect_us: (\d+)$/m);
+        const firstIo = stdout.match(/^first_io_us: (\d+)$/m);
+        assert(connect, 'connect time not printed');
+        assert(firstIo, 'first IO time not printed');
+        assert.isAbove(Number(connect[1]), 0);
+        assert.isAbove(Number(firstIo[1]), 0);
+        done();
+      }
+    );
+  });
+
+  it('should copy data from one replica to another', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      [
+        '--offset=4096',
+        'copy',
+        `--src=${uris[0]}`,
+        `--dst=${uris[1]}`,
+        '--length=8192'
+      ],
+      (err) => {
+        if (err) return done(err);
+        const data = fs.readFileSync(childFiles[1]).toString();
+        assert.equal(data.slice(0, 4096), 'b'.repeat(4096));
+        assert.equal(data.slice(4096, 12288), 'a'.repeat(8192));
+        assert.equal(data.slice(12288), 'b'.repeat(1024 * 1024 - 12288));
+        done();
+      }
+    );
+  });
+
+  it('should write an incrementing pattern and read it back', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      [
+        '--offset=65536',
+        uris[0],
+        'write-pattern',
+        '--pattern=incrementing',
+        '--length=1024',
+        '--verify'
+      ],
+      (err, stdout) => {
+        if (err) return done(err);
+        assert.match(stdout, /^verified 1024 bytes$/m);
+        common.execAsRoot(
+          common.getCmdPath('initiator'),
+          ['--offset=66048', uris[0], 'read', blockFile],
+          (err) => {
+            if (err) return done(err);
+            // the second block continues where the first one ended
+            const data = fs.readFileSync(blockFile);
+            assert.lengthOf(data, 512);
+            data.forEach((byte, i) => assert.equal(byte, (512 + i) % 256));
+            done();
+          }
+        );
+      }
+    );
+  });
+
+  it('should read consecutive blocks to separate files', (done) => {
+    // the blocks have been written by the pattern test
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      [
+        '--offset=65536',
+        uris[0],
+        'read-split',
+        '--count=2',
+        `${blockFile}.split`
+      ],
+      (err) => {
+        if (err) return done(err);
+        assert.isFalse(fs.existsSync(`${blockFile}.split.0002`));
+        [0, 1].forEach((i) => {
+          const data = fs.readFileSync(`${blockFile}.split.000${i}`);
+          assert.lengthOf(data, 512);
+          data.forEach((byte, j) => assert.equal(byte, (i * 512 + j) % 256));
+        });
+        done();
+      }
+    );
+  });
+
+  it('should report changes of a watched block', (done) => {
+    const offset = 131072;
+    // the block is modified behind the back of the initiator
+    const fd = fs.openSync(childFiles[0], 'r+');
+    const child = common.runAsRoot(common.getCmdPath('initiator'), [
+      `--offset=${offset}`,
+      uris[0],
+      'watch',
+      '--interval=100',
+      '--format=ascii'
+    ]);
+    let output = '';
+    let written = 0;
+    child.stdout.on('data', (data) => {
+      output += data;
+      if (written === 0 && output.match(/^initial: /m)) {
+        fs.writeSync(fd, Buffer.alloc(512, 'x'), 0, 512, offset);
+        written++;
+      } else if (written === 1 && output.match(/^change 1: /m)) {
+        fs.writeSync(fd, Buffer.alloc(512, 'y'), 0, 512, offset);
+        written++;
+      } else if (written === 2 && output.match(/^change 2: /m)) {
+        written++;
+        child.kill('SIGINT');
+      }
+    });
+    child.stderr.on('data', (data) => {
+      output += data;
+    });
+    child.on('close', (code) => {
+      fs.closeSync(fd);
+      assert.equal(code, 0, output);
+      assert.match(output, /^initial: a{512}$/m);
+      assert.match(output, /^change 1: x{512}$/m);
+      assert.match(output, /^change 2: y{512}$/m);
+      assert.match(output, /^changes: 2$/m);
+      done();
+    });
+  });
+
+  it('should print a hex dump of the block read to stdout', (done) => {
+    const offset = 262144;
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      [`--offset=${offset}`, uris[0], 'read', '--display=hex', '-'],
+      (err, stdout) => {
+        if (err) return done(err);
+        const line =
+          ' 61 61 61 61 61 61 61 61  61 61 61 61 61 61 61 61  |aaaaaaaaaaaaaaaa|';
+        let expected = '';
+        for (let i = 0; i < 32; i++) {
+          const off = (offset + i * 16).toString(16).padStart(8, '0');
+          expected += `${off} ${line}\n`;
+        }
+        assert.equal(stdout, expected);
+        done();
+      }
+    );
+  });
+
+  it('should zero a range of blocks', (done) => {
+    const offset = 196608;
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      [`--offset=${offset}`, uris[0], 'zero', '--length=1024'],
+      (err, stdout) => {
+        if (err) return done(err);
+        assert.match(stdout, /^zeroed 1024 bytes$/m);
+        // the neighbouring blocks must stay intact
+        const fd = fs.openSync(childFiles[0], 'r');
+        const data = Buffer.alloc(2048);
+        fs.readSync(fd, data, 0, 2048, offset - 512);
+        fs.closeSync(fd);
+        assert.equal(data.slice(0, 512).toString(), 'a'.repeat(512));
+        assert.isTrue(data.slice(512, 1536).equals(Buffer.alloc(1024)));
+        assert.equal(data.slice(1536).toString(), 'a'.repeat(512));
+        done();
+      }
+    );
+  });
+
+  it('should not zero a range which is not aligned to blocks', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      ['--offset=196608', uris[0], 'zero', '--length=100'],
+      (err) => {
+        assert.instanceOf(err, Error);
+        done();
+      }
+    );
+  });
+
+  it('should fall back to another socket if the default one is in use', (done) => {
+    const sock = '/tmp/initiator.sock';
+    // a stale socket of a previous run would make listen fail
+    common.execAsRoot('rm', ['-f', sock], (err) => {
+      if (err) return done(err);
+      const server = net.createServer();
+      server.listen(sock, () => {
+        // the error is logged to stdout, so we cannot use execAsRoot
+        const child = common.runAsRoot(common.getCmdPath('initiator'), [
+          uris[0],
+          'connect'
+        ]);
+        let output = '';
+        child.stdout.on('data', (data) => {
+          output += data;
+        });
+        child.stderr.on('data', (data) => {
+          output += data;
+        });
+        child.on('close', (code) => {
+          server.close();
+          assert.equal(code, 0, output);
+          assert.match(output, /is in use, listening on \/tmp\/initiator\.\d+\.sock/);
+          done();
+        });
+      });
+    });
+  });
+
+  it('should report the blocks which differ between two replicas', (done) => {
+    const diffUris = diffFiles.map((file) => `aio://${file}?blk_size=512`);
+    // the error is logged to stdout, so we cannot use execAsRoot
+    const diff = (cb) => {
+      const child = common.runAsRoot(common.getCmdPath('initiator'), [
+        'diff',
+        `--a=${diffUris[0]}`,
+        `--b=${diffUris[1]}`,
+        '--length=65536'
+      ]);
+      let output = '';
+      child.stdout.on('data', (data) => {
+        output += data;
+      });
+      child.on('close', (code) => cb(code, output));
+    };
+
+    diffFiles.forEach((file) => {
+      fs.writeFileSync(file, Buffer.alloc(65536, 'c'));
+    });
+    diff((code, output) => {
+      assert.equal(code, 0, output);
+      assert.notMatch(output, /^differ: /m);
+      assert.match(output, /^differing blocks: 0 of 128$/m);
+
+      const fd = fs.openSync(diffFiles[1], 'r+');
+      fs.writeSync(fd, Buffer.alloc(512, 'x'), 0, 512, 4096);
+      fs.closeSync(fd);
+      diff((code, output) => {
+        assert.notEqual(code, 0);
+        assert.match(output, /^differ: 4096\.\.4608$/m);
+        assert.equal(output.match(/^differ: /gm).length, 1);
+        assert.match(output, /^differing blocks: 1 of 128$/m);
+        done();
+      });
+    });
+  });
+
+  it('should not copy between replicas with different block size', (done) => {
+    // the error is logged to stdout, so we cannot use execAsRoot
+    const child = common.runAsRoot(common.getCmdPath('initiator'), [
+      'copy',
+      `--src=${uris[0]}`,
+      `--dst=aio://${childFiles[1]}?blk_size=4096`,
+      '--length=4096'
+    ]);
+    let output = '';
+    child.stdout.on('data', (data) => {
+      output += data;
+    });
+    child.stderr.on('data', (data) => {
+      output += data;
+    });
+    child.on('close', (code) => {
+      assert.notEqual(code, 0);
+      assert.match(output, /Block size of \S+ \(512\) differs/);
+      done();
+    });
+  });
+
+  it('should log the tagged DMA allocations', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      ['--tag=leak-hunt', '--verbose', uris[0], 'read', blockFile],
+      (err, stdout) => {
+        if (err) return done(err);
+        const lines = stdout
+          .split('\n')
+          .filter((line) => line.match(/tag=leak-hunt/));
+        const allocated = lines
+          .map((line) => line.match(/allocated DMA buffer (0x[0-9a-f]+)/))
+          .filter((m) => m);
+        assert.lengthOf(allocated, 1, stdout);
+        // each allocation is paired with the free of the same buffer
+        const freed = lines.filter(
+          (line) => line.indexOf(`freed DMA buffer ${allocated[0][1]}`) >= 0
+        );
+        assert.lengthOf(freed, 1, stdout);
+        done();
+      }
+    );
+  });
+
+  it('should dump the default config without iSCSI and NVMf', (done) => {
+    common.execAsRoot(
+      common.getCmdPath('initiator'),
+      ['--dump-config'],
+      (err, stdout) => {
+        if (err) return done(err);
+        assert.match(stdout, /^ {2}iscsi_enable: false$/m);
+        assert.match(stdout, /^ {2}nvmf_enable: false$/m);
+        assert.match(stdout, /^ {2}nvmf_nexus_port: 4421$/m);
+        done();
+      }
+    );
+  });
+
+  it('should dump the config read from the file', (done) => {
+    const config = [
+      'nexus_opts:',
+      '  iscsi_enable: true',
+      '  nvmf_nexus_port: 4499'
+    ].join('\n');
+    fs.writeFile(configFile, config, (err) => {
+      if (err) return done(err);
+      common.execAsRoot(
+        common.getCmdPath('initiator'),
+        [`--config=${configFile}`, '--dump-config'],
+        (err, stdout) => {
+          if (err) return done(err);
+          // the targets are disabled even if the file enables them
+          assert.match(stdout, /^ {2}iscsi_enable: false$/m);
+          assert.match(stdout, /^ {2}nvmf_enable: false$/m);
+          assert.match(stdout, /^ {2}nvmf_nexus_port: 4499$/m);
+          done();
+        }
+      );
+    });
+  });
+});
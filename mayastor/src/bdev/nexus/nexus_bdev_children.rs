@@ -51,6 +51,7 @@ use crate::{
         VerboseError,
     },
     core::Bdev,
+    nats::FaultReason,
     nexus_uri::{bdev_create, bdev_destroy, NexusBdevError},
 };
 
@@ -115,7 +116,7 @@ impl Nexus {
                     e.verbose()
                 );
                 match self.get_child_by_name(uri) {
-                    Ok(child) => child.fault(),
+                    Ok(child) => child.fault(FaultReason::RebuildFailed),
                     Err(e) => error!(
                         "Failed to find newly added child {}, error: {}",
                         uri,
@@ -274,7 +275,11 @@ impl Nexus {
     }
 
     /// fault a child device and reconfigure the IO channels
-    pub async fn fault_child(&mut self, name: &str) -> Result<(), Error> {
+    pub async fn fault_child(
+        &mut self,
+        name: &str,
+        reason: FaultReason,
+    ) -> Result<(), Error> {
         trace!("{}: fault child request for {}", self.name, name);
 
         if self.child_count < 2 {
@@ -290,7 +295,7 @@ impl Nexus {
         let result = match self.children.iter_mut().find(|c| c.name == name) {
             Some(child) => {
                 if child.status() != ChildStatus::Faulted {
-                    child.fault();
+                    child.fault(reason);
                     self.reconfigure(DREvent::ChildFault).await;
                 }
                 Ok(())
@@ -21,6 +21,7 @@ use crate::{
         VerboseError,
     },
     core::Reactors,
+    nats::FaultReason,
     rebuild::{ClientOperations, RebuildError, RebuildJob, RebuildState},
 };
 
@@ -259,7 +260,7 @@ impl Nexus {
                 {
                     // todo: retry rebuild using another child as source?
                 }
-                recovering_child.fault();
+                recovering_child.fault(FaultReason::RebuildFailed);
                 error!(
                     "Rebuild job for child {} of nexus {} failed, error: {}",
                     &job.destination,
@@ -268,7 +269,7 @@ impl Nexus {
                 );
             }
             _ => {
-                recovering_child.fault();
+                recovering_child.fault(FaultReason::RebuildFailed);
                 error!(
                     "Rebuild job for child {} of nexus {} failed with state {:?}",
                     &job.destination,
@@ -12,6 +12,7 @@ use crate::{
         NexusErrStore,
     },
     core::{Bdev, BdevHandle, CoreError, Descriptor, DmaBuf},
+    nats::{emit_child_fault, emit_replica_state, FaultReason, ReplicaState},
     nexus_uri::{bdev_destroy, NexusBdevError},
     rebuild::{ClientOperations, RebuildJob},
     subsys::Config,
@@ -237,6 +238,7 @@ impl NexusChild {
         };
 
         self.state = ChildState::Open;
+        self.emit_state(ReplicaState::Online);
 
         debug!("{}: child {} opened successfully", self.parent, self.name);
 
@@ -244,10 +246,30 @@ impl NexusChild {
     }
 
     /// Fault the child following an unrecoverable error
-    pub(crate) fn fault(&mut self) {
+    pub(crate) fn fault(&mut self, reason: FaultReason) {
         self.close();
         self.status_reasons.fatal_error();
         NexusChild::save_state_change();
+        self.emit_fault(reason);
+    }
+
+    /// Let the control plane know about the fault right away rather than
+    /// with the next heartbeat. The event is just queued for the message bus
+    /// if it is running, so that the IO path never waits for the bus, and
+    /// the bus keeps it until the NATS server is reachable.
+    fn emit_fault(&self, reason: FaultReason) {
+        if let Err(err) = emit_child_fault(&self.parent, &self.name, reason) {
+            debug!("Fault event of child {} not published: {}", self.name, err);
+        }
+    }
+
+    /// Let the control plane know that the replica has gone online or
+    /// offline. The message bus publishes the state once it settles, so
+    /// that a flapping child does not flood the bus.
+    fn emit_state(&self, state: ReplicaState) {
+        if let Err(err) = emit_replica_state(&self.name, state) {
+            debug!("State of child {} not published: {}", self.name, err);
+        }
     }
     /// Set the child as out of sync with the nexus
     /// It requires a full rebuild before it can service IO
@@ -362,6 +384,9 @@ impl NexusChild {
         drop(desc);
 
         // we leave the child structure around for when we want reopen it
+        if self.state == ChildState::Open {
+            self.emit_state(ReplicaState::Offline);
+        }
         self.state = ChildState::Closed;
         self.state
     }
@@ -19,6 +19,7 @@ use crate::{
         nexus_io::{io_status, io_type},
     },
     core::{Cores, Reactors},
+    nats::FaultReason,
     subsys::Config,
 };
 
@@ -299,7 +300,11 @@ impl Nexus {
                         {
                             let child_name = child.name.clone();
                             info!("Faulting child {}", child_name);
-                            if nexus.fault_child(&child_name).await.is_err() {
+                            if nexus
+                                .fault_child(&child_name, FaultReason::IoErrors)
+                                .await
+                                .is_err()
+                            {
                                 error!(
                                     "Failed to fault the child {}",
                                     child_name,
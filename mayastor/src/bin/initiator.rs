@@ -8,23 +8,39 @@ extern crate tracing;
 use std::{
     fmt,
     fs,
+    future::Future,
     io::{self, Write},
+    ops::{Deref, DerefMut},
+    os::{raw::c_void, unix::net::UnixStream},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ErrorKind, SubCommand};
+use once_cell::sync::OnceCell;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use spdk_sys::{spdk_poller, spdk_poller_register, spdk_poller_unregister};
+use tracing::{field, instrument, Span};
 
 use mayastor::{
     core::{
         mayastor_env_stop,
         Bdev,
+        BdevHandle,
         CoreError,
+        DmaBuf,
         DmaError,
         MayastorEnvironment,
         Reactor,
     },
     jsonrpc::print_error_chain,
     logger,
-    nexus_uri::{bdev_create, NexusBdevError},
+    nexus_uri::{bdev_create, bdev_destroy, NexusBdevError},
     subsys,
     subsys::Config,
 };
@@ -78,6 +94,214 @@ impl From<io::Error> for Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Default path of the json-rpc socket of the initiator
+const RPC_SOCKET: &str = "/tmp/initiator.sock";
+
+/// Max number of blocks transferred by a single IO of the copy
+const COPY_CHUNK_BLOCKS: u64 = 128;
+
+/// Max time to wait for a single IO to complete (unlimited if not set)
+static IO_TIMEOUT: OnceCell<Duration> = OnceCell::new();
+
+/// Label of the DMA buffers allocated by this run (see TaggedBuf)
+static DMA_TAG: OnceCell<String> = OnceCell::new();
+
+/// Context of the poller behind a Timer.
+struct TimerCtx {
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+extern "C" fn timer_fired(ctx: *mut c_void) -> i32 {
+    let ctx = unsafe { &mut *(ctx as *mut TimerCtx) };
+    ctx.fired = true;
+    if let Some(waker) = ctx.waker.take() {
+        waker.wake();
+    }
+    0
+}
+
+/// Future resolving when the time has elapsed. It is backed by an SPDK
+/// poller, which wakes the task when it fires, so that waiting does not keep
+/// the reactor busy.
+struct Timer {
+    poller: *mut spdk_poller,
+    ctx: Box<TimerCtx>,
+}
+
+impl Timer {
+    fn new(time: Duration) -> Self {
+        let mut ctx = Box::new(TimerCtx {
+            fired: false,
+            waker: None,
+        });
+        let poller = unsafe {
+            spdk_poller_register(
+                Some(timer_fired),
+                &mut *ctx as *mut TimerCtx as *mut c_void,
+                time.as_micros() as u64,
+            )
+        };
+        Timer {
+            poller,
+            ctx,
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Self::Output> {
+        if self.ctx.fired {
+            Poll::Ready(())
+        } else {
+            self.ctx.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { spdk_poller_unregister(&mut self.poller) };
+    }
+}
+
+/// Future resolving to an error if the IO does not complete before the
+/// timer fires. The IO itself cannot be aborted and SPDK may still write to
+/// its buffer, so the buffer must be neither freed nor reused afterwards.
+/// Hence a fatal timeout terminates the process right away, otherwise the
+/// IO future, along with anything it owns, is leaked.
+struct IoTimeout<F> {
+    io: Option<Pin<Box<F>>>,
+    timer: Option<Timer>,
+    /// error message if the timer fires
+    msg: String,
+    fatal: bool,
+}
+
+impl<F, T, E> Future for IoTimeout<F>
+where
+    F: Future<Output = Result<T, E>>,
+    Error: From<E>,
+{
+    type Output = Result<T>;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Self::Output> {
+        let io = self.io.as_mut().expect("IO polled after timeout");
+        if let Poll::Ready(res) = io.as_mut().poll(cx) {
+            return Poll::Ready(res.map_err(Error::from));
+        }
+        let fired = match self.timer.as_mut() {
+            Some(timer) => Pin::new(timer).poll(cx).is_ready(),
+            None => false,
+        };
+        if !fired {
+            return Poll::Pending;
+        }
+        if self.fatal {
+            error!("{}", self.msg);
+            std::process::exit(-1);
+        }
+        std::mem::forget(self.io.take());
+        Poll::Ready(Err(Error {
+            msg: self.msg.clone(),
+        }))
+    }
+}
+
+/// Apply the IO timeout given on the command line to the IO at the offset.
+/// The buffer of the IO belongs to the caller, so the timeout is fatal.
+fn io_timeout<F, T>(offset: u64, io: F) -> IoTimeout<F>
+where
+    F: Future<Output = Result<T, CoreError>>,
+{
+    IoTimeout {
+        io: Some(Box::pin(io)),
+        timer: IO_TIMEOUT.get().map(|t| Timer::new(*t)),
+        msg: format!("IO timed out at offset {}", offset),
+        fatal: true,
+    }
+}
+
+/// Fail with the message if the future does not complete within the time.
+/// The future is leaked in that case, so it must own the buffers of its IOs.
+fn with_timeout<F, T, E>(time: Duration, msg: String, io: F) -> IoTimeout<F>
+where
+    F: Future<Output = Result<T, E>>,
+    Error: From<E>,
+{
+    IoTimeout {
+        io: Some(Box::pin(io)),
+        timer: Some(Timer::new(time)),
+        msg,
+        fatal: false,
+    }
+}
+
+/// DMA buffer whose allocation and free are logged at debug level with the
+/// label given by --tag, so that leaked buffers can be attributed to the
+/// run. SPDK has no way to name the allocations itself.
+struct TaggedBuf(DmaBuf);
+
+impl Deref for TaggedBuf {
+    type Target = DmaBuf;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for TaggedBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for TaggedBuf {
+    fn drop(&mut self) {
+        if let Some(tag) = DMA_TAG.get() {
+            debug!(tag = %tag, "freed DMA buffer {:p}", *self.0);
+        }
+    }
+}
+
+/// Allocate a DMA buffer for IO to the bdev (see TaggedBuf).
+fn dma_malloc(
+    desc: &BdevHandle,
+    size: u64,
+) -> std::result::Result<TaggedBuf, DmaError> {
+    let buf = desc.dma_malloc(size)?;
+    if let Some(tag) = DMA_TAG.get() {
+        debug!(tag = %tag, "allocated DMA buffer {:p} of {} bytes", *buf, size);
+    }
+    Ok(TaggedBuf(buf))
+}
+
+/// How often sleep() checks the stop flag
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sleep for the interval or until the flag is set, whichever comes first.
+/// The flag is checked in between shorter timers, since setting it does not
+/// wake the task.
+async fn sleep(interval: Duration, stop: &AtomicBool) {
+    let deadline = Instant::now() + interval;
+    loop {
+        let now = Instant::now();
+        if now >= deadline || stop.load(Ordering::Relaxed) {
+            break;
+        }
+        Timer::new(std::cmp::min(deadline - now, STOP_CHECK_INTERVAL)).await;
+    }
+}
+
 /// Create initiator bdev.
 async fn create_bdev(uri: &str) -> Result<Bdev> {
     let bdev_name = bdev_create(uri).await?;
@@ -86,43 +310,701 @@ async fn create_bdev(uri: &str) -> Result<Bdev> {
     Ok(bdev)
 }
 
-/// Read block of data from bdev at given offset to a file.
-async fn read(uri: &str, offset: u64, file: &str) -> Result<()> {
+/// Run the operation against each of the URIs in turn and report the result
+/// for each of them. Every bdev is destroyed before moving to the next one,
+/// so that the children of a nexus can be examined independently. The index
+/// of the URI is passed to the operation.
+async fn for_each_uri<F, Fut>(uris: &[String], op: F) -> Result<()>
+where
+    F: Fn(usize, String) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    if uris.len() == 1 {
+        return op(0, uris[0].clone()).await;
+    }
+    let mut failed = 0;
+    for (i, uri) in uris.iter().enumerate() {
+        match op(i, uri.clone()).await {
+            Ok(()) => println!("child {} {}: ok", i, uri),
+            Err(err) => {
+                println!("child {} {}: failed: {}", i, uri, err);
+                failed += 1;
+            }
+        }
+        if let Err(err) = bdev_destroy(uri).await {
+            warn!("Failed to destroy {}: {}", uri, print_error_chain(&err));
+        }
+    }
+    if failed > 0 {
+        Err(Error {
+            msg: format!("{} of {} children failed", failed, uris.len()),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Name of the file for the data of the child with given index. If there
+/// are more children, the index is appended to the file name.
+fn child_file(file: &str, index: usize, count: usize) -> String {
+    if count > 1 {
+        format!("{}.{}", file, index)
+    } else {
+        file.to_owned()
+    }
+}
+
+/// Read block of data from bdev at given offset to a file, or to stdout if
+/// the file is "-".
+#[instrument(skip(file, display), fields(length = field::Empty))]
+async fn read(
+    uri: &str,
+    offset: u64,
+    file: &str,
+    display: ReadDisplay,
+) -> Result<()> {
     let bdev = create_bdev(uri).await?;
     let desc = Bdev::open(&bdev, false).unwrap().into_handle().unwrap();
-    let mut buf = desc
-        .dma_malloc(desc.get_bdev().block_len() as usize as u64)
-        .unwrap();
-    let n = desc.read_at(offset, &mut buf).await?;
-    fs::write(file, buf.as_slice())?;
+    let mut buf =
+        dma_malloc(&desc, desc.get_bdev().block_len() as usize as u64).unwrap();
+    Span::current().record("length", &buf.len());
+    let n = io_timeout(offset, desc.read_at(offset, &mut buf)).await?;
+    if file == "-" {
+        display.print(offset, buf.as_slice())?;
+    } else {
+        fs::write(file, buf.as_slice())?;
+    }
     info!("{} bytes read", n);
     Ok(())
 }
 
+/// Read the given number of consecutive blocks from bdev at given offset,
+/// each of them to a separate file named after the prefix and the index of
+/// the block (i.e. out.0000, out.0001, ...).
+#[instrument(skip(prefix))]
+async fn read_split(
+    uri: &str,
+    offset: u64,
+    count: u64,
+    prefix: &str,
+) -> Result<()> {
+    let bdev = create_bdev(uri).await?;
+    let desc = Bdev::open(&bdev, false)?.into_handle()?;
+    let block_len = desc.get_bdev().block_len() as u64;
+    let mut buf = dma_malloc(&desc, block_len)?;
+    for i in 0 .. count {
+        let off = offset + i * block_len;
+        io_timeout(off, desc.read_at(off, &mut buf)).await?;
+        fs::write(format!("{}.{:04}", prefix, i), buf.as_slice())?;
+    }
+    info!("{} blocks read", count);
+    Ok(())
+}
+
+/// Replace the non-printable characters by '.'.
+fn printable(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+/// Hex dump of the data in the format of hexdump -C, except that repeated
+/// lines are not collapsed. The offsets are those of the replica.
+fn hex_dump(offset: u64, data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (j, b) in line.iter().enumerate() {
+            if j == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!(" {:02x}", b));
+        }
+        dump.push_str(&format!(
+            "{:08x} {:<49}  |{}|\n",
+            offset + i as u64 * 16,
+            hex,
+            printable(line)
+        ));
+    }
+    dump
+}
+
+/// How the read subcommand prints the block to stdout
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReadDisplay {
+    /// hex dump with the offsets and the printable characters
+    Hex,
+    /// printable characters, 64 per line
+    Ascii,
+    /// the bytes as they are, for piping
+    Raw,
+}
+
+impl ReadDisplay {
+    /// Hex dump for a terminal, raw bytes if stdout is redirected.
+    fn for_stdout() -> Self {
+        if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+            ReadDisplay::Hex
+        } else {
+            ReadDisplay::Raw
+        }
+    }
+
+    /// Print the data read from the replica at the offset to stdout.
+    fn print(self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        match self {
+            ReadDisplay::Hex => {
+                out.write_all(hex_dump(offset, data).as_bytes())?
+            }
+            ReadDisplay::Ascii => {
+                for line in data.chunks(64) {
+                    writeln!(out, "{}", printable(line))?;
+                }
+            }
+            ReadDisplay::Raw => out.write_all(data)?,
+        }
+        out.flush()
+    }
+}
+
+/// How the watch subcommand prints the block
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WatchFormat {
+    Hex,
+    /// non-printable characters are replaced by '.'
+    Ascii,
+}
+
+impl WatchFormat {
+    fn format(self, data: &[u8]) -> String {
+        match self {
+            WatchFormat::Hex => {
+                data.iter().map(|b| format!("{:02x}", b)).collect()
+            }
+            WatchFormat::Ascii => printable(data),
+        }
+    }
+}
+
+/// Read the block at given offset in the interval and print it whenever it
+/// changes, until interrupted (Ctrl-C). The number of changes seen is
+/// printed at the end.
+#[instrument]
+async fn watch(
+    uri: &str,
+    offset: u64,
+    interval: Duration,
+    format: WatchFormat,
+) -> Result<()> {
+    // mayastor stops on SIGINT too, we just need to notice it first
+    let stop = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&stop))?;
+    let bdev = create_bdev(uri).await?;
+    let desc = Bdev::open(&bdev, false)?.into_handle()?;
+    let mut buf = dma_malloc(&desc, desc.get_bdev().block_len() as u64)?;
+    io_timeout(offset, desc.read_at(offset, &mut buf)).await?;
+    let mut last = buf.as_slice().to_vec();
+    println!("initial: {}", format.format(&last));
+    let mut changes = 0;
+    loop {
+        sleep(interval, &stop).await;
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        io_timeout(offset, desc.read_at(offset, &mut buf)).await?;
+        if buf.as_slice() != &last[..] {
+            changes += 1;
+            last = buf.as_slice().to_vec();
+            println!("change {}: {}", changes, format.format(&last));
+        }
+    }
+    println!("changes: {}", changes);
+    Ok(())
+}
+
 /// Write block of data from file to bdev at given offset.
+#[instrument(skip(file), fields(length = field::Empty))]
 async fn write(uri: &str, offset: u64, file: &str) -> Result<()> {
     let bdev = create_bdev(uri).await?;
     let bytes = fs::read(file)?;
     let desc = Bdev::open(&bdev, true).unwrap().into_handle().unwrap();
-    let mut buf = desc.dma_malloc(desc.get_bdev().block_len() as u64).unwrap();
+    let mut buf =
+        dma_malloc(&desc, desc.get_bdev().block_len() as u64).unwrap();
+    Span::current().record("length", &buf.len());
     let mut n = buf.as_mut_slice().write(&bytes[..]).unwrap();
     if n < buf.len() as usize {
         warn!("Writing a buffer which was not fully initialized from a file");
     }
-    n = desc.write_at(offset, &buf).await?;
+    n = io_timeout(offset, desc.write_at(offset, &buf)).await?;
     info!("{} bytes written", n);
     Ok(())
 }
 
-/// Create a snapshot.
-async fn create_snapshot(uri: &str) -> Result<()> {
+/// Data pattern written by write-pattern
+#[derive(Clone, Debug, PartialEq)]
+enum DataPattern {
+    /// the bytes repeated over and over
+    Bytes(Vec<u8>),
+    /// byte at offset i has value i modulo 256
+    Incrementing,
+    /// bytes generated by RNG seeded by the value
+    Random(u64),
+}
+
+impl DataPattern {
+    /// Parse the pattern given on the command line: hex string of the bytes,
+    /// "incrementing" or "random" (with the seed).
+    fn parse(val: &str, seed: u64) -> Result<Self> {
+        match val {
+            "incrementing" => Ok(Self::Incrementing),
+            "random" => Ok(Self::Random(seed)),
+            hex => {
+                let hex = hex.trim_start_matches("0x");
+                if hex.is_empty() || hex.len() % 2 != 0 {
+                    return Err(Error {
+                        msg: format!("Invalid hex pattern {}", val),
+                    });
+                }
+                (0 .. hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i .. i + 2], 16))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map(Self::Bytes)
+                    .map_err(|_| Error {
+                        msg: format!("Invalid hex pattern {}", val),
+                    })
+            }
+        }
+    }
+
+    /// Fill the buffer with the pattern.
+    fn fill(&self, buf: &mut [u8]) {
+        match self {
+            Self::Bytes(bytes) => {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = bytes[i % bytes.len()];
+                }
+            }
+            Self::Incrementing => {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = i as u8;
+                }
+            }
+            Self::Random(seed) => StdRng::seed_from_u64(*seed).fill(buf),
+        }
+    }
+}
+
+/// Write the pattern of the given length to bdev at given offset and
+/// optionally read it back to check that it has been written correctly.
+#[instrument(skip(pattern))]
+async fn write_pattern(
+    uri: &str,
+    offset: u64,
+    length: u64,
+    pattern: DataPattern,
+    verify: bool,
+) -> Result<()> {
+    let bdev = create_bdev(uri).await?;
+    let desc = Bdev::open(&bdev, true)?.into_handle()?;
+    let block_len = desc.get_bdev().block_len() as u64;
+    if length == 0 || length % block_len != 0 {
+        return Err(Error {
+            msg: format!(
+                "Length {} must be a non-zero multiple of block size {}",
+                length, block_len
+            ),
+        });
+    }
+    let mut buf = dma_malloc(&desc, length)?;
+    pattern.fill(buf.as_mut_slice());
+    let n = io_timeout(offset, desc.write_at(offset, &buf)).await?;
+    info!("{} bytes written", n);
+    if verify {
+        let mut check = dma_malloc(&desc, length)?;
+        io_timeout(offset, desc.read_at(offset, &mut check)).await?;
+        if let Some(pos) = buf
+            .as_slice()
+            .iter()
+            .zip(check.as_slice())
+            .position(|(w, r)| w != r)
+        {
+            return Err(Error {
+                msg: format!(
+                    "Data read back differ at offset {}",
+                    offset + pos as u64
+                ),
+            });
+        }
+        println!("verified {} bytes", length);
+    }
+    Ok(())
+}
+
+/// Write zeroes to the range of the bdev without transferring a zeroed
+/// buffer, unlike trim the range is guaranteed to read back as zeroes.
+#[instrument]
+async fn zero(uri: &str, offset: u64, length: u64) -> Result<()> {
+    let bdev = create_bdev(uri).await?;
+    let desc = Bdev::open(&bdev, true)?.into_handle()?;
+    let block_len = desc.get_bdev().block_len() as u64;
+    if length == 0 || length % block_len != 0 {
+        return Err(Error {
+            msg: format!(
+                "Length {} must be a non-zero multiple of block size {}",
+                length, block_len
+            ),
+        });
+    }
+    if offset % block_len != 0 {
+        return Err(Error {
+            msg: format!(
+                "Offset {} must be a multiple of block size {}",
+                offset, block_len
+            ),
+        });
+    }
+    let size = desc.get_bdev().size_in_bytes();
+    if offset + length > size {
+        return Err(Error {
+            msg: format!(
+                "Range {}..{} exceeds the size of the replica {}",
+                offset,
+                offset + length,
+                size
+            ),
+        });
+    }
+    let n = io_timeout(offset, desc.write_zeroes_at(offset, length)).await?;
+    println!("zeroed {} bytes", n);
+    Ok(())
+}
+
+/// Create a snapshot, which is named after the label if given.
+async fn create_snapshot(uri: &str, name: Option<&str>) -> Result<()> {
     let bdev = create_bdev(uri).await?;
     let h = Bdev::open(&bdev, true).unwrap().into_handle().unwrap();
-    let t = h.create_snapshot().await?;
-    info!("snapshot taken at {}", t);
+    let t = h.create_snapshot_with_label(name).await?;
+    match name {
+        Some(name) => info!("snapshot {} taken at {}", name, t),
+        None => info!("snapshot taken at {}", t),
+    }
+    Ok(())
+}
+
+/// Copy the byte range starting at the offset from one bdev to the same
+/// offset of another bdev, in chunks of up to COPY_CHUNK_BLOCKS blocks. The
+/// data are transferred through DMA buffers without staging them in a file.
+#[instrument]
+async fn copy(src: &str, dst: &str, offset: u64, length: u64) -> Result<()> {
+    let src_bdev = create_bdev(src).await?;
+    let dst_bdev = create_bdev(dst).await?;
+    let block_len =
+        check_range((src, &src_bdev), (dst, &dst_bdev), offset, length)?;
+
+    let src_desc = Bdev::open(&src_bdev, false)?.into_handle()?;
+    let dst_desc = Bdev::open(&dst_bdev, true)?.into_handle()?;
+    let chunk = COPY_CHUNK_BLOCKS * block_len;
+    let mut buf = dma_malloc(&src_desc, chunk.min(length.max(block_len)))?;
+    let mut copied = 0;
+    while copied < length {
+        let len = chunk.min(length - copied);
+        // the last chunk can be shorter
+        if len != buf.len() as u64 {
+            buf = dma_malloc(&src_desc, len)?;
+        }
+        let off = offset + copied;
+        io_timeout(off, src_desc.read_at(off, &mut buf)).await?;
+        io_timeout(off, dst_desc.write_at(off, &buf)).await?;
+        copied += len;
+    }
+    info!("{} bytes copied", copied);
+    Ok(())
+}
+
+/// Check that both bdevs have the same block size and that the byte range
+/// is aligned to it and fits in both of them. Returns the block size.
+fn check_range(
+    (a, a_bdev): (&str, &Bdev),
+    (b, b_bdev): (&str, &Bdev),
+    offset: u64,
+    length: u64,
+) -> Result<u64> {
+    let block_len = a_bdev.block_len() as u64;
+    if b_bdev.block_len() as u64 != block_len {
+        return Err(Error {
+            msg: format!(
+                "Block size of {} ({}) differs from block size of {} ({})",
+                a,
+                block_len,
+                b,
+                b_bdev.block_len()
+            ),
+        });
+    }
+    if offset % block_len != 0 || length % block_len != 0 {
+        return Err(Error {
+            msg: format!(
+                "Offset {} and length {} must be multiples of block size {}",
+                offset, length, block_len
+            ),
+        });
+    }
+    for (uri, bdev) in &[(a, a_bdev), (b, b_bdev)] {
+        let size = bdev.num_blocks() * block_len;
+        if offset + length > size {
+            return Err(Error {
+                msg: format!(
+                    "Range {}+{} is beyond the end of {} ({} bytes)",
+                    offset, length, uri, size
+                ),
+            });
+        }
+    }
+    Ok(block_len)
+}
+
+/// Compare the byte range starting at the offset of two bdevs block by
+/// block, reading up to COPY_CHUNK_BLOCKS blocks at a time from each, and
+/// print the ranges which differ followed by the number of differing blocks.
+/// It fails if any block differs.
+#[instrument]
+async fn diff(a: &str, b: &str, offset: u64, length: u64) -> Result<()> {
+    let a_bdev = create_bdev(a).await?;
+    let b_bdev = create_bdev(b).await?;
+    let block_len = check_range((a, &a_bdev), (b, &b_bdev), offset, length)?;
+
+    let a_desc = Bdev::open(&a_bdev, false)?.into_handle()?;
+    let b_desc = Bdev::open(&b_bdev, false)?.into_handle()?;
+    let chunk = COPY_CHUNK_BLOCKS * block_len;
+    let mut a_buf = dma_malloc(&a_desc, chunk.min(length.max(block_len)))?;
+    let mut b_buf = dma_malloc(&b_desc, a_buf.len())?;
+    // start of the range of differing blocks which is not printed yet
+    let mut differ_from: Option<u64> = None;
+    let mut differing = 0;
+    let mut compared = 0;
+    while compared < length {
+        let len = chunk.min(length - compared);
+        // the last chunk can be shorter
+        if len != a_buf.len() as u64 {
+            a_buf = dma_malloc(&a_desc, len)?;
+            b_buf = dma_malloc(&b_desc, len)?;
+        }
+        let off = offset + compared;
+        io_timeout(off, a_desc.read_at(off, &mut a_buf)).await?;
+        io_timeout(off, b_desc.read_at(off, &mut b_buf)).await?;
+        let blocks = a_buf
+            .as_slice()
+            .chunks(block_len as usize)
+            .zip(b_buf.as_slice().chunks(block_len as usize));
+        for (i, (a_block, b_block)) in blocks.enumerate() {
+            let block_off = off + i as u64 * block_len;
+            if a_block != b_block {
+                differing += 1;
+                differ_from.get_or_insert(block_off);
+            } else if let Some(from) = differ_from.take() {
+                println!("differ: {}..{}", from, block_off);
+            }
+        }
+        compared += len;
+    }
+    if let Some(from) = differ_from {
+        println!("differ: {}..{}", from, offset + length);
+    }
+    println!("differing blocks: {} of {}", differing, length / block_len);
+    if differing > 0 {
+        Err(Error {
+            msg: format!("{} and {} differ", a, b),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Print IO counters of the bdev. The bdev is created by this process, so in
+/// order to see non-zero numbers the given number of blocks can be read and
+/// written back in place (the data don't change) before the stats are taken.
+/// IOs which failed in the process are reported as errors. The IO timeout
+/// ends the process, so the buffer is reused only after completed IOs.
+async fn io_stats(uri: &str, offset: u64, blocks: u64) -> Result<()> {
+    let bdev = create_bdev(uri).await?;
+    let desc = Bdev::open(&bdev, true).unwrap().into_handle().unwrap();
+    let block_len = desc.get_bdev().block_len() as u64;
+    let mut buf = dma_malloc(&desc, block_len).unwrap();
+    let mut errors = 0;
+    for i in 0 .. blocks {
+        let off = offset + i * block_len;
+        if let Err(err) = io_timeout(off, desc.read_at(off, &mut buf)).await {
+            warn!("{}", err);
+            errors += 1;
+            continue;
+        }
+        if let Err(err) = io_timeout(off, desc.write_at(off, &buf)).await {
+            warn!("{}", err);
+            errors += 1;
+        }
+    }
+    let stats = bdev.stats().await.map_err(|errno| Error {
+        msg: format!("Failed to get stats of {}: errno {}", bdev.name(), errno),
+    })?;
+    println!("num_read_ops: {}", stats.num_read_ops);
+    println!("num_write_ops: {}", stats.num_write_ops);
+    println!("num_unmap_ops: {}", stats.num_unmap_ops);
+    println!("bytes_read: {}", stats.bytes_read);
+    println!("bytes_written: {}", stats.bytes_written);
+    println!("bytes_unmapped: {}", stats.bytes_unmapped);
+    println!("errors: {}", errors);
+    Ok(())
+}
+
+/// Access pattern of the benchmark
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pattern {
+    Seq,
+    Rand,
+}
+
+/// Offsets of the IOs issued by the benchmark. Random offsets are block
+/// aligned and generated by a seeded RNG, so that the runs can be reproduced.
+fn bench_offsets(
+    pattern: Pattern,
+    seed: u64,
+    count: u64,
+    start: u64,
+    block_len: u64,
+    num_blocks: u64,
+) -> Vec<u64> {
+    let first_block = start / block_len;
+    let blocks = num_blocks.saturating_sub(first_block).max(1);
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0 .. count)
+        .map(|i| {
+            let block = match pattern {
+                Pattern::Seq => i % blocks,
+                Pattern::Rand => rng.gen_range(0, blocks),
+            };
+            (first_block + block) * block_len
+        })
+        .collect()
+}
+
+/// Read the given number of blocks from the bdev one at a time and print the
+/// performance numbers.
+#[instrument(skip(show_offsets), fields(length = field::Empty))]
+async fn bench(
+    uri: &str,
+    offset: u64,
+    count: u64,
+    pattern: Pattern,
+    seed: u64,
+    show_offsets: bool,
+) -> Result<()> {
+    let bdev = create_bdev(uri).await?;
+    let desc = Bdev::open(&bdev, false).unwrap().into_handle().unwrap();
+    let block_len = desc.get_bdev().block_len() as u64;
+    let mut buf = dma_malloc(&desc, block_len).unwrap();
+    Span::current().record("length", &(count * block_len));
+    let offsets = bench_offsets(
+        pattern,
+        seed,
+        count,
+        offset,
+        block_len,
+        bdev.num_blocks(),
+    );
+
+    let start = Instant::now();
+    for off in &offsets {
+        io_timeout(*off, desc.read_at(*off, &mut buf)).await?;
+    }
+    let elapsed = start.elapsed();
+
+    println!("pattern: {:?}", pattern);
+    println!("ios: {}", count);
+    println!("bytes: {}", count * block_len);
+    println!("elapsed_us: {}", elapsed.as_micros());
+    println!(
+        "iops: {}",
+        (count as f64 / elapsed.as_secs_f64().max(1e-6)) as u64
+    );
+    if show_offsets {
+        let offsets: Vec<String> =
+            offsets.iter().map(|off| off.to_string()).collect();
+        println!("offsets: {}", offsets.join(","));
+    }
+    Ok(())
+}
+
+/// Open the bdev and read a single block from it within the timeout, and
+/// print a one-line verdict with the latency. Meant to be used as liveness
+/// probe of a replica.
+async fn probe(uri: &str, offset: u64, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    let res = with_timeout(
+        timeout,
+        format!("probe timed out after {:?}", timeout),
+        async {
+            let bdev = create_bdev(uri).await?;
+            let desc = Bdev::open(&bdev, false)?.into_handle()?;
+            let mut buf =
+                dma_malloc(&desc, desc.get_bdev().block_len() as u64)?;
+            desc.read_at(offset, &mut buf).await?;
+            Ok::<(), Error>(())
+        },
+    )
+    .await;
+    let latency = start.elapsed().as_micros();
+    match res {
+        Ok(()) => {
+            println!("OK {} latency_us={}", uri, latency);
+            Ok(())
+        }
+        Err(err) => {
+            println!("FAIL {} latency_us={}: {}", uri, latency, err);
+            Err(err)
+        }
+    }
+}
+
+/// Measure separately how long it takes to connect to the target and open
+/// the bdev, and how long the first single block read takes afterwards.
+async fn connect_latency(uri: &str, offset: u64) -> Result<()> {
+    let start = Instant::now();
+    let bdev = create_bdev(uri).await?;
+    let desc = Bdev::open(&bdev, false)?.into_handle()?;
+    let connect_time = start.elapsed();
+    let mut buf = dma_malloc(&desc, desc.get_bdev().block_len() as u64)?;
+    let start = Instant::now();
+    io_timeout(offset, desc.read_at(offset, &mut buf)).await?;
+    let io_time = start.elapsed();
+    println!("connect_us: {}", connect_time.as_micros());
+    println!("first_io_us: {}", io_time.as_micros());
     Ok(())
 }
 
+/// Return the default path of the json-rpc socket unless another process
+/// listens on it (i.e. an initiator running in parallel), in which case a
+/// path unique to this process is used instead. A stale socket left behind
+/// by a previous run is not in use and gets replaced.
+fn rpc_socket() -> String {
+    if UnixStream::connect(RPC_SOCKET).is_ok() {
+        let path = format!("/tmp/initiator.{}.sock", std::process::id());
+        warn!("{} is in use, listening on {} instead", RPC_SOCKET, path);
+        path
+    } else {
+        RPC_SOCKET.to_owned()
+    }
+}
+
 /// Connect to the target.
 async fn connect(uri: &str) -> Result<()> {
     let _bdev = create_bdev(uri).await?;
@@ -130,12 +1012,43 @@ async fn connect(uri: &str) -> Result<()> {
     Ok(())
 }
 
+/// Configuration of the initiator read from the file if given, otherwise the
+/// default one. This tool is just a client, so don't start iSCSI or NVMEoF
+/// services in either case.
+fn initiator_config(file: Option<&str>) -> Config {
+    let mut cfg = match file {
+        Some(file) => {
+            // Config::read() reverts to the defaults if there is no file
+            if fs::metadata(file).is_err() {
+                clap::Error::with_description(
+                    &format!("Configuration file {} does not exist", file),
+                    ErrorKind::InvalidValue,
+                )
+                .exit();
+            }
+            Config::read(file).unwrap_or_else(|_| {
+                clap::Error::with_description(
+                    &format!("Invalid configuration file {}", file),
+                    ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+        }
+        None => Config::default(),
+    };
+    cfg.nexus_opts.iscsi_enable = false;
+    cfg.nexus_opts.nvmf_enable = false;
+    cfg
+}
+
 fn main() {
     let matches = App::new("Test initiator for nexus replica")
         .about("Connect, read or write a block to a nexus replica using its URI")
+        // the URIs of copy and diff are given by their own options
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("URI")
-            .help("URI of the replica to connect to")
-            .required(true)
+            .help("URI of the replica to connect to (comma separated list for read and write to run against each of them)")
+            .required_unless("dump-config")
             .index(1))
         .arg(Arg::with_name("offset")
             .short("o")
@@ -143,52 +1056,427 @@ fn main() {
             .value_name("NUMBER")
             .help("Offset of IO operation on the replica in bytes (default 0)")
             .takes_value(true))
+        .arg(Arg::with_name("io-timeout")
+            .short("t")
+            .long("io-timeout")
+            .value_name("SECONDS")
+            .help("Exit with an error if an IO does not complete within the time (default unlimited)")
+            .takes_value(true))
+        .arg(Arg::with_name("log-json")
+            .long("log-json")
+            .help("Print log messages as json objects including span fields"))
+        .arg(Arg::with_name("tag")
+            .long("tag")
+            .value_name("LABEL")
+            .help("Log allocations and frees of the DMA buffers with the label to attribute leaks to this run (at debug level, see --verbose)")
+            .takes_value(true))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Print debug log messages"))
+        .arg(Arg::with_name("config")
+            .short("y")
+            .long("config")
+            .value_name("FILE")
+            .help("YAML configuration file to start from (iSCSI and NVMf are disabled regardless)")
+            .takes_value(true))
+        .arg(Arg::with_name("dump-config")
+            .long("dump-config")
+            .help("Print the effective configuration as YAML and exit"))
         .subcommand(SubCommand::with_name("connect")
             .about("Connect to and disconnect from the replica"))
         .subcommand(SubCommand::with_name("read")
             .about("Read bytes from the replica")
             .arg(Arg::with_name("FILE")
-                .help("File to write data that were read from the replica")
+                .help("File to write data that were read from the replica (suffixed by the index of the replica if there are more), - for stdout")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("display")
+                .short("d")
+                .long("display")
+                .help("How to print the data to stdout (default hex on a terminal, raw otherwise)")
+                .possible_values(&["hex", "ascii", "raw"])
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("read-split")
+            .about("Read consecutive blocks from the replica, each to a separate file")
+            .arg(Arg::with_name("count")
+                .short("c")
+                .long("count")
+                .value_name("NUMBER")
+                .help("Number of blocks to read")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("PREFIX")
+                .help("Prefix of the files for the blocks, which is followed by the index of the block (i.e. out.0000)")
                 .required(true)
                 .index(1)))
+        .subcommand(SubCommand::with_name("watch")
+            .about("Read a block from the replica repeatedly and print it whenever it changes, until interrupted")
+            .arg(Arg::with_name("interval")
+                .short("i")
+                .long("interval")
+                .value_name("MILLISECONDS")
+                .help("Time between the reads (default 500)")
+                .takes_value(true))
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .help("How to print the block (default hex)")
+                .possible_values(&["hex", "ascii"])
+                .takes_value(true)))
         .subcommand(SubCommand::with_name("write")
             .about("Write bytes to the replica")
             .arg(Arg::with_name("FILE")
                 .help("File to read data from that will be written to the replica")
                 .required(true)
                 .index(1)))
+        .subcommand(SubCommand::with_name("write-pattern")
+            .about("Write generated data to the replica")
+            .arg(Arg::with_name("pattern")
+                .short("p")
+                .long("pattern")
+                .value_name("PATTERN")
+                .help("Hex string of the bytes to repeat, \"incrementing\" or \"random\"")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("seed")
+                .short("s")
+                .long("seed")
+                .value_name("NUMBER")
+                .help("Seed of the random pattern (default 0)")
+                .takes_value(true))
+            .arg(Arg::with_name("length")
+                .short("l")
+                .long("length")
+                .value_name("NUMBER")
+                .help("Number of bytes to write (multiple of the block size)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("verify")
+                .long("verify")
+                .help("Read the data back and check that they match the pattern")))
+        .subcommand(SubCommand::with_name("zero")
+            .about("Write zeroes to the replica without reading them from a file")
+            .arg(Arg::with_name("length")
+                .short("l")
+                .long("length")
+                .value_name("NUMBER")
+                .help("Number of bytes to zero (multiple of the block size)")
+                .required(true)
+                .takes_value(true)))
         .subcommand(SubCommand::with_name("create-snapshot")
-            .about("Create a snapshot on the replica"))
+            .about("Create a snapshot on the replica")
+            .arg(Arg::with_name("name")
+                .short("n")
+                .long("name")
+                .value_name("LABEL")
+                .help("Name the snapshot <replica>-snap-LABEL instead of using the snapshot time (up to 16 alphanumerics, '-', '_' or '.')")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("bench")
+            .about("Measure read performance of the replica")
+            .arg(Arg::with_name("count")
+                .short("c")
+                .long("count")
+                .value_name("NUMBER")
+                .help("Number of blocks to read (default 1000)")
+                .takes_value(true))
+            .arg(Arg::with_name("pattern")
+                .short("p")
+                .long("pattern")
+                .help("Access pattern (default seq)")
+                .possible_values(&["seq", "rand"])
+                .takes_value(true))
+            .arg(Arg::with_name("seed")
+                .short("s")
+                .long("seed")
+                .value_name("NUMBER")
+                .help("Seed of the random pattern (default 0)")
+                .takes_value(true))
+            .arg(Arg::with_name("show-offsets")
+                .long("show-offsets")
+                .help("Print offsets of the IOs to be able to reproduce the run")))
+        .subcommand(SubCommand::with_name("io-stats")
+            .about("Print IO counters of the replica")
+            .arg(Arg::with_name("blocks")
+                .short("b")
+                .long("blocks")
+                .value_name("NUMBER")
+                .help("Number of blocks to read and write back in place before printing the counters (default 0)")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("probe")
+            .about("Check that a block can be read from the replica within the timeout (liveness probe)")
+            .arg(Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Max time for opening the replica and reading the block (default 5)")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("connect-latency")
+            .about("Measure the time to connect to the replica and the time of the first read separately"))
+        .subcommand(SubCommand::with_name("diff")
+            .about("Compare bytes of two replicas at the same offset block by block and print the ranges which differ")
+            .arg(Arg::with_name("a")
+                .long("a")
+                .value_name("URI")
+                .help("URI of the first replica")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("b")
+                .long("b")
+                .value_name("URI")
+                .help("URI of the second replica (must have the same block size)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("length")
+                .short("l")
+                .long("length")
+                .value_name("NUMBER")
+                .help("Number of bytes to compare (multiple of the block size)")
+                .required(true)
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("copy")
+            .about("Copy bytes from one replica to the same offset of another replica")
+            .arg(Arg::with_name("src")
+                .long("src")
+                .value_name("URI")
+                .help("URI of the replica to copy the data from")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("dst")
+                .long("dst")
+                .value_name("URI")
+                .help("URI of the replica to copy the data to (must have the same block size)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("length")
+                .short("l")
+                .long("length")
+                .value_name("NUMBER")
+                .help("Number of bytes to copy (multiple of the block size)")
+                .required(true)
+                .takes_value(true)))
         .get_matches();
 
-    logger::init("INFO");
+    // the data read to stdout must not be mixed with the log
+    let read_to_stdout = matches
+        .subcommand_matches("read")
+        .map_or(false, |m| m.value_of("FILE") == Some("-"));
+    if let Some(tag) = matches.value_of("tag") {
+        DMA_TAG.set(tag.to_owned()).unwrap();
+    }
+    let level = if matches.is_present("verbose") {
+        "DEBUG"
+    } else {
+        "INFO"
+    };
+    if matches.is_present("log-json") {
+        logger::init_json(level);
+    } else if read_to_stdout || matches.is_present("dump-config") {
+        logger::init_stderr(level);
+    } else {
+        logger::init(level);
+    }
+
+    let config = initiator_config(matches.value_of("config"));
+    if matches.is_present("dump-config") {
+        print!("{}", serde_yaml::to_string(&config).unwrap());
+        std::process::exit(0);
+    }
 
-    let uri = matches.value_of("URI").unwrap().to_owned();
+    let uris: Vec<String> = match matches.value_of("URI") {
+        Some(val) => val.split(',').map(String::from).collect(),
+        None if matches!(
+            matches.subcommand_name(),
+            Some("copy") | Some("diff")
+        ) =>
+        {
+            Vec::new()
+        }
+        None => clap::Error::with_description(
+            "The URI of the replica is required",
+            ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
+    if read_to_stdout && uris.len() > 1 {
+        clap::Error::with_description(
+            "Only one replica can be read to stdout",
+            ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    // the other subcommands would silently use just the first replica
+    if uris.len() > 1
+        && !matches!(matches.subcommand_name(), Some("read") | Some("write"))
+    {
+        clap::Error::with_description(
+            "Only read and write accept a list of replicas",
+            ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    let uri = uris.first().cloned().unwrap_or_default();
     let offset: u64 = match matches.value_of("offset") {
         Some(val) => val.parse().expect("Offset must be a number"),
         None => 0,
     };
 
+    if let Some(val) = matches.value_of("io-timeout") {
+        let secs: u64 = val.parse().expect("IO timeout must be a number");
+        IO_TIMEOUT.set(Duration::from_secs(secs)).unwrap();
+    }
+
     let mut ms = MayastorEnvironment::default();
 
     ms.name = "initiator".into();
-    ms.rpc_addr = "/tmp/initiator.sock".into();
-    // This tool is just a client, so don't start iSCSI or NVMEoF services.
-    Config::get_or_init(|| {
-        let mut cfg = Config::default();
-        cfg.nexus_opts.iscsi_enable = false;
-        cfg.nexus_opts.nvmf_enable = false;
-        cfg
-    });
+    ms.rpc_addr = rpc_socket();
+    Config::get_or_init(|| config);
     ms.start(move || {
         let fut = async move {
             let res = if let Some(matches) = matches.subcommand_matches("read")
             {
-                read(&uri, offset, matches.value_of("FILE").unwrap()).await
+                let file = matches.value_of("FILE").unwrap();
+                let display = match matches.value_of("display") {
+                    Some("hex") => ReadDisplay::Hex,
+                    Some("ascii") => ReadDisplay::Ascii,
+                    Some(_) => ReadDisplay::Raw,
+                    None => ReadDisplay::for_stdout(),
+                };
+                for_each_uri(&uris, |i, uri| {
+                    let file = child_file(file, i, uris.len());
+                    async move { read(&uri, offset, &file, display).await }
+                })
+                .await
+            } else if let Some(matches) =
+                matches.subcommand_matches("read-split")
+            {
+                let count: u64 = matches
+                    .value_of("count")
+                    .unwrap()
+                    .parse()
+                    .expect("Count must be a number");
+                let prefix = matches.value_of("PREFIX").unwrap();
+                read_split(&uri, offset, count, prefix).await
+            } else if let Some(matches) = matches.subcommand_matches("watch") {
+                let interval: u64 = match matches.value_of("interval") {
+                    Some(val) => {
+                        val.parse().expect("Interval must be a number")
+                    }
+                    None => 500,
+                };
+                let format = match matches.value_of("format") {
+                    Some("ascii") => WatchFormat::Ascii,
+                    _ => WatchFormat::Hex,
+                };
+                watch(&uri, offset, Duration::from_millis(interval), format)
+                    .await
             } else if let Some(matches) = matches.subcommand_matches("write") {
-                write(&uri, offset, matches.value_of("FILE").unwrap()).await
-            } else if matches.subcommand_matches("create-snapshot").is_some() {
-                create_snapshot(&uri).await
+                let file = matches.value_of("FILE").unwrap();
+                for_each_uri(&uris, |_, uri| async move {
+                    write(&uri, offset, file).await
+                })
+                .await
+            } else if let Some(matches) =
+                matches.subcommand_matches("write-pattern")
+            {
+                let length: u64 = matches
+                    .value_of("length")
+                    .unwrap()
+                    .parse()
+                    .expect("Length must be a number");
+                let seed: u64 = match matches.value_of("seed") {
+                    Some(val) => val.parse().expect("Seed must be a number"),
+                    None => 0,
+                };
+                match DataPattern::parse(
+                    matches.value_of("pattern").unwrap(),
+                    seed,
+                ) {
+                    Ok(pattern) => {
+                        write_pattern(
+                            &uri,
+                            offset,
+                            length,
+                            pattern,
+                            matches.is_present("verify"),
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                }
+            } else if let Some(matches) = matches.subcommand_matches("zero") {
+                let length: u64 = matches
+                    .value_of("length")
+                    .unwrap()
+                    .parse()
+                    .expect("Length must be a number");
+                zero(&uri, offset, length).await
+            } else if let Some(matches) =
+                matches.subcommand_matches("create-snapshot")
+            {
+                create_snapshot(&uri, matches.value_of("name")).await
+            } else if let Some(matches) = matches.subcommand_matches("bench") {
+                let count: u64 = match matches.value_of("count") {
+                    Some(val) => val.parse().expect("Count must be a number"),
+                    None => 1000,
+                };
+                let seed: u64 = match matches.value_of("seed") {
+                    Some(val) => val.parse().expect("Seed must be a number"),
+                    None => 0,
+                };
+                let pattern = match matches.value_of("pattern") {
+                    Some("rand") => Pattern::Rand,
+                    _ => Pattern::Seq,
+                };
+                bench(
+                    &uri,
+                    offset,
+                    count,
+                    pattern,
+                    seed,
+                    matches.is_present("show-offsets"),
+                )
+                .await
+            } else if let Some(matches) = matches.subcommand_matches("io-stats")
+            {
+                let blocks: u64 = match matches.value_of("blocks") {
+                    Some(val) => val.parse().expect("Blocks must be a number"),
+                    None => 0,
+                };
+                io_stats(&uri, offset, blocks).await
+            } else if let Some(matches) = matches.subcommand_matches("probe") {
+                let secs: u64 = match matches.value_of("timeout") {
+                    Some(val) => val.parse().expect("Timeout must be a number"),
+                    None => 5,
+                };
+                probe(&uri, offset, Duration::from_secs(secs)).await
+            } else if matches.subcommand_matches("connect-latency").is_some() {
+                connect_latency(&uri, offset).await
+            } else if let Some(matches) = matches.subcommand_matches("copy") {
+                let length: u64 = matches
+                    .value_of("length")
+                    .unwrap()
+                    .parse()
+                    .expect("Length must be a number");
+                copy(
+                    matches.value_of("src").unwrap(),
+                    matches.value_of("dst").unwrap(),
+                    offset,
+                    length,
+                )
+                .await
+            } else if let Some(matches) = matches.subcommand_matches("diff") {
+                let length: u64 = matches
+                    .value_of("length")
+                    .unwrap()
+                    .parse()
+                    .expect("Length must be a number");
+                diff(
+                    matches.value_of("a").unwrap(),
+                    matches.value_of("b").unwrap(),
+                    offset,
+                    length,
+                )
+                .await
             } else {
                 connect(&uri).await
             };
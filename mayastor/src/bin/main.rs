@@ -20,10 +20,25 @@ fn main() -> Result<(), std::io::Error> {
     // automatically. trace maps to debug at FFI level. If RUST_LOG is
     // passed, we will use it regardless.
 
-    if !args.log_components.is_empty() {
-        logger::init("TRACE");
+    let level = if !args.log_components.is_empty() {
+        "TRACE"
     } else {
-        logger::init("INFO");
+        "INFO"
+    };
+    if args.log_json {
+        logger::init_json(level);
+    } else {
+        logger::init(level);
+    }
+
+    let (mbus_once, mbus_dry_run) = (args.mbus.once, args.mbus.dry_run);
+    let env = MayastorEnvironment::new(args);
+    env.check_registration();
+    if mbus_once {
+        std::process::exit(env.register_once());
+    }
+    if mbus_dry_run {
+        std::process::exit(env.message_bus_dry_run());
     }
 
     let hugepage_path = Path::new("/sys/kernel/mm/hugepages/hugepages-2048kB");
@@ -44,7 +59,6 @@ fn main() -> Result<(), std::io::Error> {
         if uring_supported { "yes" } else { "no" }
     );
     info!("free_pages: {} nr_pages: {}", free_pages, nr_pages);
-    let env = MayastorEnvironment::new(args);
     env.start(|| {
         info!("Mayastor started {} ...", '\u{1F680}');
     })
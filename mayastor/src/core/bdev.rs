@@ -49,6 +49,8 @@ pub struct BdevStats {
     pub num_write_ops: u64,
     pub bytes_read: u64,
     pub bytes_written: u64,
+    pub num_unmap_ops: u64,
+    pub bytes_unmapped: u64,
 }
 
 /// Newtype structure that represents a block device. The soundness of the API
@@ -419,6 +421,8 @@ impl Bdev {
                 num_write_ops: stat.num_write_ops,
                 bytes_read: stat.bytes_read,
                 bytes_written: stat.bytes_written,
+                num_unmap_ops: stat.num_unmap_ops,
+                bytes_unmapped: stat.bytes_unmapped,
             })
         }
     }
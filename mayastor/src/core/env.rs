@@ -1,6 +1,8 @@
 use std::{
     env,
     ffi::CString,
+    fmt,
+    fs,
     net::Ipv4Addr,
     os::raw::{c_char, c_void},
     pin::Pin,
@@ -17,7 +19,7 @@ use futures::{channel::oneshot, future};
 use once_cell::sync::Lazy;
 use snafu::Snafu;
 use structopt::StructOpt;
-use tokio::{runtime::Builder, task};
+use tokio::{runtime::Builder, task, time::delay_for};
 
 use spdk_sys::{
     maya_log,
@@ -40,7 +42,10 @@ use spdk_sys::{
 };
 
 use crate::{
-    bdev::nexus::nexus_child_status_config::ChildStatusConfig,
+    bdev::{
+        nexus::{instances, nexus_child_status_config::ChildStatusConfig},
+        ChildStatus,
+    },
     core::{
         reactor::{Reactor, ReactorState, Reactors},
         Cores,
@@ -86,6 +91,218 @@ fn add_default_port(endpoint: Option<String>, port: u16) -> Option<String> {
     }
 }
 
+/// Parse the core of the dedicated message bus thread, which must be one of
+/// the online cpus, so that the thread can be bound to it.
+fn parse_mbus_core(src: &str) -> Result<u32, String> {
+    let core = src
+        .parse::<u32>()
+        .map_err(|e| format!("Invalid core {}: {}", src, e))?;
+    let online = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if core as usize >= libc::CPU_SETSIZE as usize || core as i64 >= online {
+        return Err(format!(
+            "Core {} is out of range, there are {} online cpus",
+            core, online
+        ));
+    }
+    Ok(core)
+}
+
+/// Secret shared with the control plane for signing of the messages, which
+/// is never shown in the debug output
+#[derive(Clone)]
+pub struct HmacKey(Vec<u8>);
+
+impl fmt::Debug for HmacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HmacKey(<redacted>)")
+    }
+}
+
+/// Read the HMAC key from the file, without the trailing newline which the
+/// tools writing it tend to add.
+fn read_hmac_key(path: &str) -> Result<HmacKey, String> {
+    let mut key = fs::read(path)
+        .map_err(|e| format!("Cannot read HMAC key from {}: {}", path, e))?;
+    while key.last().map_or(false, |c| *c == b'\n' || *c == b'\r') {
+        key.pop();
+    }
+    if key.is_empty() {
+        return Err(format!("HMAC key file {} is empty", path));
+    }
+    Ok(HmacKey(key))
+}
+
+/// Options of the message bus used for registration with the control plane
+/// and publishing of the events
+#[derive(Debug, Clone, StructOpt)]
+pub struct MbusConfig {
+    #[structopt(long = "mbus-register-delay")]
+    /// Delay in seconds before the first registration with the control plane
+    pub register_delay: Option<u64>,
+    #[structopt(long = "mbus-register-subject", default_value = "register")]
+    /// Subject of the register messages sent to the control plane
+    pub register_subject: String,
+    #[structopt(
+        long = "mbus-deregister-subject",
+        default_value = "deregister"
+    )]
+    /// Subject of the deregister messages sent to the control plane
+    pub deregister_subject: String,
+    #[structopt(
+        long = "mbus-format",
+        default_value = "json",
+        possible_values = &["json", "msgpack", "compact"]
+    )]
+    /// Serialization format of the messages sent to the control plane
+    pub format: nats::PayloadFormat,
+    #[structopt(long = "mbus-queue-size", default_value = "16")]
+    /// Max number of events waiting for the message bus (control commands
+    /// are always queued)
+    pub queue_size: usize,
+    #[structopt(
+        long = "mbus-queue-overflow",
+        default_value = "reject",
+        possible_values = &["reject", "drop-oldest"]
+    )]
+    /// What to do with an event when the message bus queue is full
+    pub queue_overflow: nats::OverflowPolicy,
+    #[structopt(long = "mbus-connect-timeout")]
+    /// Give up connecting to the NATS server after this many seconds
+    /// (0 or none means retrying forever)
+    pub connect_timeout: Option<u64>,
+    #[structopt(long = "mbus-max-reconnects")]
+    /// Give up reconnecting to the NATS server after this many attempts and
+    /// report the message bus as failed
+    pub max_reconnects: Option<usize>,
+    #[structopt(
+        long = "mbus-fatal-on-disconnect",
+        requires = "max-reconnects"
+    )]
+    /// Shut down mayastor when giving up reconnecting to the NATS server
+    pub fatal_on_disconnect: bool,
+    #[structopt(long = "mbus-keepalive")]
+    /// Send the register message only when the state of the node changes
+    /// (checked every heartbeat interval) and otherwise in this interval in
+    /// seconds
+    pub keepalive: Option<u64>,
+    #[structopt(long = "mbus-health-throttle-ms")]
+    /// Gather the health summary of the heartbeats at most once per this many
+    /// milliseconds and reuse the last one within it
+    pub health_throttle_ms: Option<u64>,
+    #[structopt(long = "mbus-event-flush")]
+    /// Flush the published events to the NATS server after this many events
+    /// rather than leaving it to the NATS client
+    pub event_flush: Option<u32>,
+    #[structopt(long = "mbus-event-flush-ms", requires = "event-flush")]
+    /// Flush the published events at the latest this many milliseconds after
+    /// the first unflushed one (default 1000)
+    pub event_flush_ms: Option<u64>,
+    #[structopt(long = "mbus-event-replay")]
+    /// Keep up to this many events which could not be sent while the NATS
+    /// server was unreachable and send them after reconnecting
+    pub event_replay: Option<usize>,
+    #[structopt(long = "mbus-max-rate")]
+    /// Send at most this many events per second to each subject (register
+    /// messages are not limited)
+    pub max_rate: Option<u32>,
+    #[structopt(
+        long = "mbus-rate-policy",
+        default_value = "drop",
+        possible_values = &["drop", "delay"]
+    )]
+    /// What to do with the events over the rate limit
+    pub rate_policy: nats::RateLimitPolicy,
+    #[structopt(long = "mbus-self-test")]
+    /// Check that the messages sent to the NATS server come back before the
+    /// first registration and flag the message bus as failed if they do not
+    pub self_test: bool,
+    #[structopt(
+        long = "mbus-hmac-key-file",
+        parse(try_from_str = read_hmac_key)
+    )]
+    /// Sign all messages sent to the NATS server with HMAC-SHA256 using the
+    /// secret shared with the control plane, which is read from the file
+    pub hmac_key: Option<HmacKey>,
+    #[structopt(long = "mbus-register-shards")]
+    /// Send the (de)register messages to one of this many subjects (i.e.
+    /// register.<shard>) chosen by the hash of the node name, so that the
+    /// nodes can be split among control plane instances
+    pub register_shards: Option<u32>,
+    #[structopt(long = "mbus-ship-logs")]
+    /// Publish the warn and error log messages to logs.<node> subject, so
+    /// that the control plane can collect them
+    pub ship_logs: bool,
+    #[structopt(long = "mbus-no-echo", conflicts_with = "self-test")]
+    /// Do not deliver the messages published by mayastor to its own
+    /// subscriptions on the same subjects
+    pub no_echo: bool,
+    #[structopt(long = "mbus-dedicated-thread")]
+    /// Run the message bus on a dedicated thread, so that heartbeats are not
+    /// delayed by the IO load of the reactors
+    pub dedicated_thread: bool,
+    #[structopt(
+        long = "mbus-core",
+        requires = "dedicated-thread",
+        parse(try_from_str = parse_mbus_core)
+    )]
+    /// Bind the dedicated message bus thread to the core, preferably one not
+    /// used by the reactors (default: any core not used by the reactors)
+    pub core: Option<u32>,
+    #[structopt(
+        long = "mbus-thread-nice",
+        requires = "dedicated-thread",
+        allow_hyphen_values = true
+    )]
+    /// Nice value of the dedicated message bus thread, i.e. -10 to schedule
+    /// heartbeats ahead of the IO (negative values require CAP_SYS_NICE)
+    pub thread_nice: Option<i32>,
+    #[structopt(long = "mbus-once")]
+    /// Register with the control plane once, wait for the ack and exit
+    pub once: bool,
+    #[structopt(long = "mbus-log-register")]
+    /// Log the register message which would be sent to the control plane if
+    /// the NATS endpoint was set, for debugging of the registration
+    pub log_register: bool,
+    #[structopt(long = "mbus-dry-run", conflicts_with = "once")]
+    /// Validate the message bus configuration by sending a register message
+    /// to the validation subject and exit
+    pub dry_run: bool,
+}
+
+impl Default for MbusConfig {
+    fn default() -> Self {
+        Self {
+            register_delay: None,
+            register_subject: nats::REGISTER_SUBJECT.into(),
+            deregister_subject: nats::DEREGISTER_SUBJECT.into(),
+            format: nats::PayloadFormat::Json,
+            queue_size: nats::COMMAND_QUEUE_SIZE,
+            queue_overflow: nats::OverflowPolicy::Reject,
+            connect_timeout: None,
+            max_reconnects: None,
+            fatal_on_disconnect: false,
+            keepalive: None,
+            health_throttle_ms: None,
+            event_flush: None,
+            event_flush_ms: None,
+            event_replay: None,
+            max_rate: None,
+            rate_policy: nats::RateLimitPolicy::Drop,
+            self_test: false,
+            hmac_key: None,
+            register_shards: None,
+            ship_logs: false,
+            no_echo: false,
+            log_register: false,
+            dedicated_thread: false,
+            core: None,
+            thread_nice: None,
+            once: false,
+            dry_run: false,
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "Mayastor",
@@ -103,6 +320,10 @@ pub struct MayastorCliArgs {
     #[structopt(short = "L")]
     /// Enable logging for sub components
     pub log_components: Vec<String>,
+    #[structopt(long = "log-json")]
+    /// Print log messages as json objects with the fields of the events (i.e.
+    /// node and grpc_endpoint of registration events) for log aggregation
+    pub log_json: bool,
     #[structopt(short = "m", default_value = "0x1")]
     /// The reactor mask to be used for starting up the instance
     pub reactor_mask: String,
@@ -112,6 +333,8 @@ pub struct MayastorCliArgs {
     #[structopt(short = "n")]
     /// IP address and port of the NATS server
     pub nats_endpoint: Option<String>,
+    #[structopt(flatten)]
+    pub mbus: MbusConfig,
     /// The maximum amount of hugepage memory we are allowed to allocate in MiB
     /// (default: all)
     #[structopt(
@@ -146,6 +369,7 @@ impl Default for MayastorCliArgs {
         Self {
             grpc_endpoint: None,
             nats_endpoint: None,
+            mbus: MbusConfig::default(),
             node_name: None,
             env_context: None,
             reactor_mask: "0x1".into(),
@@ -153,6 +377,7 @@ impl Default for MayastorCliArgs {
             rpc_address: "/var/tmp/mayastor.sock".to_string(),
             no_pci: true,
             log_components: vec![],
+            log_json: false,
             config: None,
             mayastor_config: None,
             child_status_config: None,
@@ -210,6 +435,7 @@ pub struct MayastorEnvironment {
     pub config: Option<String>,
     node_name: String,
     nats_endpoint: Option<String>,
+    mbus: MbusConfig,
     grpc_endpoint: Option<String>,
     mayastor_config: Option<String>,
     child_status_config: Option<String>,
@@ -245,6 +471,7 @@ impl Default for MayastorEnvironment {
             config: None,
             node_name: "mayastor-node".into(),
             nats_endpoint: None,
+            mbus: MbusConfig::default(),
             grpc_endpoint: None,
             mayastor_config: None,
             child_status_config: None,
@@ -293,7 +520,7 @@ async fn do_shutdown(arg: *mut c_void) {
         warn!("Mayastor stopped non-zero: {}", rc);
     }
 
-    nats::message_bus_stop();
+    nats::message_bus_stop_and_wait(nats::SHUTDOWN_GRACE_PERIOD).await;
     iscsi::fini();
 
     unsafe {
@@ -350,6 +577,7 @@ impl MayastorEnvironment {
         Self {
             grpc_endpoint: add_default_port(args.grpc_endpoint, 10124),
             nats_endpoint: add_default_port(args.nats_endpoint, 4222),
+            mbus: args.mbus,
             node_name: args.node_name.unwrap_or_else(|| "mayastor-node".into()),
             config: args.config,
             mayastor_config: args.mayastor_config,
@@ -722,12 +950,216 @@ impl MayastorEnvironment {
             assert_eq!(receiver.await.unwrap(), true);
         });
 
-        // load any bdevs that need to be created
-        Config::get().import_bdevs();
+        // methods for draining the node can be called even if the message
+        // bus is not used, they will just fail
+        nats::register_rpc_methods();
+
+        // load any bdevs that need to be created, the node is not ready
+        // for the control plane until they are
+        if Config::get().import_bdevs() == 0 {
+            nats::message_bus_set_status(nats::NodeStatus::Ready);
+        } else {
+            nats::message_bus_set_status(nats::NodeStatus::Degraded);
+        }
 
         self
     }
 
+    /// create the message bus used for registration with the control plane
+    /// if both gRPC and NATS endpoints are given
+    fn message_bus(&self) -> Option<nats::MessageBus> {
+        let grpc_ep = self.grpc_endpoint.as_ref()?;
+        let nats_ep = self.nats_endpoint.as_ref()?;
+        Some(self.message_bus_to(nats_ep, grpc_ep))
+    }
+
+    /// create the message bus connecting to the NATS endpoint as configured
+    /// by the mbus options
+    fn message_bus_to(&self, nats_ep: &str, grpc_ep: &str) -> nats::MessageBus {
+        // the message bus on a dedicated thread must not touch the nexus
+        // instances, it gets the health summary published by the core
+        let health: nats::HealthGatherer = if self.mbus.dedicated_thread {
+            Box::new(nats::published_health)
+        } else {
+            Box::new(Self::health_summary)
+        };
+        let mut mbus = nats::MessageBus::new(nats_ep, &self.node_name, grpc_ep)
+            .with_health(health)
+            .with_subjects(
+                &self.mbus.register_subject,
+                &self.mbus.deregister_subject,
+            )
+            .with_format(self.mbus.format)
+            .with_command_queue(self.mbus.queue_size, self.mbus.queue_overflow);
+        if let Some(delay) = self.mbus.register_delay {
+            mbus = mbus.with_register_delay(Duration::from_secs(delay));
+        }
+        if let Some(timeout) = self.mbus.connect_timeout {
+            mbus = mbus.with_connect_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(max) = self.mbus.max_reconnects {
+            mbus = mbus.with_max_reconnects(max, self.mbus.fatal_on_disconnect);
+        }
+        if let Some(keepalive) = self.mbus.keepalive {
+            mbus = mbus.with_keepalive(Duration::from_secs(keepalive));
+        }
+        if let Some(throttle) = self.mbus.health_throttle_ms {
+            mbus = mbus.with_health_throttle(Duration::from_millis(throttle));
+        }
+        if let Some(count) = self.mbus.event_flush {
+            let interval = self
+                .mbus
+                .event_flush_ms
+                .map_or(nats::EVENT_FLUSH_INTERVAL, Duration::from_millis);
+            mbus = mbus.with_event_flush(count, interval);
+        }
+        if let Some(capacity) = self.mbus.event_replay {
+            mbus = mbus.with_event_replay(capacity);
+        }
+        if let Some(rate) = self.mbus.max_rate {
+            mbus = mbus.with_rate_limit(rate, self.mbus.rate_policy);
+        }
+        if self.mbus.self_test {
+            mbus = mbus.with_self_test(nats::SELF_TEST_TIMEOUT);
+        }
+        if let Some(HmacKey(key)) = &self.mbus.hmac_key {
+            mbus = mbus.with_hmac_key(key);
+        }
+        if let Some(shards) = self.mbus.register_shards {
+            mbus = mbus.with_register_shards(shards);
+        }
+        if self.mbus.ship_logs {
+            mbus = mbus.with_log_shipping();
+        }
+        if self.mbus.no_echo {
+            mbus = mbus.with_no_echo();
+        }
+        mbus
+    }
+
+    /// warn if the node is expected to register with the control plane
+    /// (gRPC endpoint is set) but cannot, because the NATS endpoint is not
+    /// set, and log the register message it would send if asked to
+    pub fn check_registration(&self) {
+        let grpc_ep = match (&self.grpc_endpoint, &self.nats_endpoint) {
+            (Some(grpc_ep), None) => grpc_ep,
+            _ => return,
+        };
+        warn!(
+            "gRPC endpoint {} is set but NATS endpoint (-n) is not, the node \
+             will not register with the control plane",
+            grpc_ep
+        );
+        if self.mbus.log_register {
+            // nothing has been created yet, so the health is empty
+            let mbus = self
+                .message_bus_to("", grpc_ep)
+                .with_health(Box::new(|| Ok(nats::HealthSummary::default())));
+            info!(
+                "Register message which would be sent to {}: {}",
+                mbus.config().register_subject,
+                serde_json::to_string(&mbus.register_args()).unwrap()
+            );
+        }
+    }
+
+    /// send a single register message and wait for the control plane to
+    /// acknowledge it, returns the exit code of the process
+    pub fn register_once(&self) -> i32 {
+        self.check_message_bus("Registration", |mut mbus| async move {
+            mbus.register_once(nats::REGISTER_ACK_TIMEOUT).await
+        })
+    }
+
+    /// validate the message bus configuration without joining the cluster,
+    /// returns the exit code of the process
+    pub fn message_bus_dry_run(&self) -> i32 {
+        self.check_message_bus("Dry run", |mut mbus| async move {
+            mbus.dry_run().await
+        })
+    }
+
+    /// run the check of the message bus in a new runtime and return the exit
+    /// code of the process
+    fn check_message_bus<F, Fut>(&self, what: &str, check: F) -> i32
+    where
+        F: FnOnce(nats::MessageBus) -> Fut,
+        Fut: future::Future<Output = std::result::Result<(), nats::Error>>,
+    {
+        let mbus = match self.message_bus() {
+            Some(mbus) => mbus,
+            None => {
+                error!("Both gRPC and NATS endpoints are required to register");
+                return 1;
+            }
+        };
+
+        let mut rt = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        match rt.block_on(check(mbus)) {
+            Ok(()) => {
+                info!("{} of message bus succeeded", what);
+                0
+            }
+            Err(err) => {
+                error!("{} of message bus failed: {}", what, err);
+                1
+            }
+        }
+    }
+
+    /// summary of the nexus health sent to the control plane with each
+    /// heartbeat
+    fn health_summary() -> Result<nats::HealthSummary, String> {
+        Ok(nats::HealthSummary {
+            nexus: instances()
+                .iter()
+                .map(|n| nats::NexusHealth {
+                    name: n.name.clone(),
+                    status: n.status().to_string(),
+                    degraded_children: n
+                        .children
+                        .iter()
+                        .filter(|c| c.status() != ChildStatus::Online)
+                        .count() as u32,
+                })
+                .collect(),
+        })
+    }
+
+    /// publish the health summary for the message bus running on a dedicated
+    /// thread in the given interval until the message bus is stopped
+    async fn publish_health(interval: Duration) -> Result<(), ()> {
+        while nats::message_bus_running() {
+            match Self::health_summary() {
+                Ok(summary) => nats::message_bus_set_health(summary),
+                Err(err) => warn!("Failed to gather health summary: {}", err),
+            }
+            delay_for(interval).await;
+        }
+        Ok(())
+    }
+
+    /// join the dedicated message bus thread, but do not hang the shutdown
+    /// on it for longer than the grace period
+    fn join_message_bus(thread: std::thread::JoinHandle<()>, grace: Duration) {
+        let (joined, done) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = thread.join();
+            let _ = joined.send(());
+        });
+        if done.recv_timeout(grace).is_err() {
+            warn!(
+                "Message bus thread did not exit within {:?}, leaving it",
+                grace
+            );
+        }
+    }
+
     // finalize our environment
     fn fini() {
         unsafe {
@@ -745,8 +1177,11 @@ impl MayastorEnvironment {
     {
         type FutureResult = Result<(), ()>;
         let grpc_endpoint = self.grpc_endpoint.clone();
-        let nats_endpoint = self.nats_endpoint.clone();
-        let node_name = self.node_name.clone();
+        let mbus = self.message_bus();
+        let dedicated_mbus = self.mbus.dedicated_thread;
+        let mbus_core = self.mbus.core;
+        let mbus_nice = self.mbus.thread_nice;
+        let mut mbus_thread = None;
         self.init();
 
         let mut rt = Builder::new()
@@ -768,14 +1203,30 @@ impl MayastorEnvironment {
                         futures.push(Box::pin(grpc::MayastorGrpcServer::run(
                             grpc_ep,
                         )));
-                        if let Some(nats_ep) = nats_endpoint.as_ref() {
-                            futures.push(Box::pin(nats::message_bus_run(
-                                nats_ep, &node_name, grpc_ep,
-                            )));
+                        if let Some(mbus) = mbus {
+                            if dedicated_mbus {
+                                let interval = mbus.hb_interval();
+                                mbus_thread = Some(nats::message_bus_spawn(
+                                    mbus, mbus_core, mbus_nice,
+                                ));
+                                futures.push(Box::pin(Self::publish_health(
+                                    interval,
+                                )));
+                            } else {
+                                futures.push(Box::pin(nats::message_bus_run(
+                                    mbus,
+                                )));
+                            }
                         }
                     };
                     futures.push(Box::pin(master));
                     let _out = future::try_join_all(futures).await;
+                    if let Some(thread) = mbus_thread.take() {
+                        Self::join_message_bus(
+                            thread,
+                            nats::SHUTDOWN_GRACE_PERIOD,
+                        );
+                    }
                     info!("reactors stopped");
                     Self::fini();
                 })
@@ -19,6 +19,7 @@ use spdk_sys::{
     spdk_bdev_read,
     spdk_bdev_reset,
     spdk_bdev_write,
+    spdk_bdev_write_zeroes,
     spdk_io_channel,
 };
 
@@ -26,6 +27,7 @@ use crate::{
     bdev::nexus::nexus_io::nvme_admin_opc,
     core::{Bdev, CoreError, Descriptor, DmaBuf, DmaError, IoChannel},
     ffihelper::cb_arg,
+    replica::Replica,
 };
 
 /// A handle to a bdev, is an interface to submit IO. The ['Descriptor'] may be
@@ -145,6 +147,44 @@ impl BdevHandle {
         }
     }
 
+    /// write zeroes to the given range, which is guaranteed to read back as
+    /// zeroes. If the bdev does not support it natively, spdk emulates it by
+    /// writing a zeroed buffer.
+    pub async fn write_zeroes_at(
+        &self,
+        offset: u64,
+        len: u64,
+    ) -> Result<u64, CoreError> {
+        let (s, r) = oneshot::channel::<bool>();
+        let errno = unsafe {
+            spdk_bdev_write_zeroes(
+                self.desc.as_ptr(),
+                self.channel.as_ptr(),
+                offset,
+                len,
+                Some(Self::io_completion_cb),
+                cb_arg(s),
+            )
+        };
+
+        if errno != 0 {
+            return Err(CoreError::WriteDispatch {
+                source: Errno::from_i32(errno),
+                offset,
+                len,
+            });
+        }
+
+        if r.await.expect("Failed awaiting write zeroes IO") {
+            Ok(len)
+        } else {
+            Err(CoreError::WriteFailed {
+                offset,
+                len,
+            })
+        }
+    }
+
     /// read at given offset into the ['DmaBuf']
     pub async fn read_at(
         &self,
@@ -209,7 +249,30 @@ impl BdevHandle {
     /// create a snapshot on all children
     /// returns snapshot time as u64 seconds since Unix epoch
     pub async fn create_snapshot(&self) -> Result<u64, CoreError> {
+        self.create_snapshot_with_label(None).await
+    }
+
+    /// create a snapshot on all children, which is named after the label
+    /// instead of the snapshot time if given
+    /// returns snapshot time as u64 seconds since Unix epoch
+    pub async fn create_snapshot_with_label(
+        &self,
+        label: Option<&str>,
+    ) -> Result<u64, CoreError> {
         let mut cmd = spdk_sys::spdk_nvme_cmd::default();
+        // encode snapshot label in cdw12-15
+        if let Some(label) = label {
+            let dwords =
+                Replica::encode_snapshot_label(label).ok_or_else(|| {
+                    CoreError::InvalidSnapshotLabel {
+                        label: label.to_owned(),
+                    }
+                })?;
+            cmd.cdw12 = dwords[0];
+            cmd.cdw13 = dwords[1];
+            cmd.cdw14 = dwords[2];
+            cmd.cdw15 = dwords[3];
+        }
         cmd.set_opc(nvme_admin_opc::CREATE_SNAPSHOT.into());
         // encode snapshot time in cdw10/11
         let now = SystemTime::now()
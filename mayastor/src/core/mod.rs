@@ -12,8 +12,10 @@ pub use descriptor::{Descriptor, RangeContext};
 pub use dma::{DmaBuf, DmaError};
 pub use env::{
     mayastor_env_stop,
+    HmacKey,
     MayastorCliArgs,
     MayastorEnvironment,
+    MbusConfig,
     GLOBAL_RC,
 };
 pub use handle::BdevHandle;
@@ -112,4 +114,11 @@ pub enum CoreError {
     NotSupported {
         source: Errno,
     },
+    #[snafu(display(
+        "invalid snapshot label '{}' (1 to 16 alphanumerics, '-', '_' or '.')",
+        label
+    ))]
+    InvalidSnapshotLabel {
+        label: String,
+    },
 }
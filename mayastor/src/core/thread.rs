@@ -196,6 +196,58 @@ impl Mthread {
         })
     }
 
+    /// spawns a named thread which is bound to the given core, or which has
+    /// the inverse cpu set of mayastor if no core is given
+    pub fn spawn_named<F, T>(
+        name: &str,
+        core: Option<u32>,
+        f: F,
+    ) -> std::thread::JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        std::thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                match core {
+                    Some(core) => Self::bind_to_core(core),
+                    None => Self::unaffinitize(),
+                }
+                f()
+            })
+            .expect("failed to spawn thread")
+    }
+
+    fn bind_to_core(core: u32) {
+        if core as usize >= libc::CPU_SETSIZE as usize {
+            warn!("core {} does not fit in the cpu set, not binding", core);
+            return;
+        }
+        if Cores::count().into_iter().any(|c| c == core) {
+            warn!("core {} is shared with a reactor", core);
+        }
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_SET(core as usize, &mut set);
+
+            if libc::sched_setaffinity(
+                0,
+                std::mem::size_of::<libc::cpu_set_t>(),
+                &set,
+            ) != 0
+            {
+                warn!(
+                    "failed to bind pthread to core {}: {}",
+                    core,
+                    std::io::Error::last_os_error()
+                );
+            }
+
+            info!("pthread started on core {}", libc::sched_getcpu());
+        }
+    }
+
     fn unaffinitize() {
         unsafe {
             let mut set: libc::cpu_set_t = std::mem::zeroed();
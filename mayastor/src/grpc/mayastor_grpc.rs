@@ -29,6 +29,7 @@ use crate::{
         sync_config,
         GrpcResult,
     },
+    nats::FaultReason,
 };
 
 #[derive(Debug)]
@@ -217,7 +218,9 @@ impl mayastor_server::Mayastor for MayastorSvc {
             let uri = args.uri.clone();
             debug!("Faulting child {} on nexus {}", uri, uuid);
             locally! { async move {
-                nexus_lookup(&args.uuid)?.fault_child(&args.uri).await
+                nexus_lookup(&args.uuid)?
+                    .fault_child(&args.uri, FaultReason::Requested)
+                    .await
             }};
             info!("Faulted child {} on nexus {}", uri, uuid);
             Ok(Response::new(Null {}))
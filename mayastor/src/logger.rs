@@ -1,10 +1,15 @@
 use std::{ffi::CStr, os::raw::c_char, str::FromStr};
 
 use tracing_log::format_trace;
-use tracing_subscriber::fmt::{format::FmtSpan, time::FormatTime, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, time::FormatTime, Subscriber},
+    layer::SubscriberExt,
+};
 
 use spdk_sys::{spdk_log_get_print_level, spdk_log_level};
 
+use crate::nats::LogShipper;
+
 fn from_spdk_level(level: spdk_log_level) -> log::Level {
     match level {
         spdk_sys::SPDK_LOG_ERROR => log::Level::Error,
@@ -70,6 +75,9 @@ impl FormatTime for CustomTime<'_> {
 ///
 /// We might want to suppress certain messages, as some of them are redundant,
 /// in particular, the NOTICE messages as such, they are mapped to debug.
+///
+/// The warn and error messages are also shipped over the message bus if it
+/// is asked to (see LogShipper).
 pub fn init(level: &str) {
     let subscriber = Subscriber::builder()
         .with_timer(CustomTime("%FT%T%.9f%Z"))
@@ -77,8 +85,42 @@ pub fn init(level: &str) {
         .with_max_level(
             tracing::Level::from_str(level).unwrap_or(tracing::Level::TRACE),
         )
+        .finish()
+        .with(LogShipper::default());
+
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("failed to set default subscriber");
+}
+
+/// Same as init() but the messages are printed to stderr, so that stdout is
+/// left to the data produced by the program (i.e. initiator reading to -).
+pub fn init_stderr(level: &str) {
+    let subscriber = Subscriber::builder()
+        .with_timer(CustomTime("%FT%T%.9f%Z"))
+        .with_span_events(FmtSpan::FULL)
+        .with_max_level(
+            tracing::Level::from_str(level).unwrap_or(tracing::Level::TRACE),
+        )
+        .with_writer(std::io::stderr)
         .finish();
 
     tracing::subscriber::set_global_default(subscriber)
         .expect("failed to set default subscriber");
 }
+
+/// Same as init() but the messages are printed as json objects carrying the
+/// fields of the spans, so that structured log consumers can filter them.
+pub fn init_json(level: &str) {
+    let subscriber = Subscriber::builder()
+        .json()
+        .with_timer(CustomTime("%FT%T%.9f%Z"))
+        .with_span_events(FmtSpan::FULL)
+        .with_max_level(
+            tracing::Level::from_str(level).unwrap_or(tracing::Level::TRACE),
+        )
+        .finish()
+        .with(LogShipper::default());
+
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("failed to set default subscriber");
+}
@@ -0,0 +1,1721 @@
+//! The message bus itself: the connection to the NATS server, the loop
+//! serving the commands and the register/deregister protocol.
+
+use std::{
+    env,
+    panic::AssertUnwindSafe,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use crc::crc32;
+use futures::{
+    channel::oneshot,
+    future,
+    select,
+    stream::{self, Stream},
+    FutureExt,
+    StreamExt,
+};
+use nats::{
+    asynk::{Connection, Message, Subscription},
+    Options,
+};
+use ring::hmac;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    sync::watch,
+    time::{delay_for, timeout},
+};
+
+use crate::{
+    core::{mayastor_env_stop, Mthread},
+    nats::{
+        command_queue,
+        events::EventPipeline,
+        health::HealthSource,
+        lock,
+        payload::DeregisterArgs,
+        scale,
+        send_command,
+        Admission,
+        Announced,
+        Command,
+        CommandReceiver,
+        Error,
+        HealthGatherer,
+        OutageLog,
+        OverflowPolicy,
+        PayloadFormat,
+        RateLimitPolicy,
+        RateLimiter,
+        RegisterArgs,
+        ReplicaState,
+        COMMAND_QUEUE_SIZE,
+        CONFIG,
+        CONNECTION,
+        CONNECT_BACKOFF_BASE,
+        CONNECT_BACKOFF_JITTER,
+        DEREGISTER_FLUSH_TIMEOUT,
+        DEREGISTER_SUBJECT,
+        EVENT_STATS,
+        GENERATION,
+        HB_INTERVAL,
+        LOG_SUBJECT,
+        LOG_SUBJECT_PREFIX,
+        MAX_LOOP_RESTARTS,
+        MESSAGE_BUS_THREAD,
+        REGISTER_SUBJECT,
+        SCHEMA_VERSION,
+        SENDER,
+        SIGNATURE_LEN,
+        START_EPOCH,
+        STATE,
+        STATUS,
+        STOPPED,
+    },
+};
+
+/// Log a registration event of the message bus with the node and gRPC
+/// endpoint as separate fields, so that log pipelines can index them when
+/// the logs are printed as json (--log-json).
+macro_rules! registration_event {
+    ($level:ident, $mbus:expr, $event:expr, $($arg:tt)+) => {
+        $level!(
+            node = %$mbus.node,
+            grpc_endpoint = %$mbus.grpc_endpoint,
+            event = $event,
+            $($arg)+
+        )
+    };
+}
+
+/// Closure called with the payload of each configuration update pushed by
+/// the control plane (see config_subject()). The result is replied to the
+/// control plane.
+pub type ConfigHandler = Box<dyn Fn(&[u8]) -> Result<(), String> + Send>;
+
+/// Subject on which the control plane pushes configuration updates to the
+/// node.
+pub fn config_subject(node: &str) -> String {
+    format!("node.{}.config", node)
+}
+
+/// Reply to a configuration update. It is always json, since the format of
+/// the update itself is up to the handler.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ConfigAck {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<(), String>> for ConfigAck {
+    fn from(res: Result<(), String>) -> Self {
+        match res {
+            Ok(()) => Self {
+                ok: true,
+                error: None,
+            },
+            Err(error) => Self {
+                ok: false,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+/// Exponential backoff of retries: the delay starts at the base and grows by
+/// the multiplier with each attempt up to the max. With jitter each delay is
+/// randomly shortened by up to that fraction of it.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    /// the next delay before jitter is applied
+    next: Duration,
+}
+
+impl Backoff {
+    /// Create a backoff doubling the delay from the base up to the max,
+    /// without jitter.
+    pub fn new(base: Duration, max: Duration) -> Self {
+        let base = base.min(max);
+        Self {
+            base,
+            max,
+            multiplier: 2.0,
+            jitter: 0.0,
+            next: base,
+        }
+    }
+
+    /// Grow the delay by the multiplier (at least 1) instead of doubling it.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Shorten each delay randomly by up to the fraction (0 to 1) of it.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.max(0.0).min(1.0);
+        self
+    }
+
+    /// The delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = scale(self.next, self.multiplier).min(self.max);
+        if self.jitter > 0.0 {
+            delay - scale(delay, self.jitter * rand::random::<f64>())
+        } else {
+            delay
+        }
+    }
+
+    /// Start over from the base delay, i.e. after a successful attempt.
+    pub fn reset(&mut self) {
+        self.next = self.base;
+    }
+}
+
+/// Signs the published messages with HMAC-SHA256 using a secret shared with
+/// the control plane, so that it can reject spoofed or tampered messages. The
+/// signature is appended to the payload.
+#[derive(Debug)]
+pub struct Signer {
+    key: hmac::Key,
+}
+
+impl Signer {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    /// Return the payload followed by its signature.
+    pub fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        let tag = hmac::sign(&self.key, payload);
+        let mut signed = Vec::with_capacity(payload.len() + SIGNATURE_LEN);
+        signed.extend_from_slice(payload);
+        signed.extend_from_slice(tag.as_ref());
+        signed
+    }
+
+    /// Check the signature of the signed message and return the payload
+    /// without it.
+    pub fn verify<'a>(&self, signed: &'a [u8]) -> Result<&'a [u8], Error> {
+        if signed.len() < SIGNATURE_LEN {
+            return Err(Error::Signature {});
+        }
+        let (payload, tag) = signed.split_at(signed.len() - SIGNATURE_LEN);
+        hmac::verify(&self.key, payload, tag)
+            .map(|_| payload)
+            .map_err(|_| Error::Signature {})
+    }
+}
+
+/// Reply of the json-rpc method returning the effective configuration of the
+/// message bus, as parsed from the command line and environment
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BusConfig {
+    /// NATS server with the credentials redacted
+    pub server: String,
+    pub node: String,
+    #[serde(rename = "grpcEndpoint")]
+    pub grpc_endpoint: String,
+    #[serde(rename = "registerSubject")]
+    pub register_subject: String,
+    #[serde(rename = "deregisterSubject")]
+    pub deregister_subject: String,
+    pub format: String,
+    #[serde(rename = "hbIntervalMs")]
+    pub hb_interval_ms: u64,
+    #[serde(rename = "keepaliveMs")]
+    pub keepalive_ms: Option<u64>,
+    #[serde(rename = "connectTimeoutMs")]
+    pub connect_timeout_ms: Option<u64>,
+    #[serde(rename = "maxReconnects")]
+    pub max_reconnects: Option<usize>,
+    #[serde(rename = "queueSize")]
+    pub queue_size: usize,
+    /// messages are signed (the key itself is never reported)
+    pub signed: bool,
+}
+
+/// Replace the user and password in the NATS server URL (if any), so that
+/// they do not leak into diagnostics.
+pub fn redact_credentials(server: &str) -> String {
+    server
+        .split(',')
+        .map(|url| {
+            let host = url.find("://").map_or(0, |i| i + 3);
+            match url[host ..].rfind('@') {
+                Some(at) => {
+                    format!("{}***{}", &url[.. host], &url[host + at ..])
+                }
+                None => url.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Message bus implementation
+pub struct MessageBus {
+    /// NATS server endpoint
+    server: String,
+    /// Name of the node that mayastor is running on
+    node: String,
+    /// gRPC endpoint of the server provided by mayastor
+    grpc_endpoint: String,
+    /// NATS client
+    client: Option<Connection>,
+    connection: ConnectSettings,
+    /// heartbeat interval (how often the register message is sent)
+    hb_interval: Duration,
+    /// one-time delay before the first register message
+    register_delay: Option<Duration>,
+    /// if set, unchanged register messages are sent only in this interval
+    keepalive: Option<Duration>,
+    /// sequence number of the last register message
+    seq: u64,
+    /// the last register message sent and when
+    last_sent: Option<(RegisterArgs, Instant)>,
+    subjects: Subjects,
+    /// serialization format of the payloads
+    format: PayloadFormat,
+    health: HealthSource,
+    events: EventPipeline,
+    /// signs all published messages if set
+    signer: Option<Signer>,
+    /// max number of commands waiting for the message bus
+    queue_size: usize,
+    /// what to do with commands which don't fit in the queue
+    overflow: OverflowPolicy,
+    /// logs connection errors once per outage
+    outage: OutageLog,
+    /// optional handler of configuration updates pushed by the control plane
+    config_handler: Option<ConfigHandler>,
+    /// publish the warn and error log records (see LogShipper)
+    ship_logs: bool,
+}
+
+/// Settings of the connections to the NATS server
+struct ConnectSettings {
+    /// shown by the NATS server (see connection_name())
+    name: String,
+    /// give up connecting to the server after this time (None = never)
+    timeout: Option<Duration>,
+    /// max number of reconnect attempts after connection loss (the nats
+    /// library default if not set)
+    max_reconnects: Option<usize>,
+    /// shut down mayastor when the reconnect attempts are exhausted
+    fatal_on_disconnect: bool,
+    /// the server does not deliver our own messages to our subscriptions
+    no_echo: bool,
+    /// wait for the loopback message at most this long before the first
+    /// register (see MessageBus::with_self_test())
+    self_test: Option<Duration>,
+}
+
+impl ConnectSettings {
+    /// Options of connections to the NATS server. When the nats library
+    /// reconnects after connection loss, the run loop is notified, so that
+    /// the node is registered again immediately. Likewise when it gives up
+    /// reconnecting the connection of the given generation. The connection
+    /// loss itself is only noted in the state of the message bus.
+    fn options(&self, generation: u64) -> Options {
+        let mut options = Options::new()
+            .with_name(&self.name)
+            .disconnect_callback(|| {
+                lock(&STATE).connected = false;
+            })
+            .reconnect_callback(|| {
+                lock(&STATE).reconnects += 1;
+                announce_connection(None);
+                if let Err(err) = send_command(Command::Reconnected) {
+                    warn!("Failed to notify message bus of reconnect: {}", err);
+                }
+            })
+            .close_callback(move || {
+                // the message bus is gone if closed during the shutdown
+                if let Err(err) = send_command(Command::Closed(generation)) {
+                    debug!("Failed to notify message bus of close: {}", err);
+                }
+            });
+        if let Some(max) = self.max_reconnects {
+            options = options.max_reconnects(max);
+        }
+        if self.no_echo {
+            options = options.no_echo();
+        }
+        options
+    }
+}
+
+/// Subjects of the register and deregister messages
+struct Subjects {
+    register: String,
+    deregister: String,
+    /// number of subjects the messages are spread over
+    shards: Option<u32>,
+}
+
+impl Subjects {
+    /// The subject with the shard of the node appended if the messages are
+    /// sharded.
+    fn sharded(&self, subject: &str, node: &str) -> String {
+        match self.shards {
+            Some(count) => {
+                format!("{}.{}", subject, register_shard(node, count))
+            }
+            None => subject.to_owned(),
+        }
+    }
+
+    /// Subject of the register messages of the node.
+    fn register(&self, node: &str) -> String {
+        self.sharded(&self.register, node)
+    }
+
+    /// Subject of the deregister messages of the node.
+    fn deregister(&self, node: &str) -> String {
+        self.sharded(&self.deregister, node)
+    }
+}
+
+/// Name of the connections to the NATS server in the form of
+/// mayastor-<node>-<pid>-<random token>, so that the connections of two
+/// processes which share the node name by mistake can be told apart in the
+/// connection list of the server.
+pub fn connection_name(node: &str) -> String {
+    format!(
+        "mayastor-{}-{}-{:08x}",
+        node,
+        std::process::id(),
+        rand::random::<u32>()
+    )
+}
+
+/// Shard of the node among the given number of them. It is the CRC-32
+/// (IEEE) of the node name modulo the count, which the control plane can
+/// compute on its own to know which instance owns the node.
+pub fn register_shard(node: &str, count: u32) -> u32 {
+    crc32::checksum_ieee(node.as_bytes()) % count.max(1)
+}
+
+/// Parse the heartbeat interval in seconds (MAYASTOR_HB_INTERVAL). Zero is
+/// rejected since the heartbeats would be sent in a busy loop. Returns the
+/// reason if the value is invalid.
+pub fn parse_hb_interval(val: &str) -> Result<Duration, String> {
+    match val.trim().parse::<u64>() {
+        Ok(0) => Err("heartbeat interval must not be zero".to_owned()),
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(err) => {
+            Err(format!("'{}' is not a number of seconds ({})", val, err))
+        }
+    }
+}
+
+/// Parse the delay of the first register message in seconds
+/// (MAYASTOR_REGISTER_DELAY). Returns the reason if the value is invalid.
+pub fn parse_register_delay(val: &str) -> Result<Duration, String> {
+    val.trim()
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|err| {
+            format!("'{}' is not a number of seconds ({})", val, err)
+        })
+}
+
+/// Read the duration from the environment variable using the parser. An
+/// invalid value is reported and ignored, as if the variable was not set, so
+/// that the caller falls back to its default.
+fn duration_from_env(
+    var: &str,
+    parse: fn(&str) -> Result<Duration, String>,
+) -> Option<Duration> {
+    let val = env::var(var).ok()?;
+    match parse(&val) {
+        Ok(duration) => Some(duration),
+        Err(reason) => {
+            warn!("Invalid {}: {}, using the default", var, reason);
+            None
+        }
+    }
+}
+
+impl MessageBus {
+    /// Create message bus object with given parameters.
+    pub fn new(server: &str, node: &str, grpc_endpoint: &str) -> Self {
+        Self {
+            server: server.to_owned(),
+            node: node.to_owned(),
+            grpc_endpoint: grpc_endpoint.to_owned(),
+            client: None,
+            connection: ConnectSettings {
+                name: connection_name(node),
+                timeout: None,
+                max_reconnects: None,
+                fatal_on_disconnect: false,
+                no_echo: false,
+                self_test: None,
+            },
+            hb_interval: duration_from_env(
+                "MAYASTOR_HB_INTERVAL",
+                parse_hb_interval,
+            )
+            .unwrap_or_else(|| Duration::from_secs(HB_INTERVAL)),
+            register_delay: duration_from_env(
+                "MAYASTOR_REGISTER_DELAY",
+                parse_register_delay,
+            ),
+            keepalive: None,
+            seq: 0,
+            last_sent: None,
+            subjects: Subjects {
+                register: REGISTER_SUBJECT.to_owned(),
+                deregister: DEREGISTER_SUBJECT.to_owned(),
+                shards: None,
+            },
+            format: PayloadFormat::Json,
+            health: HealthSource::default(),
+            events: EventPipeline::default(),
+            signer: None,
+            queue_size: COMMAND_QUEUE_SIZE,
+            overflow: OverflowPolicy::Reject,
+            outage: OutageLog::default(),
+            config_handler: None,
+            ship_logs: false,
+        }
+    }
+
+    /// Stop trying to connect to the server if not connected within the
+    /// given time. Zero duration means trying forever.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connection.timeout = if timeout == Duration::from_secs(0) {
+            None
+        } else {
+            Some(timeout)
+        };
+        self
+    }
+
+    /// Give up reconnecting to the server after the number of failed
+    /// attempts and report the message bus as failed. If fatal is set,
+    /// mayastor is shut down then.
+    pub fn with_max_reconnects(mut self, max: usize, fatal: bool) -> Self {
+        self.connection.max_reconnects = Some(max);
+        self.connection.fatal_on_disconnect = fatal;
+        self
+    }
+
+    /// Set the size of the command queue and what happens when it is full.
+    pub fn with_command_queue(
+        mut self,
+        size: usize,
+        overflow: OverflowPolicy,
+    ) -> Self {
+        self.queue_size = size;
+        self.overflow = overflow;
+        self
+    }
+
+    /// Serialize the payloads using the given format instead of json.
+    pub fn with_format(mut self, format: PayloadFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Use different subjects for register and deregister messages than the
+    /// default ones, for control planes which listen elsewhere.
+    pub fn with_subjects(mut self, register: &str, deregister: &str) -> Self {
+        self.subjects.register = register.to_owned();
+        self.subjects.deregister = deregister.to_owned();
+        self
+    }
+
+    /// Publish the warn and error log records of mayastor to logs.<node>
+    /// while the message bus is running (see LogShipper).
+    pub fn with_log_shipping(mut self) -> Self {
+        self.ship_logs = true;
+        self
+    }
+
+    /// Ask the server not to deliver the messages we publish to our own
+    /// subscriptions, which avoids loops once we subscribe to the subjects
+    /// we publish on. The loopback test (see with_self_test()) cannot pass
+    /// then.
+    pub fn with_no_echo(mut self) -> Self {
+        self.connection.no_echo = true;
+        self
+    }
+
+    /// Send the register and deregister messages to the subject with the
+    /// shard of the node appended (see register_shard()), so that the nodes
+    /// can be split among the instances of a sharded control plane. Zero
+    /// count is treated as one.
+    pub fn with_register_shards(mut self, count: u32) -> Self {
+        self.subjects.shards = Some(count.max(1));
+        self
+    }
+
+    /// Delay the first register message, so that the other subsystems (i.e.
+    /// gRPC server) are up when the control plane tries to reach us.
+    pub fn with_register_delay(mut self, delay: Duration) -> Self {
+        self.register_delay = Some(delay);
+        self
+    }
+
+    /// Include the health summary returned by the gatherer in every register
+    /// message.
+    pub fn with_health(mut self, health: HealthGatherer) -> Self {
+        self.health.set_gatherer(health);
+        self
+    }
+
+    /// Gather the health summary at most once per the period and reuse the
+    /// last one within it, so that frequent heartbeats do not scan the nexus
+    /// instances too often. Without it the summary is gathered afresh for
+    /// each register message.
+    pub fn with_health_throttle(mut self, period: Duration) -> Self {
+        self.health.set_throttle(period);
+        self
+    }
+
+    /// Send register messages in the given interval instead of the one from
+    /// MAYASTOR_HB_INTERVAL.
+    pub fn with_hb_interval(mut self, interval: Duration) -> Self {
+        self.hb_interval = interval;
+        self
+    }
+
+    /// Send the register message only if it differs from the last one sent
+    /// (checked every heartbeat interval), or if the keepalive interval has
+    /// elapsed since then.
+    pub fn with_keepalive(mut self, keepalive: Duration) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Flush the published events to the server after every count events,
+    /// or when the interval has elapsed since the first unflushed one, so
+    /// that the events are not stranded in the client buffer while we do not
+    /// pay for a flush per event. Zero count is treated as one.
+    pub fn with_event_flush(mut self, count: u32, interval: Duration) -> Self {
+        self.events.set_flush(count, interval);
+        self
+    }
+
+    /// Keep up to capacity events which could not be published while the
+    /// server was unreachable and publish them in order once the connection
+    /// is back. Without it only the retained events are kept (up to
+    /// RETAINED_EVENTS of them) and the others are dropped.
+    pub fn with_event_replay(mut self, capacity: usize) -> Self {
+        self.events.set_replay(capacity);
+        self
+    }
+
+    /// Publish at most rate events per second to each subject, the events
+    /// over the limit are dropped or delayed according to the policy (see
+    /// RateLimiter). The register messages are not limited.
+    pub fn with_rate_limit(
+        mut self,
+        rate: u32,
+        policy: RateLimitPolicy,
+    ) -> Self {
+        self.events.set_rate_limit(RateLimiter::new(rate, policy));
+        self
+    }
+
+    /// Publish the replica state changes only after the state has been
+    /// stable for the period (REPLICA_EVENT_DEBOUNCE by default).
+    pub fn with_replica_debounce(mut self, period: Duration) -> Self {
+        self.events.set_replica_debounce(period);
+        self
+    }
+
+    /// Run the loopback test (see loopback_test()) after connecting and
+    /// before the first register message. Its failure is logged and flagged
+    /// in the health of the message bus, the heartbeats are sent anyway.
+    pub fn with_self_test(mut self, timeout: Duration) -> Self {
+        self.connection.self_test = Some(timeout);
+        self
+    }
+
+    /// Sign every published message with HMAC-SHA256 using the shared
+    /// secret (see Signer).
+    pub fn with_hmac_key(mut self, secret: &[u8]) -> Self {
+        self.signer = Some(Signer::new(secret));
+        self
+    }
+
+    /// Accept configuration updates pushed by the control plane and pass
+    /// them to the handler.
+    pub fn with_config_handler(mut self, handler: ConfigHandler) -> Self {
+        self.config_handler = Some(handler);
+        self
+    }
+
+    /// Name of the node registered with the control plane.
+    pub fn node_id(&self) -> &str {
+        &self.node
+    }
+
+    /// gRPC endpoint of the node registered with the control plane.
+    pub fn grpc_endpoint(&self) -> &str {
+        &self.grpc_endpoint
+    }
+
+    /// Interval of the register messages.
+    pub fn hb_interval(&self) -> Duration {
+        self.hb_interval
+    }
+
+    /// Delay of the first register message, if any.
+    pub fn register_delay(&self) -> Option<Duration> {
+        self.register_delay
+    }
+
+    /// Effective configuration with the credentials redacted.
+    pub fn config(&self) -> BusConfig {
+        BusConfig {
+            server: redact_credentials(&self.server),
+            node: self.node.clone(),
+            grpc_endpoint: self.grpc_endpoint.clone(),
+            register_subject: self.subjects.register(&self.node),
+            deregister_subject: self.subjects.deregister(&self.node),
+            format: self.format.to_string(),
+            hb_interval_ms: self.hb_interval.as_millis() as u64,
+            keepalive_ms: self.keepalive.map(|d| d.as_millis() as u64),
+            connect_timeout_ms: self
+                .connection
+                .timeout
+                .map(|d| d.as_millis() as u64),
+            max_reconnects: self.connection.max_reconnects,
+            queue_size: self.queue_size,
+            signed: self.signer.is_some(),
+        }
+    }
+
+    /// Build the payload of the register message. If gathering of the health
+    /// summary fails, a plain register message is returned instead, because
+    /// skipping the heartbeat would make the node look dead.
+    pub fn register_args(&self) -> RegisterArgs {
+        RegisterArgs {
+            schema_version: SCHEMA_VERSION,
+            id: self.node.clone(),
+            grpc_endpoint: self.grpc_endpoint.clone(),
+            status: *lock(&STATUS),
+            health: self.health.gather(),
+            seq: self.seq,
+            start_epoch: *START_EPOCH,
+        }
+    }
+
+    /// Build the payload of the next register message to be sent, which has
+    /// the sequence number incremented.
+    pub fn next_register_args(&mut self) -> RegisterArgs {
+        self.seq += 1;
+        self.register_args()
+    }
+
+    /// Start numbering the register messages from one again.
+    pub fn reset_sequence(&mut self) {
+        self.seq = 0;
+    }
+
+    /// Connect to the server and start emitting periodic register messages.
+    /// Runs until the sender side of the command queue is closed.
+    async fn run(
+        &mut self,
+        receiver: &mut CommandReceiver<Command>,
+    ) -> Result<(), Error> {
+        assert!(self.client.is_none());
+
+        // the message bus may be stopped while the server is unreachable
+        let client = select! {
+            res = self.wait_for_connection().fuse() => res?,
+            () = receiver.closed().fuse() => {
+                info!("Message bus stopped before connecting");
+                return Ok(());
+            }
+        };
+        announce_connection(Some(client.clone()));
+        self.client = Some(client);
+        {
+            // a new connection, the self-test below decides if it works
+            let mut state = lock(&STATE);
+            state.connected = true;
+            state.failed = false;
+        }
+        info!("Connected to the NATS server {}", self.server);
+        // restored after reconnect and switch to a different server
+        let mut config_sub = self.subscribe_config().await;
+
+        if let Some(wait) = self.connection.self_test {
+            match self.loopback_test(wait).await {
+                Ok(()) => info!("Message bus self-test passed"),
+                Err(err) => {
+                    // the messages might be dropped on the way to the server
+                    error!(
+                        "Message bus self-test with {} failed: {}",
+                        self.server, err
+                    );
+                    lock(&STATE).failed = true;
+                }
+            }
+        }
+
+        if let Some(delay) = self.register_delay {
+            info!("Delaying the first registration by {:?}", delay);
+            delay_for(delay).await;
+        }
+
+        registration_event!(
+            info,
+            self,
+            "register",
+            "Registering '{}' and grpc server {} ...",
+            self.node,
+            self.grpc_endpoint
+        );
+        // false if the node has been deregistered on request
+        let mut registered = true;
+        // heartbeats are suspended but the loop keeps accepting commands
+        let mut paused = false;
+        // waits for the result of register requested by the user
+        let mut forced: Option<oneshot::Sender<Result<(), Error>>> = None;
+        // when the next heartbeat is due, so that the commands which do not
+        // concern the registration (i.e. events) do not delay it
+        let mut next_beat = Instant::now();
+        // failed register is retried sooner than the next heartbeat
+        let mut retry = Backoff::new(CONNECT_BACKOFF_BASE, self.hb_interval)
+            .with_jitter(CONNECT_BACKOFF_JITTER);
+        loop {
+            let now = Instant::now();
+            if let Some(reply) = forced.take() {
+                next_beat = now + self.hb_interval;
+                let res = self.force_register().await;
+                match &res {
+                    Ok(()) => retry.reset(),
+                    Err(err) => registration_event!(
+                        error,
+                        self,
+                        "register",
+                        error = %err,
+                        "Registration failed: {:?}",
+                        err
+                    ),
+                }
+                // the caller might have given up waiting
+                let _ = reply.send(res);
+            } else if now >= next_beat {
+                next_beat = now + self.hb_interval;
+                if registered && !paused {
+                    match self.heartbeat().await {
+                        Ok(()) => {
+                            retry.reset();
+                            // the server takes messages, so the events which
+                            // failed to publish get another chance
+                            self.replay_events().await;
+                        }
+                        Err(err) => {
+                            next_beat = now + retry.next_delay();
+                            registration_event!(
+                                error,
+                                self,
+                                "register",
+                                error = %err,
+                                "Registration failed: {:?}",
+                                err
+                            );
+                        }
+                    }
+                }
+            }
+            for (subject, payload) in self.events.take_due(now) {
+                self.publish_event(&subject, &payload, false).await;
+            }
+            if self.events.flush_due(now) {
+                self.flush_events().await;
+            }
+            let wake_at = self
+                .events
+                .next_due()
+                .map_or(next_beat, |at| at.min(next_beat));
+            let _res = select! {
+                () = delay_for(
+                    wake_at.saturating_duration_since(Instant::now())
+                ).fuse() => (),
+                msg = next_message(&mut config_sub).fuse() => {
+                    self.push_config(msg).await
+                }
+                cmd = receiver.next() => {
+                    match cmd {
+                        Some(Command::Deregister) => {
+                            if registered {
+                                if let Err(err) = self.deregister().await {
+                                    registration_event!(
+                                        error,
+                                        self,
+                                        "deregister",
+                                        error = %err,
+                                        "Deregistration failed: {:?}",
+                                        err
+                                    );
+                                };
+                                registered = false;
+                            }
+                        }
+                        Some(Command::Register) => {
+                            // register at the top of the loop right away
+                            if !registered {
+                                self.reset_sequence();
+                            }
+                            registered = true;
+                            self.last_sent = None;
+                            next_beat = Instant::now();
+                        }
+                        Some(Command::ForceRegister(reply)) => {
+                            // register at the top of the loop right away
+                            if !registered {
+                                self.reset_sequence();
+                            }
+                            registered = true;
+                            forced = Some(reply);
+                        }
+                        Some(Command::Pause) => {
+                            info!("Pausing heartbeats");
+                            paused = true;
+                            lock(&STATE).paused = true;
+                        }
+                        Some(Command::Resume) => {
+                            // register at the top of the loop right away
+                            if paused {
+                                info!("Resuming heartbeats");
+                            }
+                            paused = false;
+                            lock(&STATE).paused = false;
+                            self.last_sent = None;
+                            next_beat = Instant::now();
+                        }
+                        Some(Command::Reconnected) => {
+                            // The server might have been restarted together
+                            // with the control plane, which then does not
+                            // know about us. Register at the top of the loop
+                            // now, rather than when the interval expires.
+                            info!(
+                                "Reconnected to the NATS server {}",
+                                self.server
+                            );
+                            {
+                                let mut state = lock(&STATE);
+                                state.connected = true;
+                                state.failed = false;
+                            }
+                            self.last_sent = None;
+                            next_beat = Instant::now();
+                            self.replay_events().await;
+                        }
+                        Some(Command::Closed(generation)) => {
+                            if generation == GENERATION.load(Ordering::SeqCst)
+                            {
+                                self.connection_lost();
+                            }
+                        }
+                        Some(Command::Event(subject, payload)) => {
+                            self.publish_limited(&subject, payload).await;
+                        }
+                        Some(Command::RetainedEvent(subject, payload)) => {
+                            self.publish_event(&subject, &payload, true)
+                                .await;
+                        }
+                        Some(Command::ReplicaState(uri, state)) => {
+                            self.events.update_replica(
+                                &uri,
+                                state,
+                                Instant::now(),
+                            );
+                        }
+                        Some(Command::Reconnect(server)) => {
+                            // the heartbeat goes out on the new connection
+                            // at the top of the loop if we are registered
+                            match self.reconnect_to(&server).await {
+                                Ok(()) => {
+                                    self.last_sent = None;
+                                    next_beat = Instant::now();
+                                    self.replay_events().await;
+                                }
+                                Err(err) => error!("{}", err),
+                            }
+                        }
+                        None => {
+                            info!("Terminating the NATS client");
+                            break;
+                        }
+                    }
+                }
+            };
+        }
+
+        if registered {
+            if let Err(err) = self.deregister().await {
+                registration_event!(
+                    error,
+                    self,
+                    "deregister",
+                    error = %err,
+                    "Deregistration failed: {:?}",
+                    err
+                );
+            };
+        }
+        Ok(())
+    }
+
+    /// We retry connect in loop with backoff up to the heartbeat interval
+    /// until successful or until the connect timeout expires if there is one.
+    /// Once connected the nats library will handle reconnections for us. The
+    /// loop does not check if the message bus has been stopped, the caller
+    /// should race it with CommandReceiver::closed().
+    pub async fn wait_for_connection(&self) -> Result<Connection, Error> {
+        let deadline = self.connection.timeout.map(|t| Instant::now() + t);
+        let mut backoff = Backoff::new(CONNECT_BACKOFF_BASE, self.hb_interval)
+            .with_jitter(CONNECT_BACKOFF_JITTER);
+        loop {
+            let res = match deadline {
+                Some(deadline) => {
+                    let left =
+                        deadline.saturating_duration_since(Instant::now());
+                    match timeout(left, self.connect()).await {
+                        Ok(res) => res,
+                        Err(_) => Err(Error::ConnectFailed {
+                            server: self.server.clone(),
+                            cause: std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                "connect timed out",
+                            ),
+                        }),
+                    }
+                }
+                None => self.connect().await,
+            };
+            let err = match res {
+                Ok(client) => {
+                    self.outage.connected();
+                    return Ok(client);
+                }
+                Err(err) => err,
+            };
+            let pause = backoff.next_delay();
+            let pause = match deadline {
+                Some(deadline) => {
+                    let left =
+                        deadline.saturating_duration_since(Instant::now());
+                    if left == Duration::from_secs(0) {
+                        return Err(err);
+                    }
+                    left.min(pause)
+                }
+                None => pause,
+            };
+            self.outage.failed(&err);
+            delay_for(pause).await;
+        }
+    }
+
+    /// Try to connect to the NATS server including DNS resolution step if
+    /// needed.
+    async fn connect(&self) -> Result<Connection, Error> {
+        debug!("Connecting to the message bus...");
+        self.connect_to(&self.server).await
+    }
+
+    /// Make a new connection to the server, which becomes the current one.
+    async fn connect_to(&self, server: &str) -> Result<Connection, Error> {
+        let generation = GENERATION.load(Ordering::SeqCst) + 1;
+        let client = self
+            .connection
+            .options(generation)
+            .connect_async(server)
+            .await
+            .map_err(|cause| Error::ConnectFailed {
+                server: server.to_owned(),
+                cause,
+            })?;
+        GENERATION.store(generation, Ordering::SeqCst);
+        Ok(client)
+    }
+
+    /// The nats library gave up reconnecting to the server. The heartbeats
+    /// keep failing until the node is switched to a different server.
+    fn connection_lost(&self) {
+        error!(
+            "Gave up reconnecting to the NATS server {} after {} attempts",
+            self.server,
+            self.connection
+                .max_reconnects
+                .map_or_else(|| "default".to_owned(), |max| max.to_string())
+        );
+        {
+            let mut state = lock(&STATE);
+            state.failed = true;
+            state.connected = false;
+        }
+        if self.connection.fatal_on_disconnect {
+            error!("Shutting down since the message bus has failed");
+            mayastor_env_stop(1);
+        }
+    }
+
+    /// Replace the connection by a new one to a different server. The current
+    /// connection is kept if the new server is not reachable.
+    pub async fn reconnect_to(&mut self, server: &str) -> Result<(), Error> {
+        let client = self.connect_to(server).await?;
+        announce_connection(Some(client.clone()));
+        if let Some(old) = self.client.replace(client) {
+            if let Err(err) = old.close().await {
+                warn!("Failed to close connection to {}: {}", self.server, err);
+            }
+        }
+        self.outage.connected();
+        {
+            let mut state = lock(&STATE);
+            state.reconnects += 1;
+            state.connected = true;
+            state.failed = false;
+        }
+        info!(
+            "Switched from the NATS server {} to {}",
+            self.server, server
+        );
+        self.server = server.to_owned();
+        if let Some(config) = lock(&CONFIG).as_mut() {
+            config.server = redact_credentials(server);
+        }
+        Ok(())
+    }
+
+    /// Name of the connections to the NATS server.
+    pub fn connection_name(&self) -> &str {
+        &self.connection.name
+    }
+
+    /// Get the NATS client if we are connected.
+    fn client(&self) -> Result<&Connection, Error> {
+        self.client.as_ref().ok_or(Error::NotStarted {})
+    }
+
+    /// Sign the payload if signing is enabled.
+    fn signed<'a>(&self, payload: &'a [u8]) -> std::borrow::Cow<'a, [u8]> {
+        match &self.signer {
+            Some(signer) => signer.sign(payload).into(),
+            None => payload.into(),
+        }
+    }
+
+    /// Publish a message to the given subject. Note that the message is only
+    /// queued and we don't know if it was really sent to the NATS server
+    /// (limitation of the nats lib) unless flush() is called afterwards.
+    pub async fn publish(
+        &self,
+        subject: &str,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        self.client()?
+            .publish(subject, self.signed(payload))
+            .await
+            .map_err(|cause| Error::Publish {
+                cause,
+                subject: subject.to_owned(),
+            })
+    }
+
+    /// Publish a message with the reply subject attached, so that the
+    /// receiver can acknowledge it later without blocking the sender. The
+    /// caller must subscribe to the reply subject to get the reply.
+    pub async fn publish_with_reply(
+        &self,
+        subject: &str,
+        reply: &str,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        self.client()?
+            .publish_request(subject, reply, self.signed(payload))
+            .await
+            .map_err(|cause| Error::Publish {
+                cause,
+                subject: subject.to_owned(),
+            })
+    }
+
+    /// Wait until all queued messages have been sent to the NATS server.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.client()?.flush().await.map_err(|cause| Error::Flush {
+            cause,
+        })
+    }
+
+    /// Send a request and wait for the reply at most for the given time.
+    pub async fn request(
+        &self,
+        subject: &str,
+        payload: &[u8],
+        wait: Duration,
+    ) -> Result<Message, Error> {
+        let payload = self.signed(payload);
+        match timeout(wait, self.client()?.request(subject, payload)).await {
+            Ok(reply) => reply.map_err(|cause| Error::Request {
+                cause,
+                subject: subject.to_owned(),
+            }),
+            Err(_) => Err(Error::Timeout {
+                operation: format!("reply to {}", subject),
+            }),
+        }
+    }
+
+    /// Subscribe to messages on the given subject.
+    pub async fn subscribe(
+        &self,
+        subject: &str,
+    ) -> Result<Subscription, Error> {
+        self.client()?.subscribe(subject).await.map_err(|cause| {
+            Error::Subscribe {
+                cause,
+                subject: subject.to_owned(),
+            }
+        })
+    }
+
+    /// Publish a message to a unique subject we are subscribed to and wait
+    /// for it to come back. It proves that the published messages reach the
+    /// server, i.e. that they are not dropped by a one-way firewall.
+    pub async fn loopback_test(&self, wait: Duration) -> Result<(), Error> {
+        let subject =
+            format!("_loopback.{}.{}", self.node, rand::random::<u64>());
+        let sub = self.subscribe(&subject).await?;
+        self.publish(&subject, b"loopback").await?;
+        self.flush_within("flush of loopback message").await?;
+        match timeout(wait, sub.next()).await {
+            Ok(Some(_)) => Ok(()),
+            _ => Err(Error::Timeout {
+                operation: format!("loopback message on {}", subject),
+            }),
+        }
+    }
+
+    /// Same as subscribe() but the subscription is made again whenever the
+    /// connection to the server is reestablished (see ResilientSubscription).
+    pub async fn subscribe_resilient(
+        &self,
+        subject: &str,
+    ) -> Result<ResilientSubscription, Error> {
+        let connections = CONNECTION.1.clone();
+        // a reconnect in the meantime makes us subscribe again needlessly
+        let seen = connections.borrow().0;
+        let sub = self.subscribe(subject).await?;
+        Ok(ResilientSubscription {
+            subject: subject.to_owned(),
+            sub,
+            seen,
+            connections,
+        })
+    }
+
+    /// Same as subscribe() but the json payloads of the messages are decoded.
+    /// A message which fails to decode is yielded as an error and does not
+    /// end the stream, so that the subscribers need not parse the messages
+    /// themselves.
+    pub async fn subscribe_typed<T: DeserializeOwned>(
+        &self,
+        subject: &str,
+    ) -> Result<impl Stream<Item = Result<T, Error>>, Error> {
+        let sub = self.subscribe(subject).await?;
+        Ok(stream::unfold(sub, |sub| async move {
+            let msg = sub.next().await?;
+            let decoded =
+                serde_json::from_slice(&msg.data).map_err(|e| Error::Decode {
+                    format: PayloadFormat::Json.to_string(),
+                    reason: e.to_string(),
+                });
+            Some((decoded, sub))
+        }))
+    }
+
+    /// Publish the encoded event and schedule the flush of the events, which
+    /// may be due right away (see with_event_flush()). Events are not
+    /// retried, the next one carries the up-to-date state, unless they are
+    /// retained or all of them are kept for the replay after reconnect (see
+    /// with_event_replay()).
+    async fn publish_event(
+        &mut self,
+        subject: &str,
+        payload: &[u8],
+        retain: bool,
+    ) {
+        let keep = self.events.keeps(retain);
+        if keep && !lock(&STATE).connected {
+            self.events.keep(subject, payload);
+            return;
+        }
+        match self.publish(subject, payload).await {
+            Ok(()) => {
+                EVENT_STATS.published.fetch_add(1, Ordering::Relaxed);
+                if self.events.published(Instant::now()) {
+                    self.flush_events().await;
+                }
+            }
+            Err(err) => {
+                warn!("Failed to publish event: {}", err);
+                if keep {
+                    self.events.keep(subject, payload);
+                } else {
+                    EVENT_STATS.failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Publish the event queued by a publish handle unless its subject is
+    /// over the rate limit (see with_rate_limit()).
+    async fn publish_limited(&mut self, subject: &str, payload: Vec<u8>) {
+        match self.events.admit(subject, payload, Instant::now()) {
+            Admission::Publish(payload) => {
+                self.publish_event(subject, &payload, false).await
+            }
+            Admission::Delayed => (),
+            Admission::Dropped => {
+                debug!("Dropped event for {} over the rate limit", subject);
+                EVENT_STATS.limited.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Publish the events stored during the outage in the original order,
+    /// unless the connection is still down.
+    async fn replay_events(&mut self) {
+        if !self.events.replay_pending() || !lock(&STATE).connected {
+            return;
+        }
+        let events = self.events.take_replay();
+        info!("Replaying {} events published during outage", events.len());
+        for (subject, payload) in events {
+            self.publish_event(&subject, &payload, true).await;
+        }
+    }
+
+    /// Subscribe to configuration updates if there is a handler for them.
+    /// Failure is logged, the heartbeats are more important.
+    async fn subscribe_config(&self) -> Option<ResilientSubscription> {
+        self.config_handler.as_ref()?;
+        let subject = config_subject(&self.node);
+        match self.subscribe_resilient(&subject).await {
+            Ok(sub) => Some(sub),
+            Err(err) => {
+                error!("{}", err);
+                None
+            }
+        }
+    }
+
+    /// Pass the configuration update to the handler and reply with the
+    /// result if the control plane asked for it.
+    async fn push_config(&self, msg: Message) {
+        let res = match &self.config_handler {
+            Some(handler) => handler(&msg.data),
+            None => Err("configuration updates are not supported".to_owned()),
+        };
+        match &res {
+            Ok(()) => info!("Applied configuration update of '{}'", self.node),
+            Err(err) => {
+                error!("Failed to apply configuration update: {}", err)
+            }
+        }
+        let reply = match &msg.reply {
+            Some(reply) => reply,
+            None => return,
+        };
+        let ack = serde_json::to_vec(&ConfigAck::from(res)).unwrap();
+        if let Err(err) = self.publish(reply, &ack).await {
+            error!("Failed to acknowledge configuration update: {}", err);
+        }
+    }
+
+    /// Connect to the server, send a single register message as a request and
+    /// wait for the control plane to acknowledge it. Used to check that the
+    /// control plane is reachable (i.e. readiness probes) without starting
+    /// the heartbeats.
+    pub async fn register_once(&mut self, wait: Duration) -> Result<(), Error> {
+        if self.client.is_none() {
+            self.client = Some(self.connect().await?);
+        }
+        let payload = self.next_register_args();
+        self.request(
+            &self.format.subject(&self.subjects.register(&self.node)),
+            &self.format.encode(&payload)?,
+            wait,
+        )
+        .await?;
+        registration_event!(
+            info,
+            self,
+            "register",
+            "Registration of '{}' acknowledged by the control plane",
+            self.node
+        );
+        Ok(())
+    }
+
+    /// Validate the configuration of the message bus without joining the
+    /// cluster: connect to the server, send the register message to the
+    /// validation subject (register subject with ".validate" suffix) and
+    /// flush it. Heartbeats are not started.
+    pub async fn dry_run(&mut self) -> Result<(), Error> {
+        if self.client.is_none() {
+            self.client = Some(self.connect().await?);
+        }
+        let subject = format!(
+            "{}.validate",
+            self.format.subject(&self.subjects.register(&self.node))
+        );
+        let payload = self.next_register_args();
+        self.publish(&subject, &self.format.encode(&payload)?)
+            .await?;
+        self.flush().await?;
+        info!(
+            "Message bus configuration of '{}' is valid (sent to {})",
+            self.node, subject
+        );
+        Ok(())
+    }
+
+    /// Send a register message to the NATS server.
+    async fn register(&mut self) -> Result<(), Error> {
+        let payload = self.next_register_args();
+        self.send_register(payload).await
+    }
+
+    /// Send a periodic register message. With keepalive it is skipped if
+    /// nothing has changed since the last one, unless the keepalive interval
+    /// has elapsed.
+    async fn heartbeat(&mut self) -> Result<(), Error> {
+        let mut payload = self.register_args();
+        if let (Some(keepalive), Some((last, sent_at))) =
+            (self.keepalive, &self.last_sent)
+        {
+            // the sequence number differs in every message
+            payload.seq = last.seq;
+            if payload == *last && sent_at.elapsed() < keepalive {
+                return Ok(());
+            }
+        }
+        self.seq += 1;
+        payload.seq = self.seq;
+        self.send_register(payload).await
+    }
+
+    /// Publish the register message and remember it.
+    async fn send_register(
+        &mut self,
+        payload: RegisterArgs,
+    ) -> Result<(), Error> {
+        self.publish(
+            &self.format.subject(&self.subjects.register(&self.node)),
+            &self.format.encode(&payload)?,
+        )
+        .await?;
+        let now = Instant::now();
+        {
+            let mut state = lock(&STATE);
+            state.registered = true;
+            state.last_register = Some(now);
+        }
+        self.last_sent = Some((payload, now));
+        registration_event!(
+            debug,
+            self,
+            "register",
+            "Registered '{}' and grpc server {}",
+            self.node,
+            self.grpc_endpoint
+        );
+        Ok(())
+    }
+
+    /// Send a register message and wait until it has been flushed to the NATS
+    /// server.
+    async fn force_register(&mut self) -> Result<(), Error> {
+        self.register().await?;
+        self.flush_within("flush of register message").await
+    }
+
+    /// Flush the events published since the last flush. Failure is logged,
+    /// the events are not retried.
+    async fn flush_events(&mut self) {
+        self.events.flushed();
+        match self.flush_within("flush of events").await {
+            Ok(()) => {
+                EVENT_STATS.flushes.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => warn!("Failed to flush events: {}", err),
+        }
+    }
+
+    /// Flush the queued messages, but do not wait longer than the timeout.
+    async fn flush_within(&self, operation: &str) -> Result<(), Error> {
+        match timeout(DEREGISTER_FLUSH_TIMEOUT, self.flush()).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::Timeout {
+                operation: operation.to_owned(),
+            }),
+        }
+    }
+
+    /// Send a deregister message to the NATS server.
+    async fn deregister(&mut self) -> Result<(), Error> {
+        let payload = DeregisterArgs {
+            id: self.node.clone(),
+        };
+        self.publish(
+            &self.format.subject(&self.subjects.deregister(&self.node)),
+            &self.format.encode(&payload)?,
+        )
+        .await?;
+        lock(&STATE).registered = false;
+        // Make sure the message leaves the process before we carry on with
+        // the shutdown, otherwise the control plane might never learn about
+        // it.
+        self.flush_within("flush of deregister message").await?;
+        registration_event!(
+            info,
+            self,
+            "deregister",
+            "Deregistered '{}' and grpc server {}",
+            self.node,
+            self.grpc_endpoint
+        );
+        Ok(())
+    }
+}
+
+/// Next message of the subscription, never resolves if there is none.
+async fn next_message(sub: &mut Option<ResilientSubscription>) -> Message {
+    match sub {
+        Some(sub) => sub.next().await,
+        None => future::pending().await,
+    }
+}
+
+/// Announce the new connection to the resilient subscriptions, or the
+/// reconnect of the current one if there is no new connection.
+fn announce_connection(client: Option<Connection>) {
+    let sender = lock(&CONNECTION.0);
+    let (count, current) = CONNECTION.1.borrow().clone();
+    // cannot fail, the receiver is never dropped
+    let _ = sender.broadcast((count + 1, client.or(current)));
+}
+
+/// Subscription which survives the loss of the connection to the NATS
+/// server. When the connection is reestablished, either by the nats library
+/// or by switching to a different server, the subject is subscribed again,
+/// so that we do not depend on the library restoring the subscriptions.
+/// Messages received on the old subscription and not read yet are dropped.
+pub struct ResilientSubscription {
+    subject: String,
+    sub: Subscription,
+    /// number of the connection the subscription was made on
+    seen: u64,
+    connections: watch::Receiver<Announced>,
+}
+
+impl ResilientSubscription {
+    /// Subject of the subscription.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Wait for the next message. Unlike Subscription::next() it does not
+    /// end when the subscription is closed, it waits for a new connection
+    /// to subscribe again instead.
+    pub async fn next(&mut self) -> Message {
+        let mut closed = false;
+        loop {
+            let announced = if closed {
+                self.connections.recv().await
+            } else {
+                select! {
+                    msg = self.sub.next().fuse() => match msg {
+                        Some(msg) => return msg,
+                        None => {
+                            debug!("Subscription to {} closed", self.subject);
+                            closed = true;
+                            continue;
+                        }
+                    },
+                    announced = self.connections.recv().fuse() => announced,
+                }
+            };
+            let client = match announced {
+                Some((count, Some(client))) if count != self.seen => {
+                    self.seen = count;
+                    client
+                }
+                _ => continue,
+            };
+            match self.resubscribe(&client).await {
+                Ok(()) => closed = false,
+                Err(err) => error!("{}", err),
+            }
+        }
+    }
+
+    /// Replace the subscription by a new one made on the given connection.
+    async fn resubscribe(&mut self, client: &Connection) -> Result<(), Error> {
+        let sub = client.subscribe(&self.subject).await.map_err(|cause| {
+            Error::Subscribe {
+                cause,
+                subject: self.subject.clone(),
+            }
+        })?;
+        debug!("Subscribed to {} again", self.subject);
+        // the nats library might have restored the old one as well
+        let old = std::mem::replace(&mut self.sub, sub);
+        if let Err(err) = old.unsubscribe().await {
+            debug!("Failed to unsubscribe from {}: {}", self.subject, err);
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the NATS server and start emitting periodic register messages.
+/// Runs until the message_bus_stop() is called or until the server is found
+/// unreachable within the connect timeout. The error is logged and not
+/// returned, since mayastor can carry on without the message bus.
+pub async fn message_bus_run(mbus: MessageBus) -> Result<(), ()> {
+    let (receiver, stopped) = message_bus_init(&mbus);
+    message_bus_serve(mbus, receiver, stopped).await
+}
+
+/// Same as message_bus_run() but the message bus runs on a dedicated thread
+/// with its own runtime, which is not affected by the load of the reactors.
+/// The thread is bound to the core if given, otherwise it runs on the cores
+/// not used by the reactors. If the nice value is given, it is applied to the
+/// thread, so that heartbeats are scheduled ahead of the data path work
+/// competing for the same cpus. A negative value requires CAP_SYS_NICE (or
+/// RLIMIT_NICE permitting it), without it the thread keeps the default
+/// priority. The health gatherer of the message bus must not touch the nexus
+/// instances, the core should publish the health summary using
+/// message_bus_set_health() instead.
+pub fn message_bus_spawn(
+    mbus: MessageBus,
+    core: Option<u32>,
+    nice: Option<i32>,
+) -> std::thread::JoinHandle<()> {
+    // the sender must be in place before this function returns, so that
+    // message_bus_running() does not report a stopped message bus
+    let (receiver, stopped) = message_bus_init(&mbus);
+    Mthread::spawn_named(MESSAGE_BUS_THREAD, core, move || {
+        if let Some(nice) = nice {
+            // the nice value applies to the calling thread only on linux
+            let rc = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+            if rc != 0 {
+                warn!(
+                    "Failed to set nice value of message bus thread to {}: {}",
+                    nice,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _ = rt.block_on(message_bus_serve(mbus, receiver, stopped));
+    })
+}
+
+/// Install the global sender of commands for the message bus and the
+/// receiver of the signal that it has stopped.
+fn message_bus_init(
+    mbus: &MessageBus,
+) -> (CommandReceiver<Command>, oneshot::Sender<()>) {
+    let (sender, receiver) =
+        command_queue::<Command>(mbus.queue_size, mbus.overflow);
+    let mut sender_maybe = lock(&SENDER);
+    if sender_maybe.is_some() {
+        panic!("Double initialization of message bus");
+    }
+    *sender_maybe = Some(sender);
+    *lock(&CONFIG) = Some(mbus.config());
+    if mbus.ship_logs {
+        *lock(&LOG_SUBJECT) =
+            Some(format!("{}.{}", LOG_SUBJECT_PREFIX, mbus.node));
+    }
+    let (stopped, stopped_receiver) = oneshot::channel();
+    *lock(&STOPPED) = Some(stopped_receiver);
+    (receiver, stopped)
+}
+
+/// Run the message bus loop and restart it if it panics, otherwise the
+/// heartbeats would silently stop and the node would look dead. Stopping the
+/// message bus or failure to connect are not restarted.
+async fn watchdog(
+    mbus: &mut MessageBus,
+    mut receiver: CommandReceiver<Command>,
+) -> Result<(), Error> {
+    let mut panics = 0;
+    loop {
+        match AssertUnwindSafe(mbus.run(&mut receiver))
+            .catch_unwind()
+            .await
+        {
+            Ok(res) => return res,
+            Err(_) if panics < MAX_LOOP_RESTARTS => {
+                panics += 1;
+                error!(
+                    "Message bus loop panicked, restarting it ({} of {})",
+                    panics, MAX_LOOP_RESTARTS
+                );
+                // the loop starts with a new connection
+                mbus.client = None;
+                lock(&STATE).connected = false;
+            }
+            Err(_) => {
+                return Err(Error::Panicked {
+                    panics: panics + 1,
+                })
+            }
+        }
+    }
+}
+
+/// Run the message bus until it is stopped and clean up the global state.
+async fn message_bus_serve(
+    mut mbus: MessageBus,
+    receiver: CommandReceiver<Command>,
+    stopped: oneshot::Sender<()>,
+) -> Result<(), ()> {
+    let res = watchdog(&mut mbus, receiver).await;
+    // nobody would ever pick up the queued commands
+    lock(&SENDER).take();
+    lock(&LOG_SUBJECT).take();
+    {
+        let mut state = lock(&STATE);
+        state.connected = false;
+        state.registered = false;
+        state.paused = false;
+    }
+    if let Err(err) = res {
+        error!("Message bus is unavailable: {}", err);
+    }
+    // nobody might be waiting for it
+    let _ = stopped.send(());
+    Ok(())
+}
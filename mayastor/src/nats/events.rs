@@ -0,0 +1,714 @@
+//! Events published over the message bus and the throttling, rate limiting
+//! and buffering of them.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::nats::{
+    lock,
+    send_command,
+    Command,
+    Error,
+    PayloadFormat,
+    EVENT_STATS,
+    FAULT_EVENT_SUBJECT,
+    REBUILD_EVENT_SUBJECT,
+    REBUILD_PROGRESS_STEP,
+    REPLICA_EVENT_DEBOUNCE,
+    REPLICA_EVENT_SUBJECT,
+    RETAINED_EVENTS,
+};
+
+/// Last published rebuild progress of each nexus child.
+static REBUILD_PROGRESS: Lazy<Mutex<ProgressThrottle>> =
+    Lazy::new(|| Mutex::new(ProgressThrottle::default()));
+
+/// What to do with an event whose subject is over the rate limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitPolicy {
+    /// Discard the event and count it (see EventStats::limited())
+    Drop,
+    /// Publish the event once the subject is under the limit again
+    Delay,
+}
+
+impl FromStr for RateLimitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "delay" => Ok(Self::Delay),
+            _ => Err(format!("Invalid rate limit policy {}", s)),
+        }
+    }
+}
+
+/// Rebuild progress event payload
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RebuildProgress {
+    pub nexus: String,
+    pub child: String,
+    /// percentage of the child which has been rebuilt (0-100)
+    pub progress: u64,
+}
+
+/// What happened to the pool
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PoolAction {
+    Created,
+    Deleted,
+}
+
+/// Pool lifecycle event payload
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PoolEvent {
+    pub action: PoolAction,
+    pub name: String,
+    pub disk: String,
+    /// capacity of the pool in bytes
+    pub size: u64,
+}
+
+/// Why a nexus child has been faulted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FaultReason {
+    /// too many IO errors recorded in the error store of the child
+    IoErrors,
+    /// rebuild of the child could not be started or did not complete
+    RebuildFailed,
+    /// faulted on request of the control plane
+    Requested,
+}
+
+/// Child fault event payload
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChildFaultEvent {
+    pub nexus: String,
+    /// URI of the child
+    pub child: String,
+    pub reason: FaultReason,
+}
+
+/// State of a replica as seen by the nexus using it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicaState {
+    Online,
+    Offline,
+}
+
+/// Replica state event payload
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReplicaEvent {
+    pub uri: String,
+    pub state: ReplicaState,
+}
+
+/// Coalesces rapid state changes of the replicas. A state is published only
+/// once it has not changed for the debounce period, and only if it differs
+/// from the last published state of the replica.
+#[derive(Debug)]
+pub struct StateDebouncer {
+    period: Duration,
+    /// last published state of each replica
+    published: HashMap<String, ReplicaState>,
+    /// the latest state which is not published yet and when it was reported
+    pending: HashMap<String, (ReplicaState, Instant)>,
+}
+
+impl StateDebouncer {
+    /// Create a debouncer with the given debounce period.
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            published: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Record the new state of the replica, which restarts its debounce
+    /// period.
+    pub fn update(&mut self, uri: &str, state: ReplicaState, now: Instant) {
+        self.pending.insert(uri.to_owned(), (state, now));
+    }
+
+    /// When the earliest pending state becomes due, if there is any.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.values().map(|(_, at)| *at + self.period).min()
+    }
+
+    /// Take the states which have been stable for the debounce period and
+    /// return those which should be published.
+    pub fn take_due(&mut self, now: Instant) -> Vec<(String, ReplicaState)> {
+        let period = self.period;
+        let due: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, at))| *at + period <= now)
+            .map(|(uri, _)| uri.clone())
+            .collect();
+        let mut changed = Vec::new();
+        for uri in due {
+            let (state, _) = self.pending.remove(&uri).unwrap();
+            if self.published.get(&uri) != Some(&state) {
+                self.published.insert(uri.clone(), state);
+                changed.push((uri, state));
+            }
+        }
+        changed
+    }
+}
+
+/// Decides which progress updates are worth publishing: the first one for
+/// the key, the ones differing by at least REBUILD_PROGRESS_STEP from the
+/// last published one and the completion.
+#[derive(Debug, Default)]
+pub struct ProgressThrottle {
+    last: HashMap<String, u64>,
+}
+
+impl ProgressThrottle {
+    /// Return true if the progress should be published and remember it.
+    pub fn update(&mut self, key: &str, progress: u64) -> bool {
+        let publish = match self.last.get(key) {
+            None => true,
+            Some(&last) => {
+                (progress == 100 && last != 100)
+                    || (progress as i64 - last as i64).abs()
+                        >= REBUILD_PROGRESS_STEP as i64
+            }
+        };
+        if publish {
+            self.last.insert(key.to_owned(), progress);
+        }
+        publish
+    }
+
+    /// Forget the progress of the key, i.e. after completion.
+    pub fn forget(&mut self, key: &str) {
+        self.last.remove(key);
+    }
+}
+
+/// Token bucket of a subject
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// events waiting for a token, the oldest first
+    delayed: VecDeque<Vec<u8>>,
+}
+
+/// Decision of the rate limiter about an event
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    /// The subject is under the limit, publish the event now
+    Publish(Vec<u8>),
+    /// The event is kept until the subject is under the limit again
+    Delayed,
+    /// The event has been discarded
+    Dropped,
+}
+
+/// Limits the rate of the events of each subject to the given number per
+/// second, so that a subsystem emitting events in a tight loop cannot flood
+/// the control plane. Each subject has a token bucket holding up to a
+/// second worth of events. With the delay policy at most as many events as
+/// the rate wait for each subject, the newer ones are dropped.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u32,
+    policy: RateLimitPolicy,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    /// Create a limiter with the rate of events per second and subject.
+    /// Zero rate is treated as one.
+    pub fn new(rate: u32, policy: RateLimitPolicy) -> Self {
+        Self {
+            rate: rate.max(1),
+            policy,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Add the tokens accumulated since the last update of the bucket.
+    fn refill(rate: u32, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate as f64)
+            .min(rate as f64);
+        bucket.updated = now;
+    }
+
+    /// Decide what to do with the event of the subject.
+    pub fn admit(
+        &mut self,
+        subject: &str,
+        payload: Vec<u8>,
+        now: Instant,
+    ) -> Admission {
+        let rate = self.rate;
+        if !self.buckets.contains_key(subject) {
+            self.buckets.insert(
+                subject.to_owned(),
+                Bucket {
+                    tokens: rate as f64,
+                    updated: now,
+                    delayed: VecDeque::new(),
+                },
+            );
+        }
+        let bucket = self.buckets.get_mut(subject).unwrap();
+        Self::refill(rate, bucket, now);
+        // the delayed events go first
+        if bucket.delayed.is_empty() && bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Admission::Publish(payload);
+        }
+        match self.policy {
+            RateLimitPolicy::Delay if bucket.delayed.len() < rate as usize => {
+                bucket.delayed.push_back(payload);
+                Admission::Delayed
+            }
+            _ => Admission::Dropped,
+        }
+    }
+
+    /// Take the delayed events which can be published now, in the order in
+    /// which they were admitted for each subject.
+    pub fn take_due(&mut self, now: Instant) -> Vec<(String, Vec<u8>)> {
+        let rate = self.rate;
+        let mut due = Vec::new();
+        for (subject, bucket) in self.buckets.iter_mut() {
+            if bucket.delayed.is_empty() {
+                continue;
+            }
+            Self::refill(rate, bucket, now);
+            while bucket.tokens >= 1.0 {
+                match bucket.delayed.pop_front() {
+                    Some(payload) => {
+                        bucket.tokens -= 1.0;
+                        due.push((subject.clone(), payload));
+                    }
+                    None => break,
+                }
+            }
+        }
+        due
+    }
+
+    /// When the earliest delayed event can be published, if there is any.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.buckets
+            .values()
+            .filter(|bucket| !bucket.delayed.is_empty())
+            .map(|bucket| {
+                let missing = (1.0 - bucket.tokens).max(0.0);
+                bucket.updated
+                    + Duration::from_secs_f64(missing / self.rate as f64)
+            })
+            .min()
+    }
+}
+
+/// Events which could not be published while the NATS server was
+/// unreachable, kept in the order of publishing to be replayed after the
+/// reconnect (see MessageBus::with_event_replay() and
+/// EventPublisher::publish_retained()). The oldest events are dropped to make
+/// room for the new ones when it is full.
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    events: VecDeque<(String, Vec<u8>)>,
+}
+
+impl ReplayBuffer {
+    /// Create a buffer for at most capacity events.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Store the event and return the oldest one if it has been dropped.
+    pub fn push(
+        &mut self,
+        subject: String,
+        payload: Vec<u8>,
+    ) -> Option<(String, Vec<u8>)> {
+        if self.capacity == 0 {
+            return Some((subject, payload));
+        }
+        let dropped = if self.events.len() >= self.capacity {
+            self.events.pop_front()
+        } else {
+            None
+        };
+        self.events.push_back((subject, payload));
+        dropped
+    }
+
+    /// Number of the stored events.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// True if there are no stored events.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Take all stored events, the oldest first.
+    pub fn take(&mut self) -> VecDeque<(String, Vec<u8>)> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// Event state of the message bus: batching of the flushes, the replay
+/// after reconnect, the rate limit and the debouncing of the replica states.
+/// It decides what is published and when, the message bus does the
+/// publishing itself.
+#[derive(Debug)]
+pub(crate) struct EventPipeline {
+    /// flush after this many events or when the oldest unflushed one has
+    /// been waiting for the interval
+    flush: Option<(u32, Duration)>,
+    /// number of events published since the last flush
+    unflushed: u32,
+    /// when the unflushed events must be flushed at the latest
+    flush_at: Option<Instant>,
+    replay: ReplayBuffer,
+    /// replay all events, not only the retained ones
+    replay_all: bool,
+    rate_limit: Option<RateLimiter>,
+    replica_states: StateDebouncer,
+}
+
+impl Default for EventPipeline {
+    fn default() -> Self {
+        Self {
+            flush: None,
+            unflushed: 0,
+            flush_at: None,
+            replay: ReplayBuffer::new(RETAINED_EVENTS),
+            replay_all: false,
+            rate_limit: None,
+            replica_states: StateDebouncer::new(REPLICA_EVENT_DEBOUNCE),
+        }
+    }
+}
+
+impl EventPipeline {
+    /// See MessageBus::with_event_flush().
+    pub fn set_flush(&mut self, count: u32, interval: Duration) {
+        self.flush = Some((count.max(1), interval));
+    }
+
+    /// See MessageBus::with_event_replay().
+    pub fn set_replay(&mut self, capacity: usize) {
+        self.replay = ReplayBuffer::new(capacity);
+        self.replay_all = true;
+    }
+
+    /// See MessageBus::with_rate_limit().
+    pub fn set_rate_limit(&mut self, limiter: RateLimiter) {
+        self.rate_limit = Some(limiter);
+    }
+
+    /// See MessageBus::with_replica_debounce().
+    pub fn set_replica_debounce(&mut self, period: Duration) {
+        self.replica_states = StateDebouncer::new(period);
+    }
+
+    /// True if the event is kept for the replay when it cannot be published.
+    pub fn keeps(&self, retain: bool) -> bool {
+        retain || self.replay_all
+    }
+
+    /// Store the event for the replay after reconnect. The event dropped to
+    /// make room for it, if any, is counted as failed.
+    pub fn keep(&mut self, subject: &str, payload: &[u8]) {
+        EVENT_STATS.buffered.fetch_add(1, Ordering::Relaxed);
+        if let Some((subject, _)) =
+            self.replay.push(subject.to_owned(), payload.to_owned())
+        {
+            debug!("Dropped event for {} from the replay buffer", subject);
+            EVENT_STATS.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// True if there are events waiting for the replay.
+    pub fn replay_pending(&self) -> bool {
+        !self.replay.is_empty()
+    }
+
+    /// Take the events stored for the replay, the oldest first.
+    pub fn take_replay(&mut self) -> VecDeque<(String, Vec<u8>)> {
+        self.replay.take()
+    }
+
+    /// Account for a published event. Returns true if the events should be
+    /// flushed right away, otherwise the flush is scheduled (see
+    /// flush_due()).
+    pub fn published(&mut self, now: Instant) -> bool {
+        let (count, interval) = match self.flush {
+            Some(flush) => flush,
+            None => return false,
+        };
+        self.unflushed += 1;
+        if self.unflushed >= count {
+            return true;
+        }
+        if self.flush_at.is_none() {
+            self.flush_at = Some(now + interval);
+        }
+        false
+    }
+
+    /// True if the scheduled flush of the events is due.
+    pub fn flush_due(&self, now: Instant) -> bool {
+        self.flush_at.map_or(false, |at| now >= at)
+    }
+
+    /// Account for the flush of the events.
+    pub fn flushed(&mut self) {
+        self.unflushed = 0;
+        self.flush_at = None;
+    }
+
+    /// Decide what to do with the event according to the rate limit, if
+    /// there is one.
+    pub fn admit(
+        &mut self,
+        subject: &str,
+        payload: Vec<u8>,
+        now: Instant,
+    ) -> Admission {
+        match self.rate_limit.as_mut() {
+            Some(limiter) => limiter.admit(subject, payload, now),
+            None => Admission::Publish(payload),
+        }
+    }
+
+    /// Note the reported state of the replica (see StateDebouncer).
+    pub fn update_replica(
+        &mut self,
+        uri: &str,
+        state: ReplicaState,
+        now: Instant,
+    ) {
+        self.replica_states.update(uri, state, now);
+    }
+
+    /// Take the events which can be published now: the replica states which
+    /// have been stable for the debounce period, followed by the events
+    /// delayed by the rate limit.
+    pub fn take_due(&mut self, now: Instant) -> Vec<(String, Vec<u8>)> {
+        let mut due = self
+            .replica_states
+            .take_due(now)
+            .into_iter()
+            .map(|(uri, state)| {
+                let event = ReplicaEvent {
+                    uri,
+                    state,
+                };
+                let payload = serde_json::to_vec(&event).unwrap();
+                (REPLICA_EVENT_SUBJECT.to_owned(), payload)
+            })
+            .collect::<Vec<_>>();
+        if let Some(limiter) = self.rate_limit.as_mut() {
+            due.extend(limiter.take_due(now));
+        }
+        due
+    }
+
+    /// When there is something to do with the events at the earliest, be it
+    /// the flush or publishing of the pending ones.
+    pub fn next_due(&self) -> Option<Instant> {
+        [
+            self.flush_at,
+            self.replica_states.next_due(),
+            self.rate_limit.as_ref().and_then(RateLimiter::next_due),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+}
+
+/// Counters of the events passed to the message bus. They are shared by all
+/// publish handles as they all use the same connection.
+#[derive(Debug, Default)]
+pub struct EventStats {
+    pub(super) queued: AtomicU64,
+    pub(super) published: AtomicU64,
+    pub(super) failed: AtomicU64,
+    pub(super) flushes: AtomicU64,
+    pub(super) buffered: AtomicU64,
+    pub(super) limited: AtomicU64,
+}
+
+impl EventStats {
+    /// Number of events accepted by the command queue.
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Number of events handed over to the NATS connection.
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Number of events which failed to be published and were dropped.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Number of flushes of the published events (see
+    /// MessageBus::with_event_flush()).
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    /// Number of events stored for the replay after reconnect (see
+    /// MessageBus::with_event_replay()).
+    pub fn buffered(&self) -> u64 {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    /// Number of events dropped over the rate limit of their subject (see
+    /// MessageBus::with_rate_limit()).
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+}
+
+/// Cheap cloneable handle for publishing events over the connection of the
+/// message bus. Handles can be obtained before the message bus is started,
+/// publishing fails with NotStarted until it is running.
+#[derive(Clone, Debug)]
+pub struct EventPublisher {
+    stats: &'static EventStats,
+}
+
+impl EventPublisher {
+    /// Publish the event to the subject. Events are always json encoded,
+    /// they are consumed by tools rather than by the registry.
+    pub fn publish<T: Serialize>(
+        &self,
+        subject: &str,
+        event: &T,
+    ) -> Result<(), Error> {
+        let payload = serde_json::to_vec(event).map_err(|e| Error::Encode {
+            format: PayloadFormat::Json.to_string(),
+            reason: e.to_string(),
+        })?;
+        send_command(Command::Event(subject.to_owned(), payload))?;
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Same as publish() but the event is kept while the server is
+    /// unreachable and published after reconnect, for the events which the
+    /// control plane must not miss. It is not subject to the rate limit.
+    pub fn publish_retained<T: Serialize>(
+        &self,
+        subject: &str,
+        event: &T,
+    ) -> Result<(), Error> {
+        let payload = serde_json::to_vec(event).map_err(|e| Error::Encode {
+            format: PayloadFormat::Json.to_string(),
+            reason: e.to_string(),
+        })?;
+        send_command(Command::RetainedEvent(subject.to_owned(), payload))?;
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Counters of the events published through the shared connection.
+    pub fn stats(&self) -> &'static EventStats {
+        self.stats
+    }
+}
+
+/// Get a handle for publishing events over the shared connection of the
+/// message bus.
+pub fn message_bus_publisher() -> EventPublisher {
+    EventPublisher {
+        stats: &EVENT_STATS,
+    }
+}
+
+/// Publish the event to the subject using a temporary publish handle.
+pub fn message_bus_event<T: Serialize>(
+    subject: &str,
+    event: &T,
+) -> Result<(), Error> {
+    message_bus_publisher().publish(subject, event)
+}
+
+/// Publish the rebuild progress of the nexus child unless it has changed
+/// too little since the last published one (see ProgressThrottle). Returns
+/// true if the event has been queued. The rebuild job calls it only when
+/// the progress in percent changes, not for every segment.
+pub fn emit_rebuild_progress(nexus: &str, child: &str, progress: u64) -> bool {
+    let key = format!("{}/{}", nexus, child);
+    {
+        let mut throttle = lock(&REBUILD_PROGRESS);
+        if !throttle.update(&key, progress) {
+            return false;
+        }
+        // the next rebuild of the child starts from scratch
+        if progress == 100 {
+            throttle.forget(&key);
+        }
+    }
+    let event = RebuildProgress {
+        nexus: nexus.to_owned(),
+        child: child.to_owned(),
+        progress,
+    };
+    match message_bus_event(REBUILD_EVENT_SUBJECT, &event) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!("Rebuild progress of {} not published: {}", key, err);
+            false
+        }
+    }
+}
+
+/// Publish the fault of the nexus child. The event is retained, so that it
+/// reaches the control plane even if the server is unreachable right now.
+pub fn emit_child_fault(
+    nexus: &str,
+    child: &str,
+    reason: FaultReason,
+) -> Result<(), Error> {
+    let event = ChildFaultEvent {
+        nexus: nexus.to_owned(),
+        child: child.to_owned(),
+        reason,
+    };
+    message_bus_publisher().publish_retained(FAULT_EVENT_SUBJECT, &event)
+}
+
+/// Report the new state of the replica to the message bus, which publishes
+/// it once it has settled (see StateDebouncer).
+pub fn emit_replica_state(uri: &str, state: ReplicaState) -> Result<(), Error> {
+    send_command(Command::ReplicaState(uri.to_owned(), state))
+}
@@ -0,0 +1,248 @@
+//! Health of the node reported to the control plane and of the connection
+//! to the NATS server, and monitoring of the heartbeats of other nodes.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::nats::{lock, scale, Error, RegisterArgs, HEALTH};
+
+/// Makes sure that the connection errors are logged only once per outage, so
+/// that the log is not flooded while retrying. It is re-armed by a successful
+/// connection, so that the next outage is logged again.
+#[derive(Debug, Default)]
+pub struct OutageLog {
+    logged: Cell<bool>,
+}
+
+impl OutageLog {
+    /// Log the connection error unless an error has been logged since the
+    /// last successful connection. Returns true if the error was logged.
+    pub fn failed(&self, err: &Error) -> bool {
+        if self.logged.replace(true) {
+            debug!("{}", err);
+            false
+        } else {
+            warn!("{} (retrying without further warnings)", err);
+            true
+        }
+    }
+
+    /// Re-arm the warning after successful connection.
+    pub fn connected(&self) {
+        if self.logged.replace(false) {
+            info!("Connection to the NATS server has been restored");
+        }
+    }
+}
+
+/// Status of the node as seen by the control plane
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum NodeStatus {
+    /// mayastor is still starting up (i.e. importing pools)
+    Starting,
+    /// mayastor is fully functional
+    Ready,
+    /// mayastor is running but some of its resources are not healthy
+    Degraded,
+}
+
+/// Health of a single nexus as reported in the register message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NexusHealth {
+    pub name: String,
+    pub status: String,
+    #[serde(rename = "degradedChildren")]
+    pub degraded_children: u32,
+}
+
+/// Compact health summary which is optionally carried by the register
+/// message, so that the control plane learns about degradation between full
+/// polls.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HealthSummary {
+    pub nexus: Vec<NexusHealth>,
+}
+
+/// Closure called on each heartbeat to gather the health summary. It is
+/// supplied by the caller, so that the message bus does not have to know
+/// about the nexus internals.
+/// It must be thread safe, because the message bus can run on a dedicated
+/// thread (see message_bus_spawn()).
+pub type HealthGatherer = Box<dyn Fn() -> Result<HealthSummary, String> + Send>;
+
+/// Health summary of the register messages: the gatherer and the last
+/// summary it returned if gathering is throttled.
+#[derive(Default)]
+pub(crate) struct HealthSource {
+    gatherer: Option<HealthGatherer>,
+    throttle: Option<Duration>,
+    /// the last gathered summary and when it was gathered
+    last: Mutex<Option<(Instant, HealthSummary)>>,
+}
+
+impl HealthSource {
+    /// See MessageBus::with_health().
+    pub fn set_gatherer(&mut self, gatherer: HealthGatherer) {
+        self.gatherer = Some(gatherer);
+    }
+
+    /// See MessageBus::with_health_throttle().
+    pub fn set_throttle(&mut self, period: Duration) {
+        self.throttle = Some(period);
+    }
+
+    /// The health summary, if there is a gatherer and it succeeds. A failure
+    /// is logged and not remembered, the next heartbeat tries again.
+    pub fn gather(&self) -> Option<HealthSummary> {
+        let gatherer = self.gatherer.as_ref()?;
+        match self.gather_throttled(gatherer) {
+            Ok(summary) => Some(summary),
+            Err(err) => {
+                warn!("Failed to gather health summary: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Call the gatherer unless the last summary was gathered within the
+    /// throttle period.
+    fn gather_throttled(
+        &self,
+        gatherer: &HealthGatherer,
+    ) -> Result<HealthSummary, String> {
+        let throttle = match self.throttle {
+            Some(throttle) => throttle,
+            None => return gatherer(),
+        };
+        let mut last = lock(&self.last);
+        if let Some((at, summary)) = &*last {
+            if at.elapsed() < throttle {
+                return Ok(summary.clone());
+            }
+        }
+        let summary = gatherer()?;
+        *last = Some((Instant::now(), summary.clone()));
+        Ok(summary)
+    }
+}
+
+/// Detects dead nodes on the consumer side of the register messages, as the
+/// control plane would do it: a node is flagged as missed when no register
+/// message arrived from it within grace times the expected heartbeat
+/// interval. The callback is called once per miss, the node is alive again
+/// with its next register message.
+pub struct HeartbeatMonitor {
+    timeout: Duration,
+    /// time of the last register message of each node and whether the node
+    /// has been flagged as missed since then
+    nodes: HashMap<String, (Instant, bool)>,
+    on_miss: Option<Box<dyn FnMut(&str) + Send>>,
+}
+
+impl HeartbeatMonitor {
+    /// Create a monitor of nodes sending heartbeats at the given interval.
+    /// The grace is the number of intervals (i.e. HEARTBEAT_GRACE) to wait
+    /// for a heartbeat, greater than one to tolerate jitter.
+    pub fn new(interval: Duration, grace: f64) -> Self {
+        Self {
+            timeout: scale(interval, grace.max(1.0)),
+            nodes: HashMap::new(),
+            on_miss: None,
+        }
+    }
+
+    /// Call the callback with the id of each node when it is missed.
+    pub fn on_miss<F: FnMut(&str) + Send + 'static>(mut self, f: F) -> Self {
+        self.on_miss = Some(Box::new(f));
+        self
+    }
+
+    /// Record the register message received from the node.
+    pub fn register(&mut self, args: &RegisterArgs, now: Instant) {
+        self.nodes.insert(args.id.clone(), (now, false));
+    }
+
+    /// Stop monitoring the node which deregistered.
+    pub fn deregister(&mut self, id: &str) {
+        self.nodes.remove(id);
+    }
+
+    /// Whether the node has missed its heartbeat.
+    pub fn is_missed(&self, id: &str) -> bool {
+        self.nodes.get(id).map_or(false, |(_, missed)| *missed)
+    }
+
+    /// When the earliest node which has not been missed yet will be, if it
+    /// does not send a heartbeat.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.nodes
+            .values()
+            .filter(|(_, missed)| !*missed)
+            .map(|(at, _)| *at + self.timeout)
+            .min()
+    }
+
+    /// Flag the nodes whose deadline has passed, call the callback for each
+    /// of them and return their ids.
+    pub fn check(&mut self, now: Instant) -> Vec<String> {
+        let timeout = self.timeout;
+        let mut missed: Vec<String> = self
+            .nodes
+            .iter_mut()
+            .filter(|(_, (at, missed))| !*missed && *at + timeout <= now)
+            .map(|(id, (_, missed))| {
+                *missed = true;
+                id.clone()
+            })
+            .collect();
+        missed.sort();
+        for id in &missed {
+            warn!("Node {} missed its heartbeat", id);
+            if let Some(on_miss) = self.on_miss.as_mut() {
+                on_miss(id);
+            }
+        }
+        missed
+    }
+}
+
+/// Reply of the json-rpc method inspecting the message bus
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BusHealth {
+    /// connected to the NATS server
+    pub connected: bool,
+    /// the last register message has been sent and not deregistered since
+    pub registered: bool,
+    /// heartbeats are paused without deregistering
+    pub paused: bool,
+    /// gave up reconnecting to the NATS server (see --mbus-max-reconnects)
+    /// or the loopback self-test has failed (see --mbus-self-test)
+    pub failed: bool,
+    /// milliseconds since the last successful register message
+    #[serde(rename = "lastRegisterAgeMs")]
+    pub last_register_age_ms: Option<u64>,
+    /// number of reconnections to the same or a different NATS server
+    #[serde(rename = "reconnectCount")]
+    pub reconnect_count: u64,
+    pub status: NodeStatus,
+}
+
+/// Publish the health summary for the message bus running on a dedicated
+/// thread, which is sent with the next register message.
+pub fn message_bus_set_health(summary: HealthSummary) {
+    *lock(&HEALTH) = Some(summary);
+}
+
+/// Health gatherer returning the summary published by
+/// message_bus_set_health().
+pub fn published_health() -> Result<HealthSummary, String> {
+    lock(&HEALTH)
+        .clone()
+        .ok_or_else(|| "health summary has not been published yet".to_owned())
+}
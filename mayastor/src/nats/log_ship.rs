@@ -0,0 +1,157 @@
+//! Shipping of the error log records over the message bus.
+
+use std::{
+    cell::Cell,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{
+    field::{Field, Visit},
+    Event,
+    Level,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::layer::{self, Layer};
+
+use crate::nats::{
+    lock,
+    Command,
+    EVENT_STATS,
+    LOG_SHIPPING_RATE,
+    LOG_SUBJECT,
+    SENDER,
+};
+
+/// Shipped log record payload
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LogRecord {
+    pub level: String,
+    /// module which has logged the record
+    pub target: String,
+    pub message: String,
+    /// time of the record in milliseconds since unix epoch
+    #[serde(rename = "timestampMs")]
+    pub timestamp_ms: u64,
+}
+
+/// Collects the message and the other fields of the event into one string.
+#[derive(Default)]
+struct RecordMessage(String);
+
+impl Visit for RecordMessage {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.0.insert_str(0, &format!("{:?}", value)),
+            // the fields describing the origin of a log crate record
+            name if name.starts_with("log.") => (),
+            name => self.0.push_str(&format!(" {}={:?}", name, value)),
+        }
+    }
+}
+
+/// Rate limit of the shipped log records
+#[derive(Debug)]
+struct ShippingWindow {
+    start: Instant,
+    shipped: u32,
+}
+
+thread_local! {
+    /// Set while a record is being shipped, so that whatever is logged in
+    /// the process is not shipped in turn.
+    static SHIPPING: Cell<bool> = Cell::new(false);
+}
+
+/// Tracing layer forwarding the warn and error log records to logs.<node>
+/// while the message bus with log shipping is running, so that the control
+/// plane can collect them. It is cheap when shipping is off.
+///
+/// The records of the message bus and of the nats library are not shipped,
+/// since their trouble shipping records would produce more of them. The
+/// records are dropped rather than queued if the command queue of the
+/// message bus is full or there have been LOG_SHIPPING_RATE of them in the
+/// last second.
+#[derive(Debug)]
+pub struct LogShipper {
+    window: Mutex<ShippingWindow>,
+}
+
+impl Default for LogShipper {
+    fn default() -> Self {
+        Self {
+            window: Mutex::new(ShippingWindow {
+                start: Instant::now(),
+                shipped: 0,
+            }),
+        }
+    }
+}
+
+impl LogShipper {
+    /// Count the record in the current window, false if it is over limit.
+    fn admit(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(window.start) >= Duration::from_secs(1) {
+            window.start = now;
+            window.shipped = 0;
+        }
+        if window.shipped >= LOG_SHIPPING_RATE {
+            return false;
+        }
+        window.shipped += 1;
+        true
+    }
+
+    fn ship(&self, event: &Event<'_>) {
+        let normalized = event.normalized_metadata();
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        if *meta.level() > Level::WARN {
+            return;
+        }
+        let target = meta.target();
+        // all modules of the message bus, not only this one
+        if target.starts_with("mayastor::nats") || target.starts_with("nats") {
+            return;
+        }
+        let subject = match lock(&LOG_SUBJECT).clone() {
+            Some(subject) => subject,
+            None => return,
+        };
+        if !self.admit() {
+            return;
+        }
+        let mut message = RecordMessage::default();
+        event.record(&mut message);
+        let record = LogRecord {
+            level: meta.level().to_string(),
+            target: target.to_owned(),
+            message: message.0,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+        };
+        // failures are not logged, that is what we are in the middle of
+        if let Ok(payload) = serde_json::to_vec(&record) {
+            if let Some(sender) = lock(&SENDER).as_ref() {
+                if sender.offer(Command::Event(subject, payload)) {
+                    EVENT_STATS.queued.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+impl<S: tracing::Subscriber> Layer<S> for LogShipper {
+    fn on_event(&self, event: &Event<'_>, _ctx: layer::Context<'_, S>) {
+        SHIPPING.with(|shipping| {
+            if !shipping.replace(true) {
+                self.ship(event);
+                shipping.set(false);
+            }
+        });
+    }
+}
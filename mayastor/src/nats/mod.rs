@@ -0,0 +1,535 @@
+//! NATS message bus connecting mayastor to control plane (moac).
+//!
+//! It is designed to make sending events to control plane easy in the future.
+//! That's the reason for global sender protected by the mutex, that is used to
+//! pass commands to the message bus and to terminate it.
+//!
+//! There is at most one message bus running in the process (starting a second
+//! one panics) and it owns the only connection to the NATS server. Subsystems
+//! emitting events must not open their own connections, they should obtain a
+//! publish handle from message_bus_publisher() instead, which queues the
+//! events for the shared connection.
+
+use std::{
+    sync::{atomic::AtomicU64, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{channel::oneshot, future, FutureExt};
+use nats::asynk::Connection;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use snafu::Snafu;
+use tokio::{sync::watch, time::timeout};
+
+use crate::jsonrpc::{jsonrpc_register, Code, RpcErrorCode};
+
+pub use bus::{
+    config_subject,
+    connection_name,
+    message_bus_run,
+    message_bus_spawn,
+    parse_hb_interval,
+    parse_register_delay,
+    redact_credentials,
+    register_shard,
+    Backoff,
+    BusConfig,
+    ConfigAck,
+    ConfigHandler,
+    MessageBus,
+    ResilientSubscription,
+    Signer,
+};
+pub use events::{
+    emit_child_fault,
+    emit_rebuild_progress,
+    emit_replica_state,
+    message_bus_event,
+    message_bus_publisher,
+    Admission,
+    ChildFaultEvent,
+    EventPublisher,
+    EventStats,
+    FaultReason,
+    PoolAction,
+    PoolEvent,
+    ProgressThrottle,
+    RateLimitPolicy,
+    RateLimiter,
+    RebuildProgress,
+    ReplayBuffer,
+    ReplicaEvent,
+    ReplicaState,
+    StateDebouncer,
+};
+pub use health::{
+    message_bus_set_health,
+    published_health,
+    BusHealth,
+    HealthGatherer,
+    HealthSummary,
+    HeartbeatMonitor,
+    NexusHealth,
+    NodeStatus,
+    OutageLog,
+};
+pub use log_ship::{LogRecord, LogShipper};
+pub use payload::{CompactPayload, PayloadFormat, RegisterArgs};
+pub use queue::{
+    command_queue,
+    CommandClass,
+    CommandReceiver,
+    CommandSender,
+    OverflowPolicy,
+    QueuedCommand,
+};
+
+mod bus;
+mod events;
+mod health;
+mod log_ship;
+mod payload;
+mod queue;
+
+/// Mayastor sends registration messages in this interval (kind of heart-beat)
+const HB_INTERVAL: u64 = 10;
+
+/// How long we wait for the deregister message (or a forced register) to be
+/// flushed to the server (i.e. during shutdown), so that we never block
+/// forever.
+const DEREGISTER_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the self-test waits for the loopback message to come back
+pub const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Length of the HMAC-SHA256 signature appended to signed messages
+pub const SIGNATURE_LEN: usize = 32;
+
+/// Default max time the published events wait in the client buffer when
+/// they are flushed every N events (see MessageBus::with_event_flush())
+pub const EVENT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default subject of register messages
+pub const REGISTER_SUBJECT: &str = "register";
+
+/// Default subject of deregister messages
+pub const DEREGISTER_SUBJECT: &str = "deregister";
+
+/// How long we wait for the control plane to acknowledge the register
+/// message in one-shot mode
+pub const REGISTER_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Version of the register message schema sent in every register message.
+/// It is encoded as major * 100 + minor. Minor version is bumped when fields
+/// are added (older consumers ignore them), major version when the existing
+/// fields change in an incompatible way.
+pub const SCHEMA_VERSION: u32 = 101;
+
+/// How long the shutdown waits for the message bus to deregister, so that
+/// it never hangs if the NATS server is unresponsive
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Subject of the rebuild progress events
+pub const REBUILD_EVENT_SUBJECT: &str = "events.rebuild";
+
+/// Subject of the pool lifecycle events
+pub const POOL_EVENT_SUBJECT: &str = "events.pool";
+
+/// Subject of child fault events
+pub const FAULT_EVENT_SUBJECT: &str = "events.fault";
+
+/// Subject of replica online/offline events
+pub const REPLICA_EVENT_SUBJECT: &str = "events.replica";
+
+/// How long the state of a replica must stay the same before it is
+/// published, so that a flapping replica does not flood the bus.
+pub const REPLICA_EVENT_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Prefix of the subjects of the shipped log records (logs.<node>)
+pub const LOG_SUBJECT_PREFIX: &str = "logs";
+
+/// Max number of log records shipped per second, the rest is dropped, so that
+/// an error repeated in a loop does not flood the bus.
+pub const LOG_SHIPPING_RATE: u32 = 10;
+
+/// Default tolerance of the heartbeat monitor in heartbeat intervals: a node
+/// is missed after two heartbeats did not arrive plus half an interval of
+/// jitter.
+pub const HEARTBEAT_GRACE: f64 = 2.5;
+
+/// First pause between the attempts to connect to the NATS server or to send
+/// the register message, which doubles with each failed attempt up to the
+/// heartbeat interval
+pub const CONNECT_BACKOFF_BASE: Duration = Duration::from_secs(1);
+
+/// Fraction by which the connect and register backoff is randomly shortened,
+/// so that the nodes which lost the server at the same time do not retry in
+/// lockstep
+pub const CONNECT_BACKOFF_JITTER: f64 = 0.2;
+
+/// Rebuild progress is published only when it changes at least by this many
+/// percent (and on completion), so that big rebuilds do not flood the bus.
+pub const REBUILD_PROGRESS_STEP: u64 = 5;
+
+/// How many times the message bus loop is restarted after a panic before
+/// giving up, so that a persistent bug does not end up in a restart loop
+pub const MAX_LOOP_RESTARTS: u32 = 3;
+
+/// Default number of events which can wait for the message bus, the control
+/// commands do not count (see command_queue())
+pub const COMMAND_QUEUE_SIZE: usize = 16;
+
+/// Max number of retained events kept while the NATS server is unreachable
+/// if the replay of all events is not enabled (see
+/// MessageBus::with_event_replay())
+pub const RETAINED_EVENTS: usize = 64;
+
+/// Name of the dedicated message bus thread
+pub const MESSAGE_BUS_THREAD: &str = "mbus";
+
+/// The end of channel used to send messages to or terminate the NATS client.
+static SENDER: Lazy<Mutex<Option<CommandSender<Command>>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// Status of the node reported to the control plane in register messages.
+/// Until the core flips it to ready the node must not be used for volumes.
+static STATUS: Lazy<Mutex<NodeStatus>> =
+    Lazy::new(|| Mutex::new(NodeStatus::Starting));
+
+/// Connectivity and registration state of the message bus for diagnostics.
+static STATE: Lazy<Mutex<BusState>> =
+    Lazy::new(|| Mutex::new(BusState::default()));
+
+/// Generation of the current connection to the NATS server, so that closing
+/// a replaced connection is not mistaken for the loss of the current one.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Number of the announced connection to the NATS server and the connection.
+type Announced = (u64, Option<Connection>);
+
+/// The current connection to the NATS server, announced again whenever the
+/// nats library reconnects it or it is replaced, so that the resilient
+/// subscriptions know when to subscribe again.
+static CONNECTION: Lazy<(
+    Mutex<watch::Sender<Announced>>,
+    watch::Receiver<Announced>,
+)> = Lazy::new(|| {
+    let (sender, receiver) = watch::channel((0, None));
+    (Mutex::new(sender), receiver)
+});
+
+/// Resolves when the running message bus has terminated (and deregistered).
+static STOPPED: Lazy<Mutex<Option<oneshot::Receiver<()>>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// Subject of the shipped log records if the running message bus ships them
+/// (see LogShipper).
+static LOG_SUBJECT: Lazy<Mutex<Option<String>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// Configuration of the running message bus for diagnostics.
+static CONFIG: Lazy<Mutex<Option<BusConfig>>> = Lazy::new(|| Mutex::new(None));
+
+/// Health summary published by the core for the message bus running on a
+/// dedicated thread, which must not access the nexus instances itself.
+static HEALTH: Lazy<Mutex<Option<HealthSummary>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// Start time of this mayastor instance in milliseconds since unix epoch.
+/// It is sent with every register message, so that the control plane can
+/// tell a restarted instance from the old one and ignore stale messages.
+static START_EPOCH: Lazy<u64> = Lazy::new(|| {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+});
+
+/// Counters of events published by all handles (see EventPublisher).
+static EVENT_STATS: Lazy<EventStats> = Lazy::new(EventStats::default);
+
+/// Errors of the message bus: connecting to the NATS server, publishing,
+/// flushing, requests and subscriptions, encoding of the payloads and
+/// queueing of the commands for the message bus loop.
+///
+/// Note: The types here that would be normally used as source for snafu errors
+/// do not implement Error trait required by Snafu. So they are renamed to
+/// "cause" attribute and we use .map_err() instead of .context() when creating
+/// them.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Failed to connect to the NATS server {}: {:?}",
+        server,
+        cause
+    ))]
+    ConnectFailed {
+        cause: std::io::Error,
+        server: String,
+    },
+    #[snafu(display(
+        "Cannot issue requests if message bus hasn't been started"
+    ))]
+    NotStarted {},
+    #[snafu(display("Failed to publish message to {}: {:?}", subject, cause))]
+    Publish {
+        cause: std::io::Error,
+        subject: String,
+    },
+    #[snafu(display("Failed to flush messages: {:?}", cause))]
+    Flush { cause: std::io::Error },
+    #[snafu(display("Request to {} failed: {:?}", subject, cause))]
+    Request {
+        cause: std::io::Error,
+        subject: String,
+    },
+    #[snafu(display("Failed to subscribe to {}: {:?}", subject, cause))]
+    Subscribe {
+        cause: std::io::Error,
+        subject: String,
+    },
+    #[snafu(display("Timed out waiting for {}", operation))]
+    Timeout { operation: String },
+    #[snafu(display("Failed to encode {} payload: {}", format, reason))]
+    Encode { format: String, reason: String },
+    #[snafu(display("Failed to decode {} payload: {}", format, reason))]
+    Decode { format: String, reason: String },
+    #[snafu(display(
+        "Unsupported schema version {} of the message (major {} expected)",
+        version,
+        SCHEMA_VERSION / 100
+    ))]
+    SchemaVersion { version: u32 },
+    #[snafu(display(
+        "Failed to queue {} command for the message bus",
+        command
+    ))]
+    QueueCommand { command: String },
+    #[snafu(display("Message bus loop panicked {} times, giving up", panics))]
+    Panicked { panics: u32 },
+    #[snafu(display("Invalid signature of the message"))]
+    Signature {},
+}
+
+impl RpcErrorCode for Error {
+    fn rpc_error_code(&self) -> Code {
+        Code::InternalError
+    }
+}
+
+/// Commands which can be passed to the running message bus
+#[derive(Debug)]
+enum Command {
+    /// Send a deregister message and stop sending heartbeats (node drain)
+    Deregister,
+    /// Send a register message and resume sending heartbeats
+    Register,
+    /// Send a register message right away, even if paused, and report the
+    /// result once it has been flushed
+    ForceRegister(oneshot::Sender<Result<(), Error>>),
+    /// Stop sending heartbeats without deregistering (NATS maintenance)
+    Pause,
+    /// Send a register message and resume heartbeats after pause
+    Resume,
+    /// Switch to a different NATS server (control plane migration)
+    Reconnect(String),
+    /// The nats library has reconnected to the server after connection loss
+    Reconnected,
+    /// The nats library gave up reconnecting the connection of the given
+    /// generation
+    Closed(u64),
+    /// Publish the encoded event to the subject
+    Event(String, Vec<u8>),
+    /// Publish the encoded event to the subject, keep it for the replay
+    /// after reconnect if the server is unreachable
+    RetainedEvent(String, Vec<u8>),
+    /// The replica with the URI has gone online or offline
+    ReplicaState(String, ReplicaState),
+}
+
+impl QueuedCommand for Command {
+    fn class(&self) -> CommandClass {
+        match self {
+            Command::Event(..) => CommandClass::Plain,
+            // the replica state is debounced, a lost change would stick
+            Command::RetainedEvent(..) | Command::ReplicaState(..) => {
+                CommandClass::Retained
+            }
+            _ => CommandClass::Control,
+        }
+    }
+}
+
+/// Lock the shared state of the message bus. A panic of the message bus loop
+/// poisons the locks it holds, but the loop is restarted (see watchdog()) and
+/// the state is still good enough to carry on, so the poisoning is ignored.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Multiply the duration by the factor. Unlike Duration::mul_f64() it
+/// computes in nanoseconds, so that i.e. 100ms * 2.5 is exactly 250ms.
+fn scale(duration: Duration, factor: f64) -> Duration {
+    Duration::from_nanos((duration.as_nanos() as f64 * factor) as u64)
+}
+
+/// Connectivity and registration state updated by the running message bus
+#[derive(Debug, Default)]
+struct BusState {
+    connected: bool,
+    registered: bool,
+    paused: bool,
+    failed: bool,
+    last_register: Option<Instant>,
+    reconnects: u64,
+}
+
+/// Arguments of the json-rpc method for switching the NATS server
+#[derive(Deserialize, Debug)]
+struct ReconnectArgs {
+    server: String,
+}
+
+/// Return true if the message bus has been started and not stopped yet.
+pub fn message_bus_running() -> bool {
+    lock(&SENDER).is_some()
+}
+
+/// Set the status of the node which is sent with the next register message.
+pub fn message_bus_set_status(status: NodeStatus) {
+    let mut current = lock(&STATUS);
+    if *current != status {
+        info!("Node status changed from {:?} to {:?}", *current, status);
+        *current = status;
+    }
+}
+
+/// Pass the command to the running message bus.
+fn send_command(command: Command) -> Result<(), Error> {
+    let dropped = match lock(&SENDER).as_ref() {
+        Some(sender) => sender.send(command)?,
+        None => return Err(Error::NotStarted {}),
+    };
+    // not logged while holding the sender, the log shipper would need it
+    if let Some(dropped) = dropped {
+        warn!("Message bus command queue is full, dropped {:?}", dropped);
+    }
+    Ok(())
+}
+
+/// Deregister the node and stop sending heartbeats without stopping mayastor
+/// (i.e. when draining the node). Use message_bus_register() to rejoin.
+pub fn message_bus_deregister() -> Result<(), Error> {
+    send_command(Command::Deregister)
+}
+
+/// Register the node again after it has been deregistered by
+/// message_bus_deregister().
+pub fn message_bus_register() -> Result<(), Error> {
+    send_command(Command::Register)
+}
+
+/// Send a register message right away and wait until it has been sent to
+/// the NATS server. The node rejoins the cluster if it has been deregistered
+/// and a paused heartbeat is sent anyway.
+pub async fn message_bus_force_register() -> Result<(), Error> {
+    let (reply, result) = oneshot::channel();
+    send_command(Command::ForceRegister(reply))?;
+    // the message bus stopped before processing the command
+    result.await.map_err(|_| Error::NotStarted {})?
+}
+
+/// Stop sending heartbeats without deregistering the node, i.e. during
+/// maintenance of the NATS server. Commands are still accepted.
+pub fn message_bus_pause() -> Result<(), Error> {
+    send_command(Command::Pause)
+}
+
+/// Resume heartbeats paused by message_bus_pause() with an immediate
+/// register message.
+pub fn message_bus_resume() -> Result<(), Error> {
+    send_command(Command::Resume)
+}
+
+/// Get the effective configuration of the running message bus.
+pub fn message_bus_config() -> Result<BusConfig, Error> {
+    if !message_bus_running() {
+        return Err(Error::NotStarted {});
+    }
+    lock(&CONFIG).clone().ok_or(Error::NotStarted {})
+}
+
+/// Get the connectivity and registration state of the message bus.
+pub fn message_bus_health() -> BusHealth {
+    let state = lock(&STATE);
+    BusHealth {
+        connected: state.connected,
+        registered: state.registered,
+        paused: state.paused,
+        failed: state.failed,
+        last_register_age_ms: state
+            .last_register
+            .map(|t| t.elapsed().as_millis() as u64),
+        reconnect_count: state.reconnects,
+        status: *lock(&STATUS),
+    }
+}
+
+/// Switch the running message bus to a different NATS server without
+/// restarting mayastor.
+pub fn message_bus_reconnect(server: &str) -> Result<(), Error> {
+    send_command(Command::Reconnect(server.to_owned()))
+}
+
+/// Register json-rpc methods for controlling registration of the node.
+pub fn register_rpc_methods() {
+    jsonrpc_register::<(), _, _, Error>("mayastor_deregister", |_| {
+        future::ready(message_bus_deregister()).boxed_local()
+    });
+    jsonrpc_register::<(), _, _, Error>("mayastor_register", |_| {
+        future::ready(message_bus_register()).boxed_local()
+    });
+    jsonrpc_register::<(), _, _, Error>("mayastor_mbus_force_register", |_| {
+        message_bus_force_register().boxed_local()
+    });
+    jsonrpc_register::<(), _, _, Error>("mayastor_mbus_pause", |_| {
+        future::ready(message_bus_pause()).boxed_local()
+    });
+    jsonrpc_register::<(), _, _, Error>("mayastor_mbus_resume", |_| {
+        future::ready(message_bus_resume()).boxed_local()
+    });
+    jsonrpc_register::<(), _, _, Error>("mayastor_mbus_health", |_| {
+        future::ok(message_bus_health()).boxed_local()
+    });
+    jsonrpc_register::<(), _, _, Error>("mayastor_mbus_config", |_| {
+        future::ready(message_bus_config()).boxed_local()
+    });
+    jsonrpc_register::<ReconnectArgs, _, _, Error>(
+        "mayastor_mbus_reconnect",
+        |args| future::ready(message_bus_reconnect(&args.server)).boxed_local(),
+    );
+}
+
+/// Causes the future created by message_bus_run() to resolve.
+pub fn message_bus_stop() {
+    // this will free the sender and unblock the receiver waiting for a message
+    let _sender_maybe = lock(&SENDER).take();
+}
+
+/// Stop the message bus and wait at most for the grace period until it has
+/// deregistered, so that the control plane learns about the shutdown before
+/// the rest of mayastor goes down.
+pub async fn message_bus_stop_and_wait(grace: Duration) {
+    message_bus_stop();
+    let stopped = match lock(&STOPPED).take() {
+        Some(stopped) => stopped,
+        None => return,
+    };
+    if timeout(grace, stopped).await.is_err() {
+        warn!(
+            "Message bus did not stop within {:?}, shutting down anyway",
+            grace
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Encoding of the payloads of the register and deregister messages.
+
+use std::str::FromStr;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::nats::{Error, HealthSummary, NodeStatus, SCHEMA_VERSION};
+
+/// Serialization format of the message payloads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadFormat {
+    Json,
+    /// MessagePack is more compact than json, the subjects get ".msgpack"
+    /// suffix, so that the consumers know how to decode the payload.
+    MsgPack,
+    /// Fixed binary layout of the heartbeats for large clusters (see
+    /// CompactPayload), the subjects get ".compact" suffix.
+    Compact,
+}
+
+impl FromStr for PayloadFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "msgpack" => Ok(Self::MsgPack),
+            "compact" => Ok(Self::Compact),
+            _ => Err(format!("Invalid payload format {}", s)),
+        }
+    }
+}
+
+impl ToString for PayloadFormat {
+    fn to_string(&self) -> String {
+        match *self {
+            PayloadFormat::Json => "json",
+            PayloadFormat::MsgPack => "msgpack",
+            PayloadFormat::Compact => "compact",
+        }
+        .to_owned()
+    }
+}
+
+impl PayloadFormat {
+    /// Subject with the content-type hint for the format if any.
+    pub fn subject(&self, subject: &str) -> String {
+        match *self {
+            PayloadFormat::Json => subject.to_owned(),
+            PayloadFormat::MsgPack => format!("{}.msgpack", subject),
+            PayloadFormat::Compact => format!("{}.compact", subject),
+        }
+    }
+
+    /// Serialize the payload.
+    pub fn encode<T: Serialize + CompactPayload>(
+        &self,
+        payload: &T,
+    ) -> Result<Vec<u8>, Error> {
+        match *self {
+            PayloadFormat::Json => {
+                serde_json::to_vec(payload).map_err(|e| e.to_string())
+            }
+            // named, so that the field renames are preserved as in json
+            PayloadFormat::MsgPack => {
+                rmp_serde::to_vec_named(payload).map_err(|e| e.to_string())
+            }
+            PayloadFormat::Compact => payload.to_compact(),
+        }
+        .map_err(|reason| Error::Encode {
+            format: self.to_string(),
+            reason,
+        })
+    }
+
+    /// Deserialize the payload.
+    pub fn decode<T: DeserializeOwned + CompactPayload>(
+        &self,
+        data: &[u8],
+    ) -> Result<T, Error> {
+        match *self {
+            PayloadFormat::Json => {
+                serde_json::from_slice(data).map_err(|e| e.to_string())
+            }
+            PayloadFormat::MsgPack => {
+                rmp_serde::from_slice(data).map_err(|e| e.to_string())
+            }
+            PayloadFormat::Compact => T::from_compact(data),
+        }
+        .map_err(|reason| Error::Decode {
+            format: self.to_string(),
+            reason,
+        })
+    }
+}
+
+/// Payload which can be encoded in the compact binary format. Strings are
+/// prefixed by a single byte with their length, integers are little endian.
+pub trait CompactPayload: Sized {
+    fn to_compact(&self) -> Result<Vec<u8>, String>;
+    fn from_compact(data: &[u8]) -> Result<Self, String>;
+}
+
+/// Append the string prefixed by its length to the buffer.
+fn put_compact_str(
+    buf: &mut Vec<u8>,
+    name: &str,
+    val: &str,
+) -> Result<(), String> {
+    if val.len() > u8::MAX as usize {
+        return Err(format!("{} is longer than {} bytes", name, u8::MAX));
+    }
+    buf.push(val.len() as u8);
+    buf.extend_from_slice(val.as_bytes());
+    Ok(())
+}
+
+/// Reader of the compact payload which fails on truncated data.
+struct CompactReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> CompactReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("truncated payload".to_owned());
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+/// Register message payload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegisterArgs {
+    /// zero if sent by mayastor which did not know about schema versions
+    #[serde(rename = "schemaVersion", default)]
+    pub schema_version: u32,
+    pub id: String,
+    #[serde(rename = "grpcEndpoint")]
+    pub grpc_endpoint: String,
+    pub status: NodeStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthSummary>,
+    /// Sequence number of the register message which increases with each
+    /// message, so that the duplicate and out-of-order messages can be
+    /// dropped. Together with the start epoch it identifies the message:
+    /// it keeps increasing across reconnects and starts from one again only
+    /// when the node registers after having been deregistered.
+    #[serde(default)]
+    pub seq: u64,
+    /// Start time of the mayastor instance (see START_EPOCH), zero if sent
+    /// by mayastor older than schema version 101
+    #[serde(rename = "startEpoch", default)]
+    pub start_epoch: u64,
+}
+
+/// The compact register message carries just what the control plane needs
+/// to track the node: schema version (u16), sequence number (u64), start
+/// epoch (u64), id, gRPC endpoint and flags byte with the node status in the
+/// lowest two bits.
+/// The health summary is not included.
+impl CompactPayload for RegisterArgs {
+    fn to_compact(&self) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::with_capacity(
+            19 + self.id.len() + self.grpc_endpoint.len() + 1,
+        );
+        buf.extend_from_slice(&(self.schema_version as u16).to_le_bytes());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.extend_from_slice(&self.start_epoch.to_le_bytes());
+        put_compact_str(&mut buf, "node id", &self.id)?;
+        put_compact_str(&mut buf, "gRPC endpoint", &self.grpc_endpoint)?;
+        buf.push(match self.status {
+            NodeStatus::Starting => 0,
+            NodeStatus::Ready => 1,
+            NodeStatus::Degraded => 2,
+        });
+        Ok(buf)
+    }
+
+    fn from_compact(data: &[u8]) -> Result<Self, String> {
+        let mut reader = CompactReader {
+            data,
+        };
+        let schema_version = reader.u16()? as u32;
+        let seq = reader.u64()?;
+        let start_epoch = reader.u64()?;
+        let id = reader.string()?;
+        let grpc_endpoint = reader.string()?;
+        let status = match reader.u8()? & 0x3 {
+            0 => NodeStatus::Starting,
+            1 => NodeStatus::Ready,
+            2 => NodeStatus::Degraded,
+            n => return Err(format!("invalid node status {}", n)),
+        };
+        Ok(Self {
+            schema_version,
+            id,
+            grpc_endpoint,
+            status,
+            health: None,
+            seq,
+            start_epoch,
+        })
+    }
+}
+
+impl RegisterArgs {
+    /// Decode the register message as received by the control plane. Unknown
+    /// fields added by newer minor versions are ignored, while a newer major
+    /// version is flagged as an error.
+    pub fn decode(format: PayloadFormat, data: &[u8]) -> Result<Self, Error> {
+        let args: Self = format.decode(data)?;
+        if args.schema_version / 100 > SCHEMA_VERSION / 100 {
+            return Err(Error::SchemaVersion {
+                version: args.schema_version,
+            });
+        }
+        Ok(args)
+    }
+}
+
+/// Deregister message payload
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct DeregisterArgs {
+    pub(super) id: String,
+}
+
+impl CompactPayload for DeregisterArgs {
+    fn to_compact(&self) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::with_capacity(1 + self.id.len());
+        put_compact_str(&mut buf, "node id", &self.id)?;
+        Ok(buf)
+    }
+
+    fn from_compact(data: &[u8]) -> Result<Self, String> {
+        let mut reader = CompactReader {
+            data,
+        };
+        Ok(Self {
+            id: reader.string()?,
+        })
+    }
+}
@@ -0,0 +1,221 @@
+//! Bounded queue passing the commands to the message bus loop.
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use futures::{
+    future,
+    stream::{FusedStream, Stream},
+};
+
+use crate::nats::Error;
+
+/// What to do with a new command if the command queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued plain event to make room for the new one,
+    /// fail like Reject if there is none
+    DropOldest,
+    /// Fail to queue the new command
+    Reject,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("Invalid overflow policy {}", s)),
+        }
+    }
+}
+
+/// How the command queue treats a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandClass {
+    /// Changes the state of the message bus or has a caller waiting for it:
+    /// it is queued ahead of the others and never rejected nor dropped
+    Control,
+    /// Event which must not be lost: it is rejected if the queue is full but
+    /// never dropped to make room for another command
+    Retained,
+    /// Event which is superseded by the next one: it is the first to be
+    /// dropped if the queue is full
+    Plain,
+}
+
+/// Commands passed through the command queue
+pub trait QueuedCommand {
+    /// How the command is treated by the command queue
+    fn class(&self) -> CommandClass;
+}
+
+/// State shared by the both ends of the command queue
+struct CommandQueue<T> {
+    /// control commands, they do not count towards the capacity
+    control: VecDeque<T>,
+    items: VecDeque<T>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// receiver waiting for a new command
+    waker: Option<Waker>,
+    /// set when the sender has been dropped
+    closed: bool,
+}
+
+impl<T> CommandQueue<T> {
+    /// Wake up the receiver waiting for a new command.
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Create a bounded command queue. Unlike mpsc channel it does not grow
+/// beyond the capacity and the overflow policy decides what happens when it
+/// is full. The control commands are the exception: they are few and losing
+/// one would leave the message bus in a wrong state or its caller waiting
+/// forever, so they have an unbounded queue of their own which is consumed
+/// first. Dropping the sender terminates the receiver stream after the
+/// queued commands have been consumed. Zero capacity is treated as one.
+pub fn command_queue<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (CommandSender<T>, CommandReceiver<T>) {
+    let capacity = capacity.max(1);
+    let queue = Arc::new(Mutex::new(CommandQueue {
+        control: VecDeque::new(),
+        items: VecDeque::with_capacity(capacity),
+        capacity,
+        policy,
+        waker: None,
+        closed: false,
+    }));
+    (
+        CommandSender {
+            queue: Arc::clone(&queue),
+        },
+        CommandReceiver {
+            queue,
+        },
+    )
+}
+
+/// Sending end of the command queue
+pub struct CommandSender<T> {
+    queue: Arc<Mutex<CommandQueue<T>>>,
+}
+
+impl<T: QueuedCommand + std::fmt::Debug> CommandSender<T> {
+    /// Queue the command without blocking. If the queue is full the command
+    /// is either rejected or the oldest plain event is dropped and returned,
+    /// so that the caller can report it once it does not hold any locks.
+    /// The command is rejected if there is no plain event to drop.
+    pub fn send(&self, command: T) -> Result<Option<T>, Error> {
+        let mut queue = self.queue.lock().unwrap();
+        if command.class() == CommandClass::Control {
+            queue.control.push_back(command);
+            queue.wake();
+            return Ok(None);
+        }
+        let mut dropped = None;
+        if queue.items.len() >= queue.capacity {
+            if queue.policy == OverflowPolicy::DropOldest {
+                let plain = queue
+                    .items
+                    .iter()
+                    .position(|item| item.class() == CommandClass::Plain);
+                dropped = plain.and_then(|index| queue.items.remove(index));
+            }
+            if dropped.is_none() {
+                return Err(Error::QueueCommand {
+                    command: format!("{:?}", command),
+                });
+            }
+        }
+        queue.items.push_back(command);
+        queue.wake();
+        Ok(dropped)
+    }
+
+    /// Queue the command only if there is room for it regardless of the
+    /// overflow policy, so that it never displaces a queued command. Returns
+    /// false if the command has been dropped.
+    pub fn offer(&self, command: T) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.items.len() >= queue.capacity {
+            return false;
+        }
+        queue.items.push_back(command);
+        queue.wake();
+        true
+    }
+}
+
+impl<T> Drop for CommandSender<T> {
+    fn drop(&mut self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        queue.wake();
+    }
+}
+
+/// Receiving end of the command queue
+pub struct CommandReceiver<T> {
+    queue: Arc<Mutex<CommandQueue<T>>>,
+}
+
+impl<T> CommandReceiver<T> {
+    /// Resolve once the sender has been dropped. Unlike the end of the
+    /// stream it does not wait for the queued commands to be consumed.
+    pub async fn closed(&self) {
+        future::poll_fn(|cx| {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.closed {
+                Poll::Ready(())
+            } else {
+                queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl<T> Stream for CommandReceiver<T> {
+    type Item = T;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<T>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(command) = queue
+            .control
+            .pop_front()
+            .or_else(|| queue.items.pop_front())
+        {
+            Poll::Ready(Some(command))
+        } else if queue.closed {
+            Poll::Ready(None)
+        } else {
+            queue.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> FusedStream for CommandReceiver<T> {
+    fn is_terminated(&self) -> bool {
+        let queue = self.queue.lock().unwrap();
+        queue.closed && queue.control.is_empty() && queue.items.is_empty()
+    }
+}
@@ -37,6 +37,7 @@ use crate::{
     bdev::{util::uring, Uri},
     core::{Bdev, Share},
     ffihelper::{cb_arg, done_cb},
+    nats::{message_bus_event, PoolAction, PoolEvent, POOL_EVENT_SUBJECT},
     nexus_uri::{bdev_destroy, NexusBdevError},
     replica::ReplicaIter,
 };
@@ -286,6 +287,23 @@ impl Pool {
             spdk_bs_free_cluster_count(lvs.blobstore) * cluster_size
         }
     }
+    /// Get the base bdev of the pool as URI-like string of its driver and
+    /// name.
+    pub fn get_disk(&self) -> String {
+        let base_bdev = self.get_base_bdev();
+        base_bdev.driver() + "://" + &base_bdev.name()
+    }
+
+    /// Build the lifecycle event of the pool.
+    fn event(&self, action: PoolAction) -> PoolEvent {
+        PoolEvent {
+            action,
+            name: self.get_name().to_string(),
+            disk: self.get_disk(),
+            size: self.get_capacity(),
+        }
+    }
+
     /// Return raw pointer to spdk lvol store structure
     pub fn as_ptr(&self) -> *mut spdk_lvol_store {
         self.lvs_ptr
@@ -335,6 +353,7 @@ impl Pool {
         match Pool::lookup(&name) {
             Some(pool) => {
                 info!("The pool {} has been created", name);
+                emit_event(pool.event(PoolAction::Created));
                 Ok(pool)
             }
             None => Err(Error::PoolGone {
@@ -404,7 +423,8 @@ impl Pool {
             }
         }
 
-        // we will destroy lvol store now
+        // we will destroy lvol store now, the event needs its capacity
+        let event = self.event(PoolAction::Deleted);
         let (sender, receiver) = oneshot::channel::<i32>();
         unsafe {
             vbdev_lvs_destruct(self.lvs_ptr, Some(done_cb), cb_arg(sender));
@@ -416,6 +436,7 @@ impl Pool {
                 errno: lvs_errno,
             });
         }
+        emit_event(event);
 
         // we will destroy base bdev now
         let base_bdev = match Bdev::lookup_by_name(&base_bdev_name) {
@@ -526,11 +547,7 @@ impl From<Pool> for rpc::Pool {
     fn from(pool: Pool) -> Self {
         rpc::Pool {
             name: pool.get_name().to_owned(),
-            disks: vec![
-                pool.get_base_bdev().driver()
-                    + "://"
-                    + &pool.get_base_bdev().name(),
-            ],
+            disks: vec![pool.get_disk()],
             // TODO: figure out how to detect state of pool
             state: rpc::PoolState::PoolOnline as i32,
             capacity: pool.get_capacity(),
@@ -539,6 +556,15 @@ impl From<Pool> for rpc::Pool {
     }
 }
 
+/// Let the control plane know about the pool. It is best-effort, the event is
+/// just queued for the message bus if it is running, so that the pool
+/// operation never waits for the bus.
+fn emit_event(event: PoolEvent) {
+    if let Err(err) = message_bus_event(POOL_EVENT_SUBJECT, &event) {
+        debug!("Event of pool {} not published: {}", event.name, err);
+    }
+}
+
 async fn create_pool_legacy(args: rpc::CreatePoolRequest) -> Result<rpc::Pool> {
     // TODO: support RAID-0 devices
     if args.disks.len() != 1 {
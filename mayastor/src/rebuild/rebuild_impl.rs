@@ -16,6 +16,7 @@ use spdk_sys::{spdk_get_thread, SPDK_BDEV_LARGE_BUF_MAX_SIZE};
 use crate::{
     bdev::VerboseError,
     core::{Bdev, BdevHandle, DmaBuf, RangeContext, Reactors},
+    nats,
     nexus_uri::bdev_get_name,
 };
 
@@ -68,6 +69,8 @@ pub(super) struct RebuildTasks {
     total: usize,
 
     segments_done: u64,
+    /// last progress in percent reported to the message bus
+    progress_reported: Option<u64>,
 }
 
 /// Checks whether a range is contained within another range
@@ -152,6 +155,7 @@ impl RebuildJob {
             active: 0,
             total: SEGMENT_TASKS,
             segments_done: 0,
+            progress_reported: None,
         };
 
         for _ in 0 .. tasks.total {
@@ -236,6 +240,19 @@ impl RebuildJob {
         self.reconcile();
     }
 
+    /// Return the total number of blocks to recover and the number of blocks
+    /// recovered so far.
+    fn blocks(&self) -> (u64, u64) {
+        let blocks_total = self.range.end - self.range.start;
+
+        // segment size may not be aligned to the total size
+        let blocks_recovered = std::cmp::min(
+            self.task_pool.segments_done * self.segment_size_blks,
+            blocks_total,
+        );
+        (blocks_total, blocks_recovered)
+    }
+
     /// Return the size of the segment to be copied.
     fn get_segment_size_blks(&self, blk: u64) -> u64 {
         // Adjust the segments size for the last segment
@@ -469,14 +486,7 @@ impl std::fmt::Display for RebuildOperation {
 
 impl ClientOperations for RebuildJob {
     fn stats(&self) -> RebuildStats {
-        let blocks_total = self.range.end - self.range.start;
-
-        // segment size may not be aligned to the total size
-        let blocks_recovered = std::cmp::min(
-            self.task_pool.segments_done * self.segment_size_blks,
-            blocks_total,
-        );
-
+        let (blocks_total, blocks_recovered) = self.blocks();
         let progress = (blocks_recovered * 100) / blocks_total;
 
         info!(
@@ -588,6 +598,17 @@ impl RebuildJob {
             self.task_pool.active -= 1;
             if f.error.is_none() {
                 self.task_pool.segments_done += 1;
+                // the message bus is only bothered once per percent
+                let (total, recovered) = self.blocks();
+                let progress = recovered * 100 / total;
+                if self.task_pool.progress_reported != Some(progress) {
+                    self.task_pool.progress_reported = Some(progress);
+                    nats::emit_rebuild_progress(
+                        &self.nexus,
+                        &self.destination,
+                        progress,
+                    );
+                }
             } else {
                 self.task_pool.tasks[f.id].error = Some(f.clone());
             }
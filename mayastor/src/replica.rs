@@ -3,7 +3,10 @@
 //! Replica is a logical data volume exported over nvmf (in SPDK terminology
 //! an lvol). Here we define methods for easy management of replicas.
 #![allow(dead_code)]
-use std::ffi::{c_void, CStr, CString};
+use std::{
+    ffi::{c_void, CStr, CString},
+    fmt::Display,
+};
 
 use futures::channel::oneshot;
 use nix::errno::Errno;
@@ -38,6 +41,10 @@ use crate::{
     target,
 };
 
+/// Max length in bytes of the snapshot label, which is carried by dwords
+/// 12-15 of the create snapshot command
+pub const SNAPSHOT_LABEL_MAX_LEN: usize = 16;
+
 /// These are high-level context errors one for each rpc method.
 #[derive(Debug, Snafu)]
 pub enum RpcError {
@@ -282,8 +289,52 @@ impl Replica {
 
     /// Format snapshot name
     /// base_name is the nexus or replica UUID
-    pub fn format_snapshot_name(base_name: &str, snapshot_time: u64) -> String {
-        format!("{}-snap-{}", base_name, snapshot_time)
+    /// suffix is the snapshot time or the label given by the user
+    pub fn format_snapshot_name<T: Display>(
+        base_name: &str,
+        suffix: T,
+    ) -> String {
+        format!("{}-snap-{}", base_name, suffix)
+    }
+
+    /// Encode the snapshot label in dwords 12-15 of the create snapshot
+    /// command. Returns None if the label is empty, longer than
+    /// SNAPSHOT_LABEL_MAX_LEN bytes or contains characters other than
+    /// alphanumerics, '-', '_' and '.'.
+    pub fn encode_snapshot_label(label: &str) -> Option<[u32; 4]> {
+        let bytes = label.as_bytes();
+        if bytes.is_empty()
+            || bytes.len() > SNAPSHOT_LABEL_MAX_LEN
+            || !bytes
+                .iter()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(b))
+        {
+            return None;
+        }
+        let mut buf = [0u8; SNAPSHOT_LABEL_MAX_LEN];
+        buf[.. bytes.len()].copy_from_slice(bytes);
+        let mut dwords = [0u32; 4];
+        for (i, dword) in dwords.iter_mut().enumerate() {
+            let mut le = [0u8; 4];
+            le.copy_from_slice(&buf[4 * i .. 4 * i + 4]);
+            *dword = u32::from_le_bytes(le);
+        }
+        Some(dwords)
+    }
+
+    /// Decode the snapshot label from dwords 12-15 of the create snapshot
+    /// command. Returns None if the command does not carry a label.
+    pub fn decode_snapshot_label(dwords: [u32; 4]) -> Option<String> {
+        let bytes = dwords
+            .iter()
+            .flat_map(|dword| dword.to_le_bytes().to_vec())
+            .take_while(|b| *b != 0)
+            .collect::<Vec<_>>();
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        }
     }
 
     /// Create a snapshot
@@ -521,8 +521,9 @@ impl Config {
         }
     }
 
-    /// Import bdevs with a specific order
-    pub fn import_bdevs(&'static self) {
+    /// Import bdevs with a specific order and return the number of them
+    /// which failed to import
+    pub fn import_bdevs(&'static self) -> usize {
         assert_eq!(Cores::current(), Cores::first());
         Reactor::block_on(async move {
             // There should not be any duplicate bdevs in the config
@@ -545,7 +546,10 @@ impl Config {
             if errors != 0 {
                 warn!("Not all bdevs({}) were imported successfully", errors);
             }
-        });
+            errors
+        })
+        // the import has not run to completion
+        .unwrap_or(1)
     }
 
     /// exports the current configuration to the mayastor config file
@@ -48,8 +48,14 @@ extern "C" fn nvmf_create_snapshot_hdlr(req: *mut spdk_nvmf_request) -> i32 {
             cmd.__bindgen_anon_1.cdw10 as u64
                 | (cmd.__bindgen_anon_2.cdw11 as u64) << 32
         };
-        let snapshot_name =
-            Replica::format_snapshot_name(&bd.name(), snapshot_time);
+        // the snapshot is named after the label if the user has given one
+        let label = Replica::decode_snapshot_label([
+            cmd.cdw12, cmd.cdw13, cmd.cdw14, cmd.cdw15,
+        ]);
+        let snapshot_name = match label {
+            Some(label) => Replica::format_snapshot_name(&bd.name(), label),
+            None => Replica::format_snapshot_name(&bd.name(), snapshot_time),
+        };
         // Blobfs operations must be on md_thread
         Reactors::master().send_future(async move {
             replica.create_snapshot(req, &snapshot_name).await;
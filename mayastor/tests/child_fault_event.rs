@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup},
+    core::{mayastor_env_stop, MayastorCliArgs, MayastorEnvironment, Reactor},
+    nats::{
+        message_bus_stop,
+        ChildFaultEvent,
+        FaultReason,
+        MessageBus,
+        FAULT_EVENT_SUBJECT,
+    },
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "fault_event_nexus";
+
+static CHILD0: &str = "malloc:///malloc0?size_mb=64";
+static CHILD1: &str = "malloc:///malloc1?size_mb=64";
+
+#[test]
+fn child_fault_event() {
+    // the message bus runs in the test process as it would in mayastor
+    let server = common::mbus::MockNatsServer::start();
+    let mbus =
+        MessageBus::new(&server.endpoint(), "fault-node", "127.0.0.1:10124");
+    let thread = common::mbus::spawn_message_bus(mbus);
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+
+    common::mayastor_test_init();
+    MayastorEnvironment::new(MayastorCliArgs::default())
+        .start(|| {
+            Reactor::block_on(async {
+                let children = vec![CHILD0.to_string(), CHILD1.to_string()];
+                nexus_create(NEXUS_NAME, 32 * 1024 * 1024, None, &children)
+                    .await
+                    .unwrap();
+                let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+                nexus
+                    .fault_child(CHILD1, FaultReason::Requested)
+                    .await
+                    .unwrap();
+                // faulting the child again does not emit another event
+                nexus
+                    .fault_child(CHILD1, FaultReason::Requested)
+                    .await
+                    .unwrap();
+                nexus.destroy().await.unwrap();
+            });
+            mayastor_env_stop(0);
+        })
+        .unwrap();
+
+    // give a possible duplicate event a chance to arrive
+    server.wait_for_messages(FAULT_EVENT_SUBJECT, 2, Duration::from_secs(1));
+    let events: Vec<ChildFaultEvent> = server
+        .recorded(FAULT_EVENT_SUBJECT)
+        .iter()
+        .map(|data| serde_json::from_slice(data).unwrap())
+        .collect();
+    message_bus_stop();
+    assert!(thread.join().unwrap().is_ok());
+
+    assert_eq!(
+        events,
+        vec![ChildFaultEvent {
+            nexus: NEXUS_NAME.to_string(),
+            child: CHILD1.to_string(),
+            reason: FaultReason::Requested,
+        }]
+    );
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup},
+    core::{mayastor_env_stop, MayastorCliArgs, MayastorEnvironment, Reactor},
+    nats::{
+        message_bus_health,
+        message_bus_stop,
+        ChildFaultEvent,
+        FaultReason,
+        MessageBus,
+        FAULT_EVENT_SUBJECT,
+    },
+};
+
+pub mod common;
+
+static NEXUS_NAME: &str = "fault_outage_nexus";
+
+static CHILD0: &str = "malloc:///malloc0?size_mb=64";
+static CHILD1: &str = "malloc:///malloc1?size_mb=64";
+
+#[test]
+fn child_fault_event_during_outage() {
+    let server = common::mbus::MockNatsServer::start();
+    let port = server.port();
+    let mbus =
+        MessageBus::new(&server.endpoint(), "fault-node", "127.0.0.1:10124");
+    let thread = common::mbus::spawn_message_bus(mbus);
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+
+    // the child is faulted while the server is down
+    drop(server);
+    assert!(common::mbus::wait_for(
+        || !message_bus_health().connected,
+        Duration::from_secs(5)
+    ));
+
+    common::mayastor_test_init();
+    MayastorEnvironment::new(MayastorCliArgs::default())
+        .start(|| {
+            Reactor::block_on(async {
+                let children = vec![CHILD0.to_string(), CHILD1.to_string()];
+                nexus_create(NEXUS_NAME, 32 * 1024 * 1024, None, &children)
+                    .await
+                    .unwrap();
+                let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+                nexus
+                    .fault_child(CHILD1, FaultReason::IoErrors)
+                    .await
+                    .unwrap();
+                nexus.destroy().await.unwrap();
+            });
+            mayastor_env_stop(0);
+        })
+        .unwrap();
+
+    // and published once the server is back
+    let server = common::mbus::MockNatsServer::start_on(port);
+    let events: Vec<ChildFaultEvent> = server
+        .wait_for_messages(FAULT_EVENT_SUBJECT, 1, Duration::from_secs(15))
+        .iter()
+        .map(|(_, data)| serde_json::from_slice(data).unwrap())
+        .collect();
+    message_bus_stop();
+    assert!(thread.join().unwrap().is_ok());
+
+    assert_eq!(
+        events,
+        vec![ChildFaultEvent {
+            nexus: NEXUS_NAME.to_string(),
+            child: CHILD1.to_string(),
+            reason: FaultReason::IoErrors,
+        }]
+    );
+}
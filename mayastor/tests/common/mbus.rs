@@ -0,0 +1,639 @@
+//! Helpers for testing registration of mayastor with the control plane over
+//! the NATS message bus. Except for NatsTestServer, which needs nats-server
+//! binary in the PATH, they use the mock server running in the test process.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    future::Future,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Condvar,
+        Mutex,
+        MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures::future;
+use once_cell::sync::Lazy;
+use tokio::runtime::{Builder, Runtime};
+
+use mayastor::nats::{message_bus_run, MessageBus, RegisterArgs};
+
+use super::ms_exec::{get_path, MayastorProcess};
+
+/// Node name and gRPC endpoint registered by the tests.
+pub const NODE: &str = "test-node";
+pub const GRPC_ENDPOINT: &str = "127.0.0.1:10124";
+
+/// The message bus run by message_bus_run() and the node status are global,
+/// so the tests using them in process must not run in parallel.
+static IN_PROCESS: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Serialize the tests running the message bus in process. A failed test
+/// must not fail the others.
+pub fn in_process() -> MutexGuard<'static, ()> {
+    IN_PROCESS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Message bus of the test node which is not started.
+pub fn message_bus() -> MessageBus {
+    MessageBus::new("127.0.0.1:4222", NODE, GRPC_ENDPOINT)
+}
+
+/// Runtime for running the message bus in process.
+pub fn runtime() -> Runtime {
+    Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+/// Run the message bus in process along with the test, which must stop it
+/// (message_bus_stop()) when done. Returns the result of the message bus and
+/// the output of the test.
+pub fn run_message_bus<F: Future>(
+    mbus: MessageBus,
+    test: F,
+) -> (Result<(), ()>, F::Output) {
+    runtime().block_on(future::join(message_bus_run(mbus), test))
+}
+
+/// Run the message bus in process on a separate thread, so that the test
+/// can block, i.e. on the mock server or on mayastor environment. The test
+/// must stop it (message_bus_stop()) and join the thread.
+pub fn spawn_message_bus(
+    mbus: MessageBus,
+) -> thread::JoinHandle<Result<(), ()>> {
+    thread::spawn(|| runtime().block_on(message_bus_run(mbus)))
+}
+
+/// Start mayastor registering with the NATS server in given interval.
+pub fn start_mayastor(
+    nats_endpoint: &str,
+    hb_interval: u64,
+) -> MayastorProcess {
+    start_mayastor_with_args(nats_endpoint, hb_interval, &[])
+}
+
+/// Same as start_mayastor() with additional arguments.
+pub fn start_mayastor_with_args(
+    nats_endpoint: &str,
+    hb_interval: u64,
+    extra: &[&str],
+) -> MayastorProcess {
+    let args = vec!["-g", GRPC_ENDPOINT, "-N", NODE, "-n", nats_endpoint]
+        .into_iter()
+        .chain(extra.iter().cloned())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    MayastorProcess::new_with_env(
+        args.into_boxed_slice(),
+        &[("MAYASTOR_HB_INTERVAL", &hb_interval.to_string())],
+    )
+    .unwrap()
+}
+
+/// Read the proc file of the thread of the process with given name.
+pub fn thread_file(pid: u32, name: &str, file: &str) -> Option<String> {
+    fs::read_dir(format!("/proc/{}/task", pid))
+        .ok()?
+        .filter_map(|task| task.ok())
+        .find(|task| {
+            fs::read_to_string(task.path().join("comm"))
+                .map(|comm| comm.trim() == name)
+                .unwrap_or(false)
+        })
+        .and_then(|task| fs::read_to_string(task.path().join(file)).ok())
+}
+
+/// Cpus allowed for the thread of the process with given name.
+pub fn thread_cpus(pid: u32, name: &str) -> Option<String> {
+    thread_file(pid, name, "status")?
+        .lines()
+        .find(|line| line.starts_with("Cpus_allowed_list:"))
+        .map(|line| line["Cpus_allowed_list:".len() ..].trim().to_owned())
+}
+
+/// Nice value of the thread of the process with given name.
+pub fn thread_nice(pid: u32, name: &str) -> Option<i32> {
+    let stat = thread_file(pid, name, "stat")?;
+    // the fields following the command name, which starts with the state
+    let fields: Vec<&str> =
+        stat[stat.rfind(')')? + 1 ..].split_whitespace().collect();
+    fields.get(16)?.parse().ok()
+}
+
+/// nats-server running on a free port on localhost, so that tests using it
+/// can run in parallel. The server is killed when dropped.
+pub struct NatsTestServer {
+    child: Child,
+    port: u16,
+}
+
+impl NatsTestServer {
+    /// Start nats-server and wait until it accepts connections.
+    pub fn start() -> Self {
+        // let the OS pick a free port, there is a tiny window for someone
+        // else to grab it before nats-server binds it
+        let port = TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|l| l.local_addr())
+            .unwrap()
+            .port();
+        Self::start_on(port)
+    }
+
+    /// Start nats-server on the given port, i.e. to simulate restart of the
+    /// server which was dropped.
+    pub fn start_on(port: u16) -> Self {
+        let child = Command::new("nats-server")
+            .args(&["-a", "127.0.0.1", "-p", &port.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start nats-server");
+        let server = Self {
+            child,
+            port,
+        };
+
+        if !wait_for(
+            || TcpStream::connect(("127.0.0.1", port)).is_ok(),
+            Duration::from_secs(5),
+        ) {
+            panic!("nats-server did not start within deadline");
+        }
+        server
+    }
+
+    /// Endpoint to pass to mayastor (-n option).
+    pub fn endpoint(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    /// Port the server listens on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// URL for the NATS clients.
+    pub fn url(&self) -> String {
+        format!("nats://{}", self.endpoint())
+    }
+}
+
+impl Drop for NatsTestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Start a mock NATS server and mayastor with the given arguments connected
+/// to it, and return the first register message received within the
+/// timeout. The arguments must contain the gRPC endpoint, otherwise mayastor
+/// does not register.
+pub fn first_register(
+    args: Vec<String>,
+    timeout: Duration,
+) -> Result<RegisterArgs, String> {
+    let server = MockNatsServer::start();
+    let mut args = args;
+    args.extend(vec!["-n".to_string(), server.endpoint()]);
+    let _ms = MayastorProcess::new(args.into_boxed_slice())
+        .map_err(|_| "failed to start mayastor".to_string())?;
+    server
+        .wait_for_registers(1, timeout)
+        .into_iter()
+        .next()
+        .map(|(_, args)| args)
+        .ok_or_else(|| "no register message received".to_string())
+}
+
+/// Wait until the condition becomes true or the timeout expires. Returns
+/// the last value of the condition.
+pub fn wait_for<F: Fn() -> bool>(cond: F, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !cond() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+/// Run mayastor with the given arguments to completion and return its exit
+/// status. Only useful with the options which make mayastor exit on its own
+/// (i.e. --mbus-once).
+pub fn run_mayastor(args: &[&str]) -> ExitStatus {
+    Command::new(get_path("mayastor"))
+        .args(args)
+        .status()
+        .expect("failed to run mayastor")
+}
+
+/// Same as run_mayastor() but the output of mayastor is captured.
+pub fn run_mayastor_output(args: &[&str]) -> Output {
+    Command::new(get_path("mayastor"))
+        .args(args)
+        .output()
+        .expect("failed to run mayastor")
+}
+
+/// Messages received by the mock server along with the time of arrival
+/// indexed by the subject, and the condition signalled when a message arrives
+type Recorded = Arc<(Mutex<HashMap<String, Vec<(Instant, Vec<u8>)>>>, Condvar)>;
+
+/// Subjects for which the mock server replies to requests
+type Acked = Arc<Mutex<HashSet<String>>>;
+
+/// Check if the subject matches the subscription which may contain wildcards.
+fn subject_matches(sub: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for pattern in sub.split('.') {
+        match (pattern, tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => (),
+            (p, Some(t)) if p == t => (),
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+/// Connection of a client to the mock server
+struct Client {
+    writer: Mutex<TcpStream>,
+    /// false if the client does not want its own messages (no echo)
+    echo: AtomicBool,
+    /// subscriptions of the client: sid -> subject
+    subs: Mutex<HashMap<String, String>>,
+}
+
+impl Client {
+    fn write(&self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.lock().unwrap().write_all(data)
+    }
+
+    /// Sids of the subscriptions matching the subject.
+    fn sids(&self, subject: &str) -> Vec<String> {
+        self.subs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, sub)| subject_matches(sub, subject))
+            .map(|(sid, _)| sid.clone())
+            .collect()
+    }
+}
+
+/// Connected clients of the mock server
+type Clients = Arc<Mutex<Vec<Arc<Client>>>>;
+
+/// Minimal NATS server running in the test process, which understands just
+/// enough of the protocol for mayastor to connect, publish and subscribe.
+/// The published messages are recorded, so that the tests can make
+/// assertions on them. They are delivered to the subscribers only after
+/// deliver() has been called, until then the server behaves like a firewall
+/// dropping them.
+pub struct MockNatsServer {
+    port: u16,
+    recorded: Recorded,
+    acked: Acked,
+    deliver: Arc<AtomicBool>,
+    /// client connections, closed when the server is dropped
+    conns: Arc<Mutex<Vec<TcpStream>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MockNatsServer {
+    /// Start the server on a free port on localhost.
+    pub fn start() -> Self {
+        Self::start_on(0)
+    }
+
+    /// Start the server on the given port on localhost, i.e. to simulate
+    /// restart of the server which was dropped.
+    pub fn start_on(port: u16) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // so that the accept loop can notice the stop flag
+        listener.set_nonblocking(true).unwrap();
+
+        let recorded = Recorded::default();
+        let acked = Acked::default();
+        let deliver = Arc::new(AtomicBool::new(false));
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let recorded = Arc::clone(&recorded);
+            let acked = Arc::clone(&acked);
+            let deliver = Arc::clone(&deliver);
+            let conns = Arc::clone(&conns);
+            let stop = Arc::clone(&stop);
+            let clients = Clients::default();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            conns
+                                .lock()
+                                .unwrap()
+                                .push(stream.try_clone().unwrap());
+                            let server = Serve {
+                                port,
+                                recorded: Arc::clone(&recorded),
+                                acked: Arc::clone(&acked),
+                                deliver: Arc::clone(&deliver),
+                                clients: Arc::clone(&clients),
+                            };
+                            thread::spawn(move || server.serve(stream));
+                        }
+                        Err(_) => thread::sleep(Duration::from_millis(50)),
+                    }
+                }
+            })
+        };
+
+        Self {
+            port,
+            recorded,
+            acked,
+            deliver,
+            conns,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Endpoint to pass to mayastor (-n option).
+    pub fn endpoint(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    /// Port the server listens on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// URL for the NATS clients.
+    pub fn url(&self) -> String {
+        format!("nats://{}", self.endpoint())
+    }
+
+    /// Payloads of all messages published to the subject so far.
+    pub fn recorded(&self, subject: &str) -> Vec<Vec<u8>> {
+        self.recorded_at(subject)
+            .into_iter()
+            .map(|(_, payload)| payload)
+            .collect()
+    }
+
+    /// Same as recorded() but with the time when each message arrived.
+    pub fn recorded_at(&self, subject: &str) -> Vec<(Instant, Vec<u8>)> {
+        self.recorded
+            .0
+            .lock()
+            .unwrap()
+            .get(subject)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Block until at least n messages have been published to the subject
+    /// or the timeout expires, and return all of them with the time of
+    /// arrival.
+    pub fn wait_for_messages(
+        &self,
+        subject: &str,
+        n: usize,
+        timeout: Duration,
+    ) -> Vec<(Instant, Vec<u8>)> {
+        let deadline = Instant::now() + timeout;
+        let (lock, cond) = &*self.recorded;
+        let mut recorded = lock.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            if recorded.get(subject).map_or(0, Vec::len) >= n || now >= deadline
+            {
+                break;
+            }
+            recorded = cond.wait_timeout(recorded, deadline - now).unwrap().0;
+        }
+        recorded.get(subject).cloned().unwrap_or_default()
+    }
+
+    /// Same as wait_for_messages() for the json register messages, which
+    /// are returned decoded.
+    pub fn wait_for_registers(
+        &self,
+        n: usize,
+        timeout: Duration,
+    ) -> Vec<(Instant, RegisterArgs)> {
+        self.wait_for_messages("register", n, timeout)
+            .into_iter()
+            .map(|(at, data)| (at, serde_json::from_slice(&data).unwrap()))
+            .collect()
+    }
+
+    /// Wait until the first register message has arrived without blocking
+    /// the runtime running the message bus in process.
+    pub async fn registered(&self) {
+        while self.recorded("register").is_empty() {
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Reply with an empty message to requests sent to the subject, as the
+    /// control plane would do.
+    pub fn ack(&self, subject: &str) {
+        self.acked.lock().unwrap().insert(subject.to_owned());
+    }
+
+    /// Deliver the messages published from now on to the subscribers, as a
+    /// real server would do.
+    pub fn deliver(&self) {
+        self.deliver.store(true, Ordering::Relaxed);
+    }
+}
+
+/// State of the mock server shared by the threads serving the clients
+struct Serve {
+    port: u16,
+    recorded: Recorded,
+    acked: Acked,
+    deliver: Arc<AtomicBool>,
+    clients: Clients,
+}
+
+impl Serve {
+    /// Handle a single client connection until it is closed.
+    fn serve(&self, stream: TcpStream) {
+        stream.set_nonblocking(false).unwrap();
+        let client = Arc::new(Client {
+            writer: Mutex::new(stream.try_clone().unwrap()),
+            echo: AtomicBool::new(true),
+            subs: Mutex::new(HashMap::new()),
+        });
+        self.clients.lock().unwrap().push(Arc::clone(&client));
+        self.handle(&client, BufReader::new(stream));
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|other| !Arc::ptr_eq(other, &client));
+    }
+
+    /// Read and handle the commands of the client.
+    fn handle(&self, client: &Arc<Client>, mut reader: BufReader<TcpStream>) {
+        let info = format!(
+            "INFO {{\"server_id\":\"mock\",\"version\":\"2.1.0\",\
+             \"proto\":1,\"host\":\"127.0.0.1\",\"port\":{},\
+             \"max_payload\":1048576}}\r\n",
+            self.port
+        );
+        if client.write(info.as_bytes()).is_err() {
+            return;
+        }
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => (),
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let res = match words.first().map(|w| w.to_uppercase()) {
+                Some(ref op) if op == "PING" => client.write(b"PONG\r\n"),
+                Some(ref op) if op == "CONNECT" => {
+                    let echo =
+                        !line.replace(' ', "").contains("\"echo\":false");
+                    client.echo.store(echo, Ordering::Relaxed);
+                    Ok(())
+                }
+                Some(ref op) if op == "PUB" && words.len() >= 3 => {
+                    // PUB <subject> [reply-to] <size>
+                    let size: usize = words.last().unwrap().parse().unwrap();
+                    let mut payload = vec![0; size + 2];
+                    if reader.read_exact(&mut payload).is_err() {
+                        return;
+                    }
+                    payload.truncate(size);
+                    let reply = if words.len() == 4 {
+                        Some(words[2])
+                    } else {
+                        None
+                    };
+                    self.publish(client, words[1], reply, payload)
+                }
+                Some(ref op) if op == "SUB" && words.len() >= 3 => {
+                    // SUB <subject> [queue group] <sid>
+                    client.subs.lock().unwrap().insert(
+                        words.last().unwrap().to_string(),
+                        words[1].to_owned(),
+                    );
+                    Ok(())
+                }
+                Some(ref op) if op == "UNSUB" && words.len() >= 2 => {
+                    // UNSUB <sid> [max msgs], the max is not honoured
+                    client.subs.lock().unwrap().remove(words[1]);
+                    Ok(())
+                }
+                // PONG needs no reply
+                _ => Ok(()),
+            };
+            if res.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Record the message published by the client, deliver it if enabled and
+    /// reply to it if the subject is acked.
+    fn publish(
+        &self,
+        client: &Arc<Client>,
+        subject: &str,
+        reply: Option<&str>,
+        payload: Vec<u8>,
+    ) -> std::io::Result<()> {
+        let now = Instant::now();
+        self.recorded
+            .0
+            .lock()
+            .unwrap()
+            .entry(subject.to_owned())
+            .or_default()
+            .push((now, payload.clone()));
+        self.recorded.1.notify_all();
+
+        if self.deliver.load(Ordering::Relaxed) {
+            let clients = self.clients.lock().unwrap().clone();
+            for other in clients {
+                if Arc::ptr_eq(&other, client)
+                    && !other.echo.load(Ordering::Relaxed)
+                {
+                    continue;
+                }
+                for sid in other.sids(subject) {
+                    let head = match reply {
+                        Some(reply) => format!(
+                            "MSG {} {} {} {}\r\n",
+                            subject,
+                            sid,
+                            reply,
+                            payload.len()
+                        ),
+                        None => {
+                            format!(
+                                "MSG {} {} {}\r\n",
+                                subject,
+                                sid,
+                                payload.len()
+                            )
+                        }
+                    };
+                    let mut msg = head.into_bytes();
+                    msg.extend_from_slice(&payload);
+                    msg.extend_from_slice(b"\r\n");
+                    // the other client may have gone away
+                    let _ = other.write(&msg);
+                }
+            }
+        }
+
+        // the client gets the reply only if it is subscribed
+        match reply {
+            Some(reply) if self.acked.lock().unwrap().contains(subject) => {
+                match client.sids(reply).first() {
+                    Some(sid) => client.write(
+                        format!("MSG {} {} 0\r\n\r\n", reply, sid).as_bytes(),
+                    ),
+                    None => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for MockNatsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        for conn in self.conns.lock().unwrap().drain(..) {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+}
@@ -22,6 +22,7 @@ use spdk_sys::spdk_get_thread;
 
 pub mod bdev_io;
 pub mod error_bdev;
+pub mod mbus;
 pub mod ms_exec;
 
 /// call F cnt times, and sleep for a duration between each invocation
@@ -2,7 +2,9 @@ use std::{
     fs,
     io,
     io::Write,
+    os::unix::process::CommandExt,
     panic,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     time::Duration,
 };
@@ -12,11 +14,13 @@ use nix::{
     unistd::{gettid, Pid},
 };
 
+use serde::Deserialize;
+
 use mayastor::core::Mthread;
 
 // there is a CARGO_EXEC_$BIN variable in recent Rust which does
 // not seem to work yet with our compiler version
-fn get_path(bin: &str) -> String {
+pub fn get_path(bin: &str) -> String {
     if std::path::Path::new("./target/debug/bin").exists() {
         format!("./target/debug/{}", bin)
     } else {
@@ -32,6 +36,21 @@ fn hugetlbfs_path() -> String {
     format!("/tmp/mayastor-test-{}", gettid())
 }
 
+fn core_dir_path() -> String {
+    format!("/tmp/mayastor-core-{}", gettid())
+}
+
+/// Return true if the kernel writes core dumps to the working directory of
+/// the crashed process, which is what MayastorProcess::new_with_core_dump()
+/// relies on. Cores piped to a helper (i.e. systemd-coredump) or written to
+/// an absolute path cannot be collected by the tests.
+pub fn core_dumps_supported() -> bool {
+    match fs::read_to_string("/proc/sys/kernel/core_pattern") {
+        Ok(pattern) => !pattern.starts_with('|') && !pattern.starts_with('/'),
+        Err(_) => false,
+    }
+}
+
 /// start mayastor as a separate process and run the closure. By wrapping the
 /// test closure, we can catch errors but still kill mayastor to avoid dangling
 /// process.
@@ -58,13 +77,49 @@ pub struct MayastorProcess {
     pub rpc_path: String,
     /// the hugepage directory we are using
     pub hugetlbfs: String,
+    /// working directory of mayastor if it should dump core on crash
+    core_dir: Option<String>,
 }
 
 impl MayastorProcess {
     /// start mayastor and open the unix socket, if we are able to connect
     /// we know we are up and running and ready for business.
     pub fn new(args: Box<[String]>) -> Result<Self, ()> {
-        let mayastor = get_path("mayastor");
+        Self::new_with_env(args, &[])
+    }
+
+    /// same as new() but with additional environment variables set for
+    /// mayastor
+    pub fn new_with_env(
+        args: Box<[String]>,
+        env: &[(&str, &str)],
+    ) -> Result<Self, ()> {
+        Self::start(args, env, None)
+    }
+
+    /// same as new() but mayastor runs with unlimited core size in its own
+    /// working directory, so that a crash leaves behind a core file (see
+    /// core_path()). Relative paths in the arguments are resolved against
+    /// that directory. The directory is removed when dropped only if there
+    /// is no core in it.
+    pub fn new_with_core_dump(args: Box<[String]>) -> Result<Self, ()> {
+        fs::create_dir_all(core_dir_path())
+            .expect("failed to create core dump directory");
+        Self::start(args, &[], Some(core_dir_path()))
+    }
+
+    fn start(
+        args: Box<[String]>,
+        env: &[(&str, &str)],
+        core_dir: Option<String>,
+    ) -> Result<Self, ()> {
+        // the working directory changes if we collect the core
+        let mayastor = fs::canonicalize(get_path("mayastor"))
+            .expect("mayastor binary not found");
+        let env: Vec<(String, String)> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
 
         let (tx, rx) = std::sync::mpsc::channel::<MayastorProcess>();
         Mthread::spawn_unaffinitized(move || {
@@ -89,14 +144,29 @@ impl MayastorProcess {
                 panic!("failed to mount hugetlbfs");
             }
 
-            let mut child = Command::new(mayastor)
-                .args(&["-r", &rpc_sock_path()])
+            let mut cmd = Command::new(mayastor);
+            cmd.args(&["-r", &rpc_sock_path()])
                 .args(&["--huge-dir", &hugetlbfs_path()])
                 .args(args.into_vec())
+                .envs(env)
                 .stdout(Stdio::piped())
-                .stderr(Stdio::inherit())
-                .spawn()
-                .unwrap();
+                .stderr(Stdio::inherit());
+            if let Some(dir) = &core_dir {
+                cmd.current_dir(dir);
+                unsafe {
+                    cmd.pre_exec(|| {
+                        let limit = libc::rlimit {
+                            rlim_cur: libc::RLIM_INFINITY,
+                            rlim_max: libc::RLIM_INFINITY,
+                        };
+                        if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            let mut child = cmd.spawn().unwrap();
 
             while !MayastorProcess::ping(&rpc_sock_path()) {
                 match child.try_wait() {
@@ -105,6 +175,7 @@ impl MayastorProcess {
                             child: child.id(),
                             rpc_path: rpc_sock_path(),
                             hugetlbfs: hugetlbfs_path(),
+                            core_dir: core_dir.clone(),
                         })
                         .unwrap(),
                     Err(_e) => tx
@@ -112,6 +183,7 @@ impl MayastorProcess {
                             child: 0,
                             rpc_path: rpc_sock_path(),
                             hugetlbfs: hugetlbfs_path(),
+                            core_dir: core_dir.clone(),
                         })
                         .unwrap(),
                     _ => (),
@@ -124,6 +196,7 @@ impl MayastorProcess {
                 child: child.id(),
                 rpc_path: rpc_sock_path(),
                 hugetlbfs: hugetlbfs_path(),
+                core_dir,
             };
 
             let _ = tx.send(m);
@@ -137,6 +210,25 @@ impl MayastorProcess {
         }
     }
 
+    /// PID of the mayastor process
+    pub fn pid(&self) -> u32 {
+        self.child
+    }
+
+    /// path of the core file if mayastor started by new_with_core_dump() has
+    /// crashed
+    pub fn core_path(&self) -> Option<PathBuf> {
+        fs::read_dir(self.core_dir.as_ref()?)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, |name| name.starts_with("core"))
+            })
+    }
+
     /// check to see if rpc is up
     pub fn ping(path: &str) -> bool {
         use std::os::unix::net::UnixStream;
@@ -153,23 +245,75 @@ impl MayastorProcess {
         method: &str,
         arg: serde_json::Value,
     ) -> Result<serde_json::Value, ()> {
+        match self.rpc_exec(method, &arg) {
+            Ok(value) => Ok(value),
+            Err(_) => panic!(
+                "RPC to socket {} with method {} failed arguments {:?}",
+                self.rpc_path, method, arg
+            ),
+        }
+    }
+
+    /// call json-rpc methods listed in the file in order and return their
+    /// responses, or the error of the first one which failed. Each line of
+    /// the file is a json object with the method and its params (null if
+    /// missing), empty lines are skipped.
+    pub fn rpc_script<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Vec<serde_json::Value>, String> {
+        #[derive(Deserialize)]
+        struct Step {
+            method: String,
+            #[serde(default)]
+            params: serde_json::Value,
+        }
+
+        let path = path.as_ref();
+        let script = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let mut responses = Vec::new();
+        for (i, line) in script.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let step: Step = serde_json::from_str(line).map_err(|e| {
+                format!("{}:{}: invalid step: {}", path.display(), i + 1, e)
+            })?;
+            let response = self
+                .rpc_exec(&step.method, &step.params)
+                .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+            responses.push(response);
+        }
+        Ok(responses)
+    }
+
+    /// call json-rpc method using the binary, the error is the output of
+    /// the binary if it fails
+    fn rpc_exec(
+        &self,
+        method: &str,
+        arg: &serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
         let jsonrpc = get_path("jsonrpc");
 
         let output = Command::new(jsonrpc)
             .args(&["-s", &self.rpc_path, "raw", method])
-            .arg(serde_json::to_string(&arg).unwrap())
+            .arg(serde_json::to_string(arg).unwrap())
             .output()
             .expect("could not exec jsonrpc");
 
         if !output.status.success() {
-            panic!(
-                "RPC to socket {} with method {} failed arguments {:?}",
-                self.rpc_path, method, arg
-            );
+            return Err(format!(
+                "method {} failed: {}",
+                method,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
         }
 
         let output_string = String::from_utf8_lossy(&output.stdout);
-        Ok(serde_json::from_str(&output_string).unwrap())
+        serde_json::from_str(&output_string)
+            .map_err(|e| format!("invalid response of {}: {}", method, e))
     }
 
     fn sig_x(&mut self, sig_str: &str, options: Option<WaitPidFlag>) {
@@ -177,7 +321,7 @@ impl MayastorProcess {
             return;
         }
         let child = self.child;
-        if sig_str == "TERM" {
+        if sig_str == "TERM" || sig_str == "SEGV" {
             self.child = 0;
         }
         Command::new("kill")
@@ -204,6 +348,11 @@ impl MayastorProcess {
     pub fn sig_cont(&mut self) {
         self.sig_x("CONT", Some(WaitPidFlag::WCONTINUED));
     }
+
+    /// crash the mayastor process and wait for it to die
+    pub fn sig_segv(&mut self) {
+        self.sig_x("SEGV", None);
+    }
 }
 
 /// ensure we umount the huge pages during shutdown
@@ -216,5 +365,9 @@ impl Drop for MayastorProcess {
             .unwrap();
         let _ = fs::remove_dir(&self.hugetlbfs);
         let _ = Command::new("rm").args(&[&self.rpc_path]).output().unwrap();
+        // fails if there is a core which must be kept for inspection
+        if let Some(dir) = &self.core_dir {
+            let _ = fs::remove_dir(dir);
+        }
     }
 }
@@ -0,0 +1,25 @@
+use std::fs;
+
+pub mod common;
+use common::ms_exec::{core_dumps_supported, MayastorProcess};
+
+#[test]
+fn core_dump_on_crash() {
+    if !core_dumps_supported() {
+        println!("Skipping the test, cores are not written to the cwd");
+        return;
+    }
+
+    let mut ms = MayastorProcess::new_with_core_dump(Box::new([])).unwrap();
+    assert_eq!(ms.core_path(), None);
+    ms.sig_segv();
+
+    let core = ms.core_path().expect("no core file produced");
+    assert!(fs::metadata(&core).unwrap().len() > 0);
+
+    // the core survives the cleanup of the process
+    drop(ms);
+    assert!(core.exists());
+    fs::remove_file(&core).unwrap();
+    let _ = fs::remove_dir(core.parent().unwrap());
+}
@@ -0,0 +1,525 @@
+//! Events published over the message bus, their throttling, rate limiting,
+//! replay after reconnect and shipping of the log records.
+
+use std::time::{Duration, Instant};
+
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+use mayastor::nats::{
+    emit_rebuild_progress,
+    emit_replica_state,
+    message_bus_health,
+    message_bus_publisher,
+    message_bus_stop,
+    Admission,
+    LogRecord,
+    LogShipper,
+    MessageBus,
+    OverflowPolicy,
+    ProgressThrottle,
+    RateLimitPolicy,
+    RateLimiter,
+    RebuildProgress,
+    ReplayBuffer,
+    ReplicaEvent,
+    ReplicaState,
+    StateDebouncer,
+    LOG_SHIPPING_RATE,
+    REBUILD_EVENT_SUBJECT,
+    REPLICA_EVENT_SUBJECT,
+};
+
+pub mod common;
+use common::mbus::{
+    in_process,
+    run_message_bus,
+    spawn_message_bus,
+    GRPC_ENDPOINT,
+    NODE,
+};
+
+#[test]
+fn rebuild_progress_throttle() {
+    let mut throttle = ProgressThrottle::default();
+    let published: Vec<u64> = [0, 1, 4, 5, 6, 9, 50, 99, 100, 100]
+        .iter()
+        .filter(|p| throttle.update("nexus0/child0", **p))
+        .cloned()
+        .collect();
+    assert_eq!(published, vec![0, 5, 50, 99, 100]);
+    // children are tracked independently
+    assert!(throttle.update("nexus0/child1", 1));
+    throttle.forget("nexus0/child0");
+    assert!(throttle.update("nexus0/child0", 1));
+}
+
+#[test]
+fn rebuild_progress_events() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT);
+
+    let emitted = run_message_bus(mbus, async {
+        server.registered().await;
+        let emitted: Vec<u64> = [0, 2, 7, 8, 100]
+            .iter()
+            .filter(|p| emit_rebuild_progress("nexus0", "child0", **p))
+            .cloned()
+            .collect();
+        for _ in 0 .. 50 {
+            if server.recorded(REBUILD_EVENT_SUBJECT).len() >= 3 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        message_bus_stop();
+        emitted
+    })
+    .1;
+
+    assert_eq!(emitted, vec![0, 7, 100]);
+    let events: Vec<RebuildProgress> = server
+        .recorded(REBUILD_EVENT_SUBJECT)
+        .iter()
+        .map(|data| serde_json::from_slice(data).unwrap())
+        .collect();
+    assert_eq!(
+        events.iter().map(|e| e.progress).collect::<Vec<_>>(),
+        vec![0, 7, 100]
+    );
+    assert!(events
+        .iter()
+        .all(|e| e.nexus == "nexus0" && e.child == "child0"));
+}
+
+#[test]
+fn replica_state_debounce() {
+    let period = Duration::from_millis(100);
+    let mut debouncer = StateDebouncer::new(period);
+    let start = Instant::now();
+    let uri = "bdev:///replica0";
+    assert_eq!(debouncer.next_due(), None);
+
+    debouncer.update(uri, ReplicaState::Online, start);
+    assert_eq!(debouncer.next_due(), Some(start + period));
+    assert!(debouncer.take_due(start).is_empty());
+    assert_eq!(
+        debouncer.take_due(start + period),
+        vec![(uri.to_owned(), ReplicaState::Online)]
+    );
+    assert_eq!(debouncer.next_due(), None);
+
+    // each change restarts the period and flapping back to the published
+    // state yields nothing
+    let mut at = start + 2 * period;
+    for state in &[ReplicaState::Offline, ReplicaState::Online] {
+        debouncer.update(uri, *state, at);
+        at += period / 2;
+    }
+    assert!(debouncer.take_due(at).is_empty());
+    assert!(debouncer.take_due(at + period).is_empty());
+    assert_eq!(debouncer.next_due(), None);
+}
+
+#[test]
+fn replica_state_events() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_replica_debounce(Duration::from_millis(300));
+    let uri = "bdev:///replica0";
+
+    let (stable, flapping) = run_message_bus(mbus, async {
+        server.registered().await;
+        emit_replica_state(uri, ReplicaState::Online).unwrap();
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+        let stable = server.recorded(REPLICA_EVENT_SUBJECT).len();
+
+        for state in &[
+            ReplicaState::Offline,
+            ReplicaState::Online,
+            ReplicaState::Offline,
+            ReplicaState::Online,
+            ReplicaState::Offline,
+        ] {
+            emit_replica_state(uri, *state).unwrap();
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+        let flapping = server.recorded(REPLICA_EVENT_SUBJECT).len();
+        message_bus_stop();
+        (stable, flapping - stable)
+    })
+    .1;
+
+    assert_eq!(stable, 1, "one event for a stable transition");
+    assert_eq!(flapping, 1, "flapping not debounced");
+    let events: Vec<ReplicaEvent> = server
+        .recorded(REPLICA_EVENT_SUBJECT)
+        .iter()
+        .map(|data| serde_json::from_slice(data).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            ReplicaEvent {
+                uri: uri.to_owned(),
+                state: ReplicaState::Online,
+            },
+            ReplicaEvent {
+                uri: uri.to_owned(),
+                state: ReplicaState::Offline,
+            },
+        ]
+    );
+}
+
+#[test]
+fn log_shipping() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_log_shipping();
+    let subject = format!("logs.{}", NODE);
+    let subscriber = Registry::default().with(LogShipper::default());
+
+    tracing::subscriber::with_default(subscriber, || {
+        run_message_bus(mbus, async {
+            while !message_bus_health().registered {
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+            }
+            tracing::info!(target: "mayastor::test", "not an issue");
+            tracing::warn!(target: "mayastor::test", "disk is slow");
+            // as if the message bus failed to ship the record
+            tracing::error!(target: "mayastor::nats::bus", "bus trouble");
+            tracing::warn!(target: "mayastor::nats::queue", "queue full");
+            for i in 0 .. 2 * LOG_SHIPPING_RATE {
+                tracing::error!(target: "mayastor::test", "error {}", i);
+            }
+            message_bus_stop();
+        })
+    });
+
+    let records: Vec<LogRecord> = server
+        .wait_for_messages(
+            &subject,
+            LOG_SHIPPING_RATE as usize,
+            Duration::from_secs(5),
+        )
+        .iter()
+        .map(|(_, data)| serde_json::from_slice(data).unwrap())
+        .collect();
+    // the rest of the errors in a row is over the rate limit
+    assert_eq!(records.len(), LOG_SHIPPING_RATE as usize);
+    assert_eq!(records[0].level, "WARN");
+    assert_eq!(records[0].target, "mayastor::test");
+    assert_eq!(records[0].message, "disk is slow");
+    assert!(records[0].timestamp_ms > 0);
+    for (i, record) in records[1 ..].iter().enumerate() {
+        assert_eq!(record.level, "ERROR");
+        assert_eq!(record.message, format!("error {}", i));
+    }
+    // nothing is shipped when the message bus is not running
+    tracing::subscriber::with_default(
+        Registry::default().with(LogShipper::default()),
+        || tracing::warn!(target: "mayastor::test", "after stop"),
+    );
+    assert_eq!(server.recorded(&subject).len(), records.len());
+}
+
+#[test]
+fn log_shipping_queue_full() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_command_queue(2, OverflowPolicy::DropOldest)
+        .with_log_shipping();
+    let publisher = message_bus_publisher();
+    let event = RebuildProgress {
+        nexus: "nexus0".to_owned(),
+        child: "child0".to_owned(),
+        progress: 50,
+    };
+    let subscriber = Registry::default().with(LogShipper::default());
+
+    // the loop gets to the queue only when the test yields, so the events
+    // overflow it and the warnings about the dropped ones are logged while
+    // shipping is on: it used to deadlock
+    tracing::subscriber::with_default(subscriber, || {
+        run_message_bus(mbus, async {
+            server.registered().await;
+            for _ in 0 .. 5 {
+                publisher.publish("events.test", &event).unwrap();
+            }
+            message_bus_stop();
+        })
+    });
+    assert!(server.recorded(&format!("logs.{}", NODE)).is_empty());
+}
+
+#[test]
+fn publishers_share_connection() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT);
+    let subject = "events.test";
+    let event = RebuildProgress {
+        nexus: "nexus0".to_owned(),
+        child: "child0".to_owned(),
+        progress: 50,
+    };
+
+    // two emitters, one of them passing a clone of its handle further
+    let first = message_bus_publisher();
+    let second = message_bus_publisher();
+    let cloned = first.clone();
+    assert!(std::ptr::eq(first.stats(), second.stats()));
+    assert!(std::ptr::eq(first.stats(), cloned.stats()));
+    let queued = first.stats().queued();
+    let published = first.stats().published();
+    let failed = first.stats().failed();
+
+    run_message_bus(mbus, async {
+        server.registered().await;
+        second.publish(subject, &event).unwrap();
+        cloned.publish(subject, &event).unwrap();
+        for _ in 0 .. 50 {
+            if first.stats().published() >= published + 2 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        message_bus_stop();
+    });
+
+    // events of both emitters went over the one connection and are counted
+    // in the stats seen by all handles
+    assert_eq!(
+        server
+            .wait_for_messages(subject, 2, Duration::from_secs(5))
+            .len(),
+        2
+    );
+    assert_eq!(first.stats().queued(), queued + 2);
+    assert_eq!(second.stats().published(), published + 2);
+    assert_eq!(first.stats().failed(), failed);
+}
+
+#[test]
+fn flush_events_every_n() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_event_flush(3, Duration::from_millis(500));
+    let publisher = message_bus_publisher();
+    let stats = publisher.stats();
+    let published = stats.published();
+    let flushes = stats.flushes();
+
+    let (by_count, by_interval) = run_message_bus(mbus, async {
+        server.registered().await;
+        for progress in 0 .. 7 {
+            let event = RebuildProgress {
+                nexus: "nexus0".to_owned(),
+                child: "child0".to_owned(),
+                progress,
+            };
+            publisher.publish("events.test", &event).unwrap();
+        }
+        for _ in 0 .. 50 {
+            if stats.published() >= published + 7 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        // two flushes for six events, the seventh waits for the interval
+        let by_count = stats.flushes() - flushes;
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+        let by_interval = stats.flushes() - flushes;
+        message_bus_stop();
+        (by_count, by_interval)
+    })
+    .1;
+
+    assert_eq!(by_count, 2);
+    assert_eq!(by_interval, 3);
+    assert_eq!(server.recorded("events.test").len(), 7);
+}
+
+#[test]
+fn replay_buffer_drops_oldest() {
+    let mut replay = ReplayBuffer::new(2);
+    assert_eq!(replay.push("a".to_owned(), vec![1]), None);
+    assert_eq!(replay.push("b".to_owned(), vec![2]), None);
+    assert_eq!(
+        replay.push("c".to_owned(), vec![3]),
+        Some(("a".to_owned(), vec![1]))
+    );
+    assert_eq!(replay.len(), 2);
+    let events: Vec<_> = replay.take().into_iter().collect();
+    assert_eq!(
+        events,
+        vec![("b".to_owned(), vec![2]), ("c".to_owned(), vec![3])]
+    );
+    assert!(replay.is_empty());
+    // nothing is kept without capacity
+    let mut replay = ReplayBuffer::new(0);
+    assert!(replay.push("a".to_owned(), vec![1]).is_some());
+    assert!(replay.is_empty());
+}
+
+#[test]
+fn replay_events_after_reconnect() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let port = server.port();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_event_replay(4);
+    let publisher = message_bus_publisher();
+    let buffered = publisher.stats().buffered();
+
+    let thread = spawn_message_bus(mbus);
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(5))
+        .is_empty());
+
+    drop(server);
+    assert!(common::mbus::wait_for(
+        || !message_bus_health().connected,
+        Duration::from_secs(5)
+    ));
+    // one more than fits in the buffer, the first one is dropped
+    for progress in 0 .. 5 {
+        let event = RebuildProgress {
+            nexus: "nexus0".to_owned(),
+            child: "child0".to_owned(),
+            progress,
+        };
+        publisher.publish("events.test", &event).unwrap();
+    }
+    assert!(common::mbus::wait_for(
+        || publisher.stats().buffered() >= buffered + 5,
+        Duration::from_secs(5)
+    ));
+
+    let server = common::mbus::MockNatsServer::start_on(port);
+    let events =
+        server.wait_for_messages("events.test", 4, Duration::from_secs(15));
+    message_bus_stop();
+    assert!(thread.join().unwrap().is_ok());
+
+    let progress: Vec<u64> = events
+        .iter()
+        .map(|(_, data)| {
+            serde_json::from_slice::<RebuildProgress>(data)
+                .unwrap()
+                .progress
+        })
+        .collect();
+    assert_eq!(progress, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn rate_limit_drop() {
+    let now = Instant::now();
+    let mut limiter = RateLimiter::new(2, RateLimitPolicy::Drop);
+    let admitted: Vec<Admission> = (0 .. 3)
+        .map(|i| limiter.admit("events.a", vec![i], now))
+        .collect();
+    assert_eq!(
+        admitted,
+        vec![
+            Admission::Publish(vec![0]),
+            Admission::Publish(vec![1]),
+            Admission::Dropped
+        ]
+    );
+    // the subjects are limited separately
+    assert_eq!(
+        limiter.admit("events.b", vec![0], now),
+        Admission::Publish(vec![0])
+    );
+    // a token per half a second
+    let later = now + Duration::from_millis(500);
+    assert_eq!(
+        limiter.admit("events.a", vec![3], later),
+        Admission::Publish(vec![3])
+    );
+    assert_eq!(
+        limiter.admit("events.a", vec![4], later),
+        Admission::Dropped
+    );
+    assert_eq!(limiter.next_due(), None);
+}
+
+#[test]
+fn rate_limit_delay() {
+    let now = Instant::now();
+    let mut limiter = RateLimiter::new(2, RateLimitPolicy::Delay);
+    for i in 0 .. 2 {
+        assert_eq!(
+            limiter.admit("events.a", vec![i], now),
+            Admission::Publish(vec![i])
+        );
+    }
+    assert_eq!(limiter.admit("events.a", vec![2], now), Admission::Delayed);
+    assert_eq!(limiter.admit("events.a", vec![3], now), Admission::Delayed);
+    // no more than a second worth of events waits
+    assert_eq!(limiter.admit("events.a", vec![4], now), Admission::Dropped);
+    assert_eq!(limiter.next_due(), Some(now + Duration::from_millis(500)));
+    assert!(limiter.take_due(now).is_empty());
+
+    let due = limiter.take_due(now + Duration::from_millis(500));
+    assert_eq!(due, vec![("events.a".to_owned(), vec![2])]);
+    // the delayed event goes before the new one
+    assert_eq!(
+        limiter.admit("events.a", vec![5], now + Duration::from_millis(600)),
+        Admission::Delayed
+    );
+    let due = limiter.take_due(now + Duration::from_secs(2));
+    assert_eq!(
+        due,
+        vec![
+            ("events.a".to_owned(), vec![3]),
+            ("events.a".to_owned(), vec![5])
+        ]
+    );
+    assert_eq!(limiter.next_due(), None);
+}
+
+#[test]
+fn rate_limit_published_events() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_rate_limit(5, RateLimitPolicy::Drop);
+    let publisher = message_bus_publisher();
+    let stats = publisher.stats();
+    let limited = stats.limited();
+
+    run_message_bus(mbus, async {
+        server.registered().await;
+        for progress in 0 .. 20 {
+            let event = RebuildProgress {
+                nexus: "nexus0".to_owned(),
+                child: "child0".to_owned(),
+                progress,
+            };
+            publisher.publish("events.test", &event).unwrap();
+        }
+        for _ in 0 .. 50 {
+            if stats.limited() >= limited + 15
+                && server.recorded("events.test").len() >= 5
+            {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        message_bus_stop();
+    });
+
+    assert_eq!(stats.limited(), limited + 15);
+    assert_eq!(server.recorded("events.test").len(), 5);
+    // the heartbeats are not limited
+    assert!(!server.recorded("register").is_empty());
+}
@@ -0,0 +1,328 @@
+//! Heartbeats of the message bus and the dedicated thread sending them.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use structopt::StructOpt;
+
+use mayastor::{
+    core::MayastorCliArgs,
+    nats::{
+        message_bus_stop,
+        HealthSummary,
+        HeartbeatMonitor,
+        MessageBus,
+        NexusHealth,
+        RegisterArgs,
+        MESSAGE_BUS_THREAD,
+    },
+};
+
+pub mod common;
+use common::mbus::{
+    in_process,
+    message_bus,
+    run_message_bus,
+    spawn_message_bus,
+    start_mayastor,
+    start_mayastor_with_args,
+    thread_cpus,
+    thread_nice,
+    GRPC_ENDPOINT,
+    NODE,
+};
+
+#[test]
+fn heartbeat_miss() {
+    let interval = Duration::from_millis(100);
+    let missed = Arc::new(Mutex::new(Vec::new()));
+    let mut monitor = HeartbeatMonitor::new(interval, 2.5).on_miss({
+        let missed = Arc::clone(&missed);
+        move |id| missed.lock().unwrap().push(id.to_owned())
+    });
+    let args = message_bus().register_args();
+    let start = Instant::now();
+    assert_eq!(monitor.next_deadline(), None);
+
+    // heartbeats arriving late by less than the grace are tolerated
+    let mut last = start;
+    for _ in 0 .. 3 {
+        monitor.register(&args, last);
+        last += interval * 2;
+        assert!(monitor.check(last).is_empty());
+    }
+    last -= interval * 2;
+    assert_eq!(monitor.next_deadline(), Some(last + interval * 5 / 2));
+
+    // withhold the heartbeats, the miss is reported once
+    let deadline = monitor.next_deadline().unwrap();
+    assert!(monitor
+        .check(deadline - Duration::from_millis(1))
+        .is_empty());
+    assert_eq!(monitor.check(deadline), vec![args.id.clone()]);
+    assert!(monitor.is_missed(&args.id));
+    assert!(monitor.check(deadline + interval * 10).is_empty());
+    assert_eq!(monitor.next_deadline(), None);
+    assert_eq!(*missed.lock().unwrap(), vec![args.id.clone()]);
+
+    // the node is back with the next heartbeat and gone after deregister
+    monitor.register(&args, deadline + interval * 10);
+    assert!(!monitor.is_missed(&args.id));
+    assert!(monitor.next_deadline().is_some());
+    monitor.deregister(&args.id);
+    assert_eq!(monitor.next_deadline(), None);
+    assert!(monitor.check(deadline + interval * 100).is_empty());
+    assert_eq!(missed.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn heartbeat_on_change_with_keepalive() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let health = Arc::new(Mutex::new(HealthSummary::default()));
+    let gatherer = {
+        let health = Arc::clone(&health);
+        Box::new(move || Ok::<_, String>(health.lock().unwrap().clone()))
+    };
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_hb_interval(Duration::from_millis(200))
+        .with_keepalive(Duration::from_secs(2))
+        .with_health(gatherer);
+
+    // number of register messages after each phase of the test
+    let counts = run_message_bus(mbus, async {
+        let count = || server.recorded("register").len();
+        while count() == 0 {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        // nothing changes for several heartbeat intervals
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+        let unchanged = count();
+        // the change goes out with the next heartbeat
+        health.lock().unwrap().nexus.push(NexusHealth {
+            name: "nexus0".to_owned(),
+            status: "degraded".to_owned(),
+            degraded_children: 1,
+        });
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        let changed = count();
+        // and nothing more until the keepalive
+        tokio::time::delay_for(Duration::from_millis(2500)).await;
+        let keepalive = count();
+        message_bus_stop();
+        (unchanged, changed, keepalive)
+    })
+    .1;
+    assert_eq!(counts, (1, 2, 3));
+
+    let registers = server.recorded_at("register");
+    let args: Vec<RegisterArgs> = registers
+        .iter()
+        .map(|(_, data)| serde_json::from_slice(data).unwrap())
+        .collect();
+    assert_eq!(args[0].health, Some(HealthSummary::default()));
+    assert_eq!(args[1].health.as_ref().unwrap().nexus.len(), 1);
+    assert_eq!(args[2].health, args[1].health);
+    assert_eq!(
+        args.iter().map(|a| a.seq).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+    assert!(registers[2].0 - registers[1].0 >= Duration::from_secs(2));
+}
+
+#[test]
+fn heartbeat_count_in_window() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let interval = Duration::from_millis(100);
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_hb_interval(interval);
+    let thread = spawn_message_bus(mbus);
+
+    let registers = server.wait_for_registers(11, Duration::from_secs(5));
+    message_bus_stop();
+    assert!(thread.join().unwrap().is_ok());
+
+    // exactly ten intervals between the first and the eleventh heartbeat
+    assert!(registers.len() >= 11, "got {} registers", registers.len());
+    let window = registers[10].0 - registers[0].0;
+    assert!(
+        window >= interval * 10 - Duration::from_millis(50)
+            && window < interval * 15,
+        "10 heartbeats took {:?}",
+        window
+    );
+    assert_eq!(
+        registers[.. 11]
+            .iter()
+            .map(|(_, a)| a.seq)
+            .collect::<Vec<_>>(),
+        (1 ..= 11).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn pause_and_resume_heartbeats() {
+    let server = common::mbus::MockNatsServer::start();
+    let ms = start_mayastor(&server.endpoint(), 2);
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+
+    ms.rpc_call("mayastor_mbus_pause", serde_json::json!(null))
+        .unwrap();
+    // let the heartbeat which might be in flight arrive
+    std::thread::sleep(Duration::from_secs(1));
+    let paused = server.recorded("register").len();
+    let health = ms
+        .rpc_call("mayastor_mbus_health", serde_json::json!(null))
+        .unwrap();
+    assert_eq!(health["paused"], true);
+    assert_eq!(health["registered"], true);
+    std::thread::sleep(Duration::from_secs(5));
+    assert_eq!(server.recorded("register").len(), paused);
+    assert!(server.recorded("deregister").is_empty());
+
+    ms.rpc_call("mayastor_mbus_resume", serde_json::json!(null))
+        .unwrap();
+    // sooner than the heartbeat interval
+    assert!(common::mbus::wait_for(
+        || server.recorded("register").len() > paused,
+        Duration::from_secs(1)
+    ));
+    let health = ms
+        .rpc_call("mayastor_mbus_health", serde_json::json!(null))
+        .unwrap();
+    assert_eq!(health["paused"], false);
+}
+
+#[test]
+fn heartbeats_recorded_by_mock_server() {
+    let server = common::mbus::MockNatsServer::start();
+
+    let _ms = start_mayastor(&server.endpoint(), 1);
+
+    std::thread::sleep(Duration::from_secs(2));
+    let first = server.recorded("register").len();
+    std::thread::sleep(Duration::from_secs(3));
+    let registers = server.recorded("register");
+    assert!(
+        registers.len() > first,
+        "heartbeats did not accumulate ({} -> {})",
+        first,
+        registers.len()
+    );
+
+    let args: RegisterArgs = serde_json::from_slice(&registers[0]).unwrap();
+    assert_eq!(args.id, NODE);
+}
+
+#[test]
+fn heartbeat_jitter_under_load() {
+    let server = common::mbus::MockNatsServer::start();
+    let _ms = start_mayastor_with_args(
+        &server.endpoint(),
+        1,
+        &["--mbus-dedicated-thread"],
+    );
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+
+    // keep all cpus busy while the heartbeats are being recorded
+    let stop = Arc::new(AtomicBool::new(false));
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    let burners = (0 .. cpus.max(1) * 2)
+        .map(|_| {
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    std::sync::atomic::spin_loop_hint();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    std::thread::sleep(Duration::from_secs(6));
+    stop.store(true, Ordering::Relaxed);
+    burners.into_iter().for_each(|t| t.join().unwrap());
+
+    let arrivals = server
+        .recorded_at("register")
+        .into_iter()
+        .map(|(at, _)| at)
+        .filter(|at| *at >= start)
+        .collect::<Vec<_>>();
+    assert!(
+        arrivals.len() >= 4,
+        "{} heartbeats under load",
+        arrivals.len()
+    );
+    for pair in arrivals.windows(2) {
+        let gap = pair[1] - pair[0];
+        assert!(
+            gap > Duration::from_millis(500)
+                && gap < Duration::from_millis(1500),
+            "heartbeat jitter out of bounds: {:?}",
+            gap
+        );
+    }
+
+    let args: RegisterArgs =
+        serde_json::from_slice(server.recorded("register").last().unwrap())
+            .unwrap();
+    // the core publishes the health summary for the dedicated thread
+    assert!(args.health.is_some());
+}
+
+#[test]
+fn message_bus_on_core() {
+    let server = common::mbus::MockNatsServer::start();
+    let ms = start_mayastor_with_args(
+        &server.endpoint(),
+        1,
+        &["--mbus-dedicated-thread", "--mbus-core", "0"],
+    );
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+    assert_eq!(
+        thread_cpus(ms.pid(), MESSAGE_BUS_THREAD).as_deref(),
+        Some("0")
+    );
+}
+
+#[test]
+fn message_bus_core_out_of_range() {
+    let args = |core| {
+        MayastorCliArgs::from_iter_safe(&[
+            "mayastor",
+            "--mbus-dedicated-thread",
+            "--mbus-core",
+            core,
+        ])
+    };
+    assert_eq!(args("0").unwrap().mbus.core, Some(0));
+    assert!(args("100000").is_err());
+}
+
+#[test]
+fn message_bus_thread_nice() {
+    let server = common::mbus::MockNatsServer::start();
+    // lowering the priority does not need any capability
+    let ms = start_mayastor_with_args(
+        &server.endpoint(),
+        1,
+        &["--mbus-dedicated-thread", "--mbus-thread-nice", "5"],
+    );
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+    assert_eq!(thread_nice(ms.pid(), MESSAGE_BUS_THREAD), Some(5));
+}
@@ -0,0 +1,300 @@
+//! Messaging API of the message bus: requests with replies, subscriptions,
+//! configuration pushed by the control plane and signed messages.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use futures::StreamExt;
+
+use mayastor::nats::{
+    config_subject,
+    message_bus_stop,
+    ConfigAck,
+    Error,
+    MessageBus,
+    RegisterArgs,
+    Signer,
+    SIGNATURE_LEN,
+};
+
+pub mod common;
+use common::mbus::{
+    in_process,
+    message_bus,
+    run_message_bus,
+    runtime,
+    start_mayastor_with_args,
+    GRPC_ENDPOINT,
+    NODE,
+};
+
+#[test]
+fn config_push() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    server.deliver();
+    let pushed = Arc::new(Mutex::new(Vec::new()));
+    let handler = {
+        let pushed = Arc::clone(&pushed);
+        Box::new(move |data: &[u8]| {
+            pushed.lock().unwrap().push(data.to_vec());
+            if data == b"bad" {
+                Err("invalid pool definition".to_owned())
+            } else {
+                Ok(())
+            }
+        })
+    };
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_config_handler(handler);
+
+    let acks = run_message_bus(mbus, async {
+        tokio::task::spawn_blocking({
+            let url = server.url();
+            move || {
+                let nc = nats::connect(&url).unwrap();
+                let subject = config_subject(NODE);
+                // the node might not have subscribed yet
+                let good = (0 .. 50)
+                    .find_map(|_| {
+                        nc.request_timeout(
+                            &subject,
+                            b"pool: p0",
+                            Duration::from_millis(100),
+                        )
+                        .ok()
+                    })
+                    .expect("config update not acknowledged");
+                let bad = nc
+                    .request_timeout(&subject, b"bad", Duration::from_secs(5))
+                    .unwrap();
+                message_bus_stop();
+                vec![good.data, bad.data]
+            }
+        })
+        .await
+        .unwrap()
+    })
+    .1;
+
+    let acks: Vec<ConfigAck> = acks
+        .iter()
+        .map(|ack| serde_json::from_slice(ack).unwrap())
+        .collect();
+    assert_eq!(
+        acks,
+        vec![
+            ConfigAck {
+                ok: true,
+                error: None,
+            },
+            ConfigAck {
+                ok: false,
+                error: Some("invalid pool definition".to_owned()),
+            },
+        ]
+    );
+    assert_eq!(
+        pushed.lock().unwrap().last().map(|d| d.as_slice()),
+        Some(&b"bad"[..])
+    );
+    assert!(pushed.lock().unwrap().contains(&b"pool: p0".to_vec()));
+}
+
+#[test]
+fn publish_with_reply() {
+    // the connection is announced to the resilient subscriptions
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    // the mock server replies on behalf of the control plane
+    server.ack("volume.event");
+    let mut mbus = message_bus();
+
+    let mut rt = runtime();
+    let reply = rt.block_on(async {
+        mbus.reconnect_to(&server.endpoint()).await.unwrap();
+        let sub = mbus.subscribe("volume.event.ack").await.unwrap();
+        mbus.publish_with_reply("volume.event", "volume.event.ack", b"{}")
+            .await
+            .unwrap();
+        mbus.flush().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), sub.next()).await
+    });
+    assert!(matches!(reply, Ok(Some(_))), "no reply received");
+    assert_eq!(server.recorded("volume.event"), vec![b"{}".to_vec()]);
+}
+
+#[test]
+fn subscribe_typed() {
+    // the connection is announced to the resilient subscriptions
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    server.deliver();
+    let mut mbus = message_bus();
+    let args = mbus.register_args();
+
+    let mut rt = runtime();
+    let received: Vec<Result<RegisterArgs, Error>> = rt.block_on(async {
+        mbus.reconnect_to(&server.endpoint()).await.unwrap();
+        let typed = Box::pin(
+            mbus.subscribe_typed::<RegisterArgs>("typed.register")
+                .await
+                .unwrap(),
+        );
+        let valid = serde_json::to_vec(&args).unwrap();
+        for payload in &[&valid[..], b"{\"id\": 1}", &valid[..]] {
+            mbus.publish("typed.register", payload).await.unwrap();
+        }
+        mbus.flush().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), typed.take(3).collect())
+            .await
+            .expect("messages not received")
+    });
+
+    assert_eq!(received.len(), 3);
+    assert_eq!(received[0].as_ref().unwrap(), &args);
+    assert!(matches!(received[1], Err(Error::Decode { .. })));
+    assert_eq!(received[2].as_ref().unwrap(), &args);
+}
+
+#[test]
+fn no_echo() {
+    // the connection is announced to the resilient subscriptions
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    server.deliver();
+    let other = nats::connect(&server.endpoint()).unwrap();
+
+    let mut rt = runtime();
+    let received: Vec<Vec<u8>> = rt.block_on(async {
+        let mut mbus = message_bus().with_no_echo();
+        mbus.reconnect_to(&server.endpoint()).await.unwrap();
+        let sub = mbus.subscribe("echo.test").await.unwrap();
+        mbus.publish("echo.test", b"self").await.unwrap();
+        mbus.flush().await.unwrap();
+        // messages of other clients are still delivered
+        other.publish("echo.test", b"other").unwrap();
+        other.flush().unwrap();
+        let mut received = Vec::new();
+        while let Ok(Some(msg)) =
+            tokio::time::timeout(Duration::from_millis(500), sub.next()).await
+        {
+            received.push(msg.data);
+        }
+        received
+    });
+    assert_eq!(received, vec![b"other".to_vec()]);
+}
+
+#[test]
+fn resilient_subscription() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    server.deliver();
+    let port = server.port();
+    let mut mbus = message_bus();
+
+    let mut rt = runtime();
+    let mut sub = rt.block_on(async {
+        mbus.reconnect_to(&server.endpoint()).await.unwrap();
+        mbus.subscribe_resilient("test.command").await.unwrap()
+    });
+    assert_eq!(sub.subject(), "test.command");
+
+    // bounce the server, the nats library reconnects to the new one
+    drop(server);
+    let server = common::mbus::MockNatsServer::start_on(port);
+    server.deliver();
+
+    // keep sending the command until the subscription is back
+    let stop = Arc::new(AtomicBool::new(false));
+    let sender = {
+        let stop = Arc::clone(&stop);
+        let url = server.url();
+        std::thread::spawn(move || {
+            let nc = nats::connect(&url).unwrap();
+            while !stop.load(Ordering::Relaxed) {
+                nc.publish("test.command", b"resume").unwrap();
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        })
+    };
+    let received = rt.block_on(async {
+        tokio::time::timeout(Duration::from_secs(10), sub.next()).await
+    });
+    stop.store(true, Ordering::Relaxed);
+    sender.join().unwrap();
+
+    let msg = received.expect("command not received after server restart");
+    assert_eq!(msg.data, b"resume".to_vec());
+}
+
+#[test]
+fn signed_round_trip() {
+    let signer = Signer::new(b"shared secret");
+    let signed = signer.sign(b"{\"id\":\"node\"}");
+    assert_eq!(signed.len(), 13 + SIGNATURE_LEN);
+    assert_eq!(signer.verify(&signed).unwrap(), b"{\"id\":\"node\"}");
+
+    // tampered payload
+    let mut tampered = signed.clone();
+    tampered[7] = b'X';
+    assert!(matches!(signer.verify(&tampered), Err(Error::Signature {})));
+    // signed by someone else
+    let other = Signer::new(b"other secret").sign(b"{\"id\":\"node\"}");
+    assert!(matches!(signer.verify(&other), Err(Error::Signature {})));
+    // too short to carry a signature
+    assert!(matches!(signer.verify(b"{}"), Err(Error::Signature {})));
+}
+
+#[test]
+fn signed_register() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT)
+        .with_hmac_key(b"shared secret");
+
+    run_message_bus(mbus, async {
+        server.registered().await;
+        message_bus_stop();
+    });
+
+    let signer = Signer::new(b"shared secret");
+    let register = &server.recorded("register")[0];
+    let args: RegisterArgs =
+        serde_json::from_slice(signer.verify(register).unwrap()).unwrap();
+    assert_eq!(args.id, NODE);
+    // the deregister message sent on stop is signed too
+    let deregister = &server.recorded("deregister")[0];
+    assert!(signer.verify(deregister).is_ok());
+}
+
+#[test]
+fn signed_register_with_key_file() {
+    // the key does not show on the command line of the process
+    let path =
+        std::env::temp_dir().join(format!("mbus-key-{}", std::process::id()));
+    std::fs::write(&path, "shared secret\n").unwrap();
+    let server = common::mbus::MockNatsServer::start();
+    let _ms = start_mayastor_with_args(
+        &server.endpoint(),
+        1,
+        &["--mbus-hmac-key-file", path.to_str().unwrap()],
+    );
+    let registers =
+        server.wait_for_messages("register", 1, Duration::from_secs(10));
+    std::fs::remove_file(&path).unwrap();
+
+    // without the trailing newline
+    let signer = Signer::new(b"shared secret");
+    let args: RegisterArgs =
+        serde_json::from_slice(signer.verify(&registers[0].1).unwrap())
+            .unwrap();
+    assert_eq!(args.id, NODE);
+}
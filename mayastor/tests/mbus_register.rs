@@ -0,0 +1,631 @@
+//! Registration of mayastor with the control plane over the message bus:
+//! the register and deregister messages and their payload.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use mayastor::{
+    nats::{
+        message_bus_health,
+        message_bus_set_status,
+        message_bus_stop,
+        parse_hb_interval,
+        parse_register_delay,
+        register_shard,
+        Error,
+        HealthSummary,
+        MessageBus,
+        NexusHealth,
+        NodeStatus,
+        PayloadFormat,
+        RegisterArgs,
+        SCHEMA_VERSION,
+    },
+    subsys::{self, Config},
+};
+
+pub mod common;
+use common::mbus::{
+    in_process,
+    message_bus,
+    run_message_bus,
+    start_mayastor,
+    start_mayastor_with_args,
+    GRPC_ENDPOINT,
+    NODE,
+};
+
+#[test]
+fn registered_identity() {
+    let mbus = message_bus();
+    assert_eq!(mbus.node_id(), NODE);
+    assert_eq!(mbus.grpc_endpoint(), GRPC_ENDPOINT);
+}
+
+#[test]
+fn connection_name_of_process() {
+    let mbus = message_bus();
+    let prefix = format!("mayastor-{}-{}-", NODE, std::process::id());
+    let name = mbus.connection_name();
+    assert!(name.starts_with(&prefix), "{}", name);
+    let token = &name[prefix.len() ..];
+    assert_eq!(token.len(), 8);
+    assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+    // the token tells apart the connections of the same process too
+    assert_ne!(message_bus().connection_name(), name);
+}
+
+#[test]
+fn hb_interval_from_env() {
+    assert_eq!(parse_hb_interval("5"), Ok(Duration::from_secs(5)));
+    assert_eq!(parse_hb_interval(" 5\n"), Ok(Duration::from_secs(5)));
+
+    let err = parse_hb_interval("ten").unwrap_err();
+    assert!(err.contains("'ten'"), "{}", err);
+    let err = parse_hb_interval("").unwrap_err();
+    assert!(err.contains("''"), "{}", err);
+    let err = parse_hb_interval("0").unwrap_err();
+    assert!(err.contains("zero"), "{}", err);
+
+    // invalid values fall back to the default interval
+    for val in &["ten", "", "0"] {
+        std::env::set_var("MAYASTOR_HB_INTERVAL", val);
+        let interval = message_bus().hb_interval();
+        std::env::remove_var("MAYASTOR_HB_INTERVAL");
+        assert_eq!(interval, Duration::from_secs(10), "for {:?}", val);
+    }
+}
+
+#[test]
+fn register_delay_from_env() {
+    assert_eq!(parse_register_delay("0"), Ok(Duration::from_secs(0)));
+    assert_eq!(parse_register_delay(" 3\n"), Ok(Duration::from_secs(3)));
+    let err = parse_register_delay("3s").unwrap_err();
+    assert!(err.contains("'3s'"), "{}", err);
+
+    // invalid value is ignored, the same as no delay
+    std::env::set_var("MAYASTOR_REGISTER_DELAY", "3s");
+    let delay = message_bus().register_delay();
+    std::env::remove_var("MAYASTOR_REGISTER_DELAY");
+    assert_eq!(delay, None);
+}
+
+#[test]
+fn register_args_with_health() {
+    let mbus = message_bus().with_health(Box::new(|| {
+        Ok(HealthSummary {
+            nexus: vec![NexusHealth {
+                name: "nexus0".into(),
+                status: "degraded".into(),
+                degraded_children: 1,
+            }],
+        })
+    }));
+
+    let args = mbus.register_args();
+    assert_eq!(args.id, NODE);
+    assert_eq!(args.grpc_endpoint, GRPC_ENDPOINT);
+    let health = args.health.expect("health summary missing");
+    assert_eq!(health.nexus.len(), 1);
+    assert_eq!(health.nexus[0].degraded_children, 1);
+
+    let json = serde_json::to_value(&mbus.register_args()).unwrap();
+    assert_eq!(json["health"]["nexus"][0]["degradedChildren"], 1);
+}
+
+#[test]
+fn register_args_health_error() {
+    let mbus =
+        message_bus().with_health(Box::new(|| Err("no nexus list".into())));
+
+    // the heartbeat must still go out, just without the summary
+    let args = mbus.register_args();
+    assert_eq!(args.id, NODE);
+    assert!(args.health.is_none());
+
+    let json = serde_json::to_value(&args).unwrap();
+    assert!(json.get("health").is_none());
+}
+
+#[test]
+fn register_args_health_throttle() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let gatherer = |calls: &Arc<AtomicUsize>| {
+        let calls = Arc::clone(calls);
+        Box::new(move || {
+            let n = calls.fetch_add(1, Ordering::SeqCst) as u32 + 1;
+            Ok::<_, String>(HealthSummary {
+                nexus: vec![NexusHealth {
+                    name: "nexus0".into(),
+                    status: "online".into(),
+                    degraded_children: n,
+                }],
+            })
+        })
+    };
+    let degraded =
+        |args: RegisterArgs| args.health.unwrap().nexus[0].degraded_children;
+
+    // without throttle each heartbeat gathers a fresh summary
+    let mbus = message_bus().with_health(gatherer(&calls));
+    for i in 1 ..= 3 {
+        assert_eq!(degraded(mbus.register_args()), i);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // within the throttle period the last summary is reused
+    calls.store(0, Ordering::SeqCst);
+    let throttle = Duration::from_millis(300);
+    let mbus = message_bus()
+        .with_health(gatherer(&calls))
+        .with_health_throttle(throttle);
+    for _ in 0 .. 3 {
+        assert_eq!(degraded(mbus.register_args()), 1);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    std::thread::sleep(throttle);
+    assert_eq!(degraded(mbus.register_args()), 2);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn register_args_status() {
+    let _guard = in_process();
+    let mbus = message_bus();
+
+    // the node is starting until the core says otherwise
+    assert_eq!(mbus.register_args().status, NodeStatus::Starting);
+
+    for status in &[NodeStatus::Ready, NodeStatus::Degraded, NodeStatus::Ready]
+    {
+        message_bus_set_status(*status);
+        let args = mbus.register_args();
+        assert_eq!(args.status, *status);
+        let json = serde_json::to_value(&args).unwrap();
+        assert_eq!(json["status"], format!("{:?}", status));
+    }
+}
+
+#[test]
+fn status_after_import() {
+    let server = common::mbus::MockNatsServer::start();
+    let _ms = start_mayastor(&server.endpoint(), 1);
+    let registers = server.wait_for_registers(1, Duration::from_secs(10));
+    assert_eq!(registers[0].1.status, NodeStatus::Ready);
+
+    // the disk of the pool does not exist
+    let server = common::mbus::MockNatsServer::start();
+    let mut cfg = Config::default();
+    cfg.pools = Some(vec![subsys::Pool {
+        name: "mbus_pool".to_string(),
+        disks: vec!["/tmp/mbus_nonexistent.img".into()],
+        blk_size: 512,
+        io_if: 1,
+        replicas: Default::default(),
+    }]);
+    cfg.nexus_opts.nvmf_enable = false;
+    cfg.write("/tmp/mbus_pool.yaml").unwrap();
+    let _ms = start_mayastor_with_args(
+        &server.endpoint(),
+        1,
+        &["-y", "/tmp/mbus_pool.yaml"],
+    );
+    let registers = server.wait_for_registers(1, Duration::from_secs(10));
+    assert_eq!(registers[0].1.status, NodeStatus::Degraded);
+}
+
+#[test]
+fn payload_format_round_trip() {
+    let args = message_bus().register_args();
+
+    for format in &[PayloadFormat::Json, PayloadFormat::MsgPack] {
+        let data = format.encode(&args).unwrap();
+        let decoded: RegisterArgs = format.decode(&data).unwrap();
+        assert_eq!(decoded, args);
+    }
+
+    assert_eq!(PayloadFormat::Json.subject("register"), "register");
+    assert_eq!(
+        PayloadFormat::MsgPack.subject("register"),
+        "register.msgpack"
+    );
+    assert!(matches!(
+        PayloadFormat::MsgPack.decode::<RegisterArgs>(b"{}"),
+        Err(Error::Decode { .. })
+    ));
+    assert!("yaml".parse::<PayloadFormat>().is_err());
+}
+
+#[test]
+fn compact_payload_round_trip() {
+    let mut mbus = message_bus();
+    let args = mbus.next_register_args();
+    let format: PayloadFormat = "compact".parse().unwrap();
+
+    let data = format.encode(&args).unwrap();
+    assert_eq!(
+        data.len(),
+        2 + 8 + 8 + 1 + NODE.len() + 1 + GRPC_ENDPOINT.len() + 1
+    );
+    assert!(data.len() < PayloadFormat::Json.encode(&args).unwrap().len());
+    let decoded = RegisterArgs::decode(format, &data).unwrap();
+    assert_eq!(decoded, args);
+    assert_eq!(format.subject("register"), "register.compact");
+
+    // truncated message
+    assert!(matches!(
+        format.decode::<RegisterArgs>(&data[.. data.len() - 1]),
+        Err(Error::Decode { .. })
+    ));
+    // the length prefix does not fit a long id
+    let long = RegisterArgs {
+        id: "x".repeat(256),
+        ..decoded
+    };
+    assert!(matches!(format.encode(&long), Err(Error::Encode { .. })));
+}
+
+#[test]
+fn register_schema_version() {
+    let args = message_bus().register_args();
+    assert_eq!(args.schema_version, SCHEMA_VERSION);
+    let json = serde_json::to_value(&args).unwrap();
+    assert_eq!(json["schemaVersion"], SCHEMA_VERSION);
+
+    // newer minor version with an extra field
+    let mut newer = json.clone();
+    newer["schemaVersion"] = (SCHEMA_VERSION + 1).into();
+    newer["zone"] = "eu-west".into();
+    let data = serde_json::to_vec(&newer).unwrap();
+    let decoded = RegisterArgs::decode(PayloadFormat::Json, &data).unwrap();
+    assert_eq!(decoded.id, NODE);
+
+    // newer major version
+    let mut newer = json;
+    newer["schemaVersion"] = (SCHEMA_VERSION + 100).into();
+    let data = serde_json::to_vec(&newer).unwrap();
+    assert!(matches!(
+        RegisterArgs::decode(PayloadFormat::Json, &data),
+        Err(Error::SchemaVersion { .. })
+    ));
+}
+
+#[test]
+fn start_epoch() {
+    let server = common::mbus::MockNatsServer::start();
+    let mut epochs = Vec::new();
+    for _ in 0 .. 2 {
+        let ms = start_mayastor(&server.endpoint(), 1);
+        let count = server.recorded("register").len();
+        let registers =
+            server.wait_for_registers(count + 2, Duration::from_secs(10));
+        drop(ms);
+        assert!(registers.len() >= count + 2);
+        let instance: Vec<u64> = registers[count ..]
+            .iter()
+            .map(|(_, args)| args.start_epoch)
+            .collect();
+        // constant for the life of the instance
+        assert!(instance[0] > 0);
+        assert!(instance.iter().all(|e| *e == instance[0]));
+        epochs.push(instance[0]);
+    }
+    // and newer after restart
+    assert!(epochs[1] > epochs[0]);
+}
+
+#[test]
+fn register_sequence() {
+    let mut mbus = message_bus();
+
+    assert_eq!(mbus.register_args().seq, 0);
+    for seq in 1 ..= 3 {
+        assert_eq!(mbus.next_register_args().seq, seq);
+    }
+    // building the payload does not consume a sequence number
+    assert_eq!(mbus.register_args().seq, 3);
+
+    mbus.reset_sequence();
+    assert_eq!(mbus.next_register_args().seq, 1);
+    let json = serde_json::to_value(&mbus.register_args()).unwrap();
+    assert_eq!(json["seq"], 1);
+}
+
+#[test]
+fn register_on_startup() {
+    let args = vec!["-g", GRPC_ENDPOINT, "-N", NODE]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+    let args = common::mbus::first_register(args, Duration::from_secs(10))
+        .expect("mayastor did not register");
+    assert_eq!(args.id, NODE);
+    assert_eq!(args.grpc_endpoint, GRPC_ENDPOINT);
+}
+
+#[test]
+fn deregister_on_sigterm() {
+    let server = common::mbus::MockNatsServer::start();
+    let mut ms = start_mayastor(&server.endpoint(), 1);
+    assert!(
+        !server
+            .wait_for_registers(1, Duration::from_secs(10))
+            .is_empty(),
+        "mayastor did not register"
+    );
+
+    // returns after mayastor has exited
+    ms.sig_term();
+    let deregister = server.recorded("deregister");
+    assert_eq!(deregister.len(), 1, "deregister was not sent before exit");
+    let args: serde_json::Value =
+        serde_json::from_slice(&deregister[0]).unwrap();
+    assert_eq!(args["id"], NODE);
+}
+
+#[test]
+fn deregister_on_stop() {
+    let _guard = in_process();
+    let server = common::mbus::MockNatsServer::start();
+    let mbus = MessageBus::new(&server.endpoint(), NODE, GRPC_ENDPOINT);
+
+    // resolves only after the run loop has exited
+    let res = run_message_bus(mbus, async {
+        server.registered().await;
+        message_bus_stop();
+    })
+    .0;
+    assert!(res.is_ok());
+
+    let deregisters = server.recorded_at("deregister");
+    assert_eq!(deregisters.len(), 1);
+    let (deregistered_at, payload) = &deregisters[0];
+    let args: serde_json::Value = serde_json::from_slice(payload).unwrap();
+    assert_eq!(args["id"], NODE);
+    let health = message_bus_health();
+    assert!(!health.registered);
+    assert!(!health.connected);
+
+    // nothing is sent after the deregister message
+    std::thread::sleep(Duration::from_secs(1));
+    let registers = server.recorded_at("register");
+    assert!(registers.iter().all(|(at, _)| at < deregistered_at));
+}
+
+#[test]
+fn register_shards() {
+    let _guard = in_process();
+    // the shard is CRC-32 of the node name modulo the count
+    let nodes = ["node-4", "node-0", "node-5", "node-1"];
+    for (shard, node) in nodes.iter().enumerate() {
+        assert_eq!(register_shard(node, 4), shard as u32);
+    }
+    assert_eq!(register_shard("node-0", 0), 0);
+
+    let server = common::mbus::MockNatsServer::start();
+    for (shard, node) in nodes.iter().enumerate() {
+        let mbus = MessageBus::new(&server.endpoint(), node, GRPC_ENDPOINT)
+            .with_register_shards(4);
+        assert_eq!(
+            mbus.config().register_subject,
+            format!("register.{}", shard)
+        );
+        run_message_bus(mbus, async {
+            while !message_bus_health().registered {
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+            }
+            message_bus_stop();
+        });
+    }
+
+    // each node registered and deregistered only in its own shard
+    assert!(server.recorded("register").is_empty());
+    for (shard, node) in nodes.iter().enumerate() {
+        let ids: Vec<String> = server
+            .recorded(&format!("register.{}", shard))
+            .iter()
+            .map(|data| {
+                serde_json::from_slice::<RegisterArgs>(data).unwrap().id
+            })
+            .collect();
+        assert!(!ids.is_empty());
+        assert!(
+            ids.iter().all(|id| id == node),
+            "{:?} in shard {}",
+            ids,
+            shard
+        );
+        assert_eq!(server.recorded(&format!("deregister.{}", shard)).len(), 1);
+    }
+}
+
+#[test]
+fn deregister_before_exit() {
+    for extra in &[&[][..], &["--mbus-dedicated-thread"][..]] {
+        let server = common::mbus::MockNatsServer::start();
+        let mut ms = start_mayastor_with_args(&server.endpoint(), 60, extra);
+        assert!(!server
+            .wait_for_registers(1, Duration::from_secs(10))
+            .is_empty());
+
+        // returns after mayastor has exited, the deregister message must
+        // have reached the server by then without waiting
+        ms.sig_term();
+        let deregisters = server.recorded("deregister");
+        assert_eq!(deregisters.len(), 1, "no deregister with {:?}", extra);
+    }
+}
+
+#[test]
+fn register_once_acked() {
+    let server = common::mbus::MockNatsServer::start();
+    server.ack("register");
+
+    let status = common::mbus::run_mayastor(&[
+        "--mbus-once",
+        "-g",
+        GRPC_ENDPOINT,
+        "-N",
+        NODE,
+        "-n",
+        &server.endpoint(),
+    ]);
+    assert!(status.success());
+    assert_eq!(server.recorded("register").len(), 1);
+}
+
+#[test]
+fn registration_logged_as_json() {
+    let server = common::mbus::MockNatsServer::start();
+    server.ack("register");
+
+    let output = common::mbus::run_mayastor_output(&[
+        "--log-json",
+        "--mbus-once",
+        "-g",
+        GRPC_ENDPOINT,
+        "-N",
+        NODE,
+        "-n",
+        &server.endpoint(),
+    ]);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .map(|line| line["fields"].clone())
+        .find(|fields| fields["event"] == "register")
+        .expect("no json line with register event");
+    assert_eq!(fields["node"], NODE);
+    assert_eq!(fields["grpc_endpoint"], GRPC_ENDPOINT);
+    assert!(fields["message"].is_string());
+}
+
+#[test]
+fn registration_without_nats_warned() {
+    // dry run exits right away, without NATS endpoint with an error
+    let output = common::mbus::run_mayastor_output(&[
+        "--mbus-dry-run",
+        "--mbus-log-register",
+        "-g",
+        GRPC_ENDPOINT,
+        "-N",
+        NODE,
+    ]);
+    assert!(!output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("the node will not register with the control plane"),
+        "no warning in: {}",
+        stdout
+    );
+    let payload = stdout
+        .lines()
+        .find(|line| line.contains("Register message which would be sent"))
+        .and_then(|line| line.find('{').map(|start| &line[start ..]))
+        .expect("no register message logged");
+    let args: RegisterArgs = serde_json::from_str(payload.trim()).unwrap();
+    assert_eq!(args.id, NODE);
+    assert_eq!(args.grpc_endpoint, GRPC_ENDPOINT);
+}
+
+#[test]
+fn register_once_not_acked() {
+    let server = common::mbus::MockNatsServer::start();
+
+    let status = common::mbus::run_mayastor(&[
+        "--mbus-once",
+        "-g",
+        GRPC_ENDPOINT,
+        "-N",
+        NODE,
+        "-n",
+        &server.endpoint(),
+    ]);
+    assert!(!status.success());
+    assert_eq!(server.recorded("register").len(), 1);
+}
+
+#[test]
+fn dry_run() {
+    let server = common::mbus::MockNatsServer::start();
+
+    let status = common::mbus::run_mayastor(&[
+        "--mbus-dry-run",
+        "-g",
+        GRPC_ENDPOINT,
+        "-N",
+        NODE,
+        "-n",
+        &server.endpoint(),
+    ]);
+    // mayastor exits on its own, so no heartbeat loop
+    assert!(status.success());
+    assert_eq!(server.recorded("register.validate").len(), 1);
+    assert!(server.recorded("register").is_empty());
+
+    // nothing listens on this port
+    let status = common::mbus::run_mayastor(&[
+        "--mbus-dry-run",
+        "-g",
+        GRPC_ENDPOINT,
+        "-n",
+        "127.0.0.1:1",
+    ]);
+    assert!(!status.success());
+}
+
+#[test]
+fn force_register() {
+    let server = common::mbus::MockNatsServer::start();
+    // long enough for the heartbeat not to interfere with the test
+    let ms = start_mayastor(&server.endpoint(), 60);
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+    let before = server.recorded("register").len();
+
+    // returns after the register message has been flushed
+    ms.rpc_call("mayastor_mbus_force_register", serde_json::json!(null))
+        .unwrap();
+    let registers = server.recorded("register");
+    assert_eq!(registers.len(), before + 1);
+    let args: RegisterArgs =
+        serde_json::from_slice(registers.last().unwrap()).unwrap();
+    assert_eq!(args.seq, before as u64 + 1);
+}
+
+#[test]
+fn register_after_server_restart() {
+    let server = common::mbus::MockNatsServer::start();
+    let port = server.port();
+    // long enough for the heartbeat not to interfere with the test
+    let _ms = start_mayastor(&server.endpoint(), 60);
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+
+    drop(server);
+    let server = common::mbus::MockNatsServer::start_on(port);
+    assert!(
+        common::mbus::wait_for(
+            || !server.recorded("register").is_empty(),
+            Duration::from_secs(15)
+        ),
+        "node was not registered again after reconnect"
+    );
+    // the start epoch is the same, so the sequence goes on
+    let args: RegisterArgs =
+        serde_json::from_slice(server.recorded("register").last().unwrap())
+            .unwrap();
+    assert!(args.seq > 1, "sequence restarted at {}", args.seq);
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use mayastor::{
+    core::{mayastor_env_stop, MayastorCliArgs, MayastorEnvironment, Reactor},
+    nats::{
+        message_bus_stop,
+        MessageBus,
+        PoolAction,
+        PoolEvent,
+        POOL_EVENT_SUBJECT,
+    },
+    pool::{create_pool, Pool},
+};
+use rpc::mayastor::CreatePoolRequest;
+
+pub mod common;
+
+#[test]
+fn pool_events() {
+    // the message bus runs in the test process as it would in mayastor
+    let server = common::mbus::MockNatsServer::start();
+    let mbus =
+        MessageBus::new(&server.endpoint(), "pool-node", "127.0.0.1:10124");
+    let thread = common::mbus::spawn_message_bus(mbus);
+    assert!(!server
+        .wait_for_registers(1, Duration::from_secs(10))
+        .is_empty());
+
+    common::mayastor_test_init();
+    MayastorEnvironment::new(MayastorCliArgs::default())
+        .start(|| {
+            Reactor::block_on(async {
+                create_pool(CreatePoolRequest {
+                    name: "events".into(),
+                    disks: vec!["malloc:///malloc0?size_mb=64".to_string()],
+                    block_size: 0,
+                    io_if: 0,
+                })
+                .await
+                .unwrap();
+                Pool::lookup("events").unwrap().destroy().await.unwrap();
+            });
+            mayastor_env_stop(0);
+        })
+        .unwrap();
+
+    let events: Vec<PoolEvent> = server
+        .wait_for_messages(POOL_EVENT_SUBJECT, 2, Duration::from_secs(5))
+        .iter()
+        .map(|(_, data)| serde_json::from_slice(data).unwrap())
+        .collect();
+    message_bus_stop();
+    assert!(thread.join().unwrap().is_ok());
+
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].action, PoolAction::Created);
+    assert_eq!(events[1].action, PoolAction::Deleted);
+    for event in &events {
+        assert_eq!(event.name, "events");
+        assert_eq!(event.disk, "malloc://malloc0");
+        assert!(event.size > 0 && event.size <= 64 * 1024 * 1024);
+    }
+    assert_eq!(events[0].size, events[1].size);
+}
@@ -11,6 +11,7 @@ use mayastor::{
         MayastorEnvironment,
         Reactor,
     },
+    replica::Replica,
     subsys,
     subsys::Config,
 };
@@ -117,6 +118,23 @@ fn replica_snapshot() {
     common::delete_file(&[DISKNAME1.to_string()]);
 }
 
+#[test]
+fn snapshot_label() {
+    for label in &["a", "nightly-2020.07", "0123456789abcdef"] {
+        let dwords = Replica::encode_snapshot_label(label).unwrap();
+        assert_eq!(
+            Replica::decode_snapshot_label(dwords).as_deref(),
+            Some(*label)
+        );
+    }
+    // no label means the snapshot time is used
+    assert_eq!(Replica::decode_snapshot_label([0; 4]), None);
+
+    for label in &["", "0123456789abcdefg", "with space", "a/b"] {
+        assert_eq!(Replica::encode_snapshot_label(label), None);
+    }
+}
+
 async fn create_nexus() {
     let ch = vec![
         "nvmf://127.0.0.1:8430/nqn.2019-05.io.openebs:".to_string()
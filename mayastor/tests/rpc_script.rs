@@ -0,0 +1,45 @@
+use std::fs;
+
+use common::ms_exec::run_test;
+
+pub mod common;
+
+static SCRIPT: &str = "/tmp/rpc_script.jsonl";
+
+#[test]
+fn rpc_script() {
+    let args = vec!["-s".into(), "128".into()];
+    run_test(Box::from(args), |ms| {
+        fs::write(
+            SCRIPT,
+            r#"{"method": "bdev_malloc_create", "params": {"name": "script0", "num_blocks": 2048, "block_size": 512}}
+
+{"method": "bdev_get_bdevs", "params": {"name": "script0"}}
+"#,
+        )
+        .unwrap();
+        let responses = ms.rpc_script(SCRIPT).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0], "script0");
+        let bdevs = responses[1].as_array().unwrap();
+        assert_eq!(bdevs.len(), 1);
+        assert_eq!(bdevs[0]["name"], "script0");
+        assert_eq!(bdevs[0]["num_blocks"], 2048);
+
+        // the steps after the failed one are not executed
+        fs::write(
+            SCRIPT,
+            r#"{"method": "bdev_get_bdevs", "params": {"name": "nonexistent"}}
+{"method": "bdev_malloc_delete", "params": {"name": "script0"}}
+"#,
+        )
+        .unwrap();
+        let err = ms.rpc_script(SCRIPT).unwrap_err();
+        assert!(err.starts_with(&format!("{}:1: ", SCRIPT)), "{}", err);
+        let bdevs = ms
+            .rpc_call("bdev_get_bdevs", serde_json::json!({"name": "script0"}))
+            .unwrap();
+        assert_eq!(bdevs.as_array().unwrap().len(), 1);
+    });
+    common::delete_file(&[SCRIPT.into()]);
+}